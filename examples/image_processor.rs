@@ -2,6 +2,7 @@ use std::path::PathBuf;
 use image::DynamicImage;
 use image::imageops::FilterType;
 use forbild_hashing::editing::*;
+use forbild_hashing::DEFAULT_SIZE;
 
 #[cfg(not(tarpaulin_include))]
 fn main() {
@@ -9,12 +10,12 @@ fn main() {
 
     let path = PathBuf::from("./data/original/2015_Japan_Tokyo_Traffic.jpg");
 
-    let img = import_image_from_file(&path);
+    let img = import_image_from_file(&path).unwrap();
 
     let img = color_to_grayscale(img);
     img.save("./data/original/out/01_gray.jpg").unwrap();
 
-    let img = downsample(img);
+    let img = downsample(img, DEFAULT_SIZE);
     img.resize(100, 100, FilterType::Nearest).save("./data/original/out/02_downsampled.jpg").unwrap();
 
     let mut img = grayscale_to_luma(img);
@@ -7,8 +7,8 @@ fn main() {
 
     let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
 
-    let hash = Hash::from_path(&path);
+    let hash = Hash::from_path(&path).unwrap();
 
-    println!("Binary: {}", hash.to_string());
+    println!("Binary: {}", hash);
     println!("Hex: {}", hash.to_string_hex());
 }
\ No newline at end of file
@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+use std::time::Instant;
+use image::imageops::FilterType;
+use forbild_hashing::config::{HashConfig, JpegDecoding};
+use forbild_hashing::hash::Hash;
+use forbild_hashing::hashmath::hamming_distance;
+
+/// Compares the hashing time and the Hamming drift of the reduced scale JPEG decoding with the full decode.
+/// Pass JPEG files as arguments, otherwise the sample images and 12 megapixel versions of them are used.
+#[cfg(not(tarpaulin_include))]
+fn main() {
+    let n = 20;
+    let modes = [JpegDecoding::Full, JpegDecoding::Scaled, JpegDecoding::Dc];
+
+    let mut paths: Vec<PathBuf> = std::env::args().skip(1).map(PathBuf::from).collect();
+    if paths.is_empty() {
+        for name in ["2015_Japan_Tokyo_Traffic", "2017_China_Chongqing_Boats"].iter() {
+            let path = PathBuf::from(format!("./data/original/{}.jpg", name));
+            let large = std::env::temp_dir().join(format!("forbild_{}_12mp.jpg", name));
+            image::open(&path).unwrap().resize(4200, 4200, FilterType::Triangle).save(&large).unwrap();
+            paths.push(path);
+            paths.push(large);
+        }
+    }
+
+    for path in &paths {
+        let (width, height) = image::image_dimensions(path).unwrap();
+        println!("\n{} ({}x{})", path.display(), width, height);
+
+        let full = Hash::from_path_with_config(path, &HashConfig::default()).unwrap();
+        let mut full_ms = 0.0;
+        for mode in modes.iter() {
+            let config = HashConfig::default().with_jpeg_decoding(*mode);
+            let now = Instant::now();
+            for _ in 0..n {
+                let _hash = Hash::from_path_with_config(path, &config).unwrap();
+            }
+            let ms = now.elapsed().as_secs_f64() * 1000.0 / n as f64;
+            if *mode == JpegDecoding::Full {
+                full_ms = ms;
+            }
+
+            let hash = Hash::from_path_with_config(path, &config).unwrap();
+            println!("{:>7}: {:8.2} ms per hash, speedup {:5.1}x, Hamming distance to full decode {}",
+                format!("{:?}", mode), ms, full_ms / ms, hamming_distance(&full, &hash));
+        }
+    }
+}
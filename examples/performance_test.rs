@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 use std::time::Instant;
 use forbild_hashing::hash::Hash;
-use forbild_hashing::hashmath::{hamming_distance, weighted_distance};
+use forbild_hashing::hashmath::weighted_distance;
 
 #[cfg(not(tarpaulin_include))]
 fn main() {
@@ -13,7 +13,7 @@ fn main() {
 
     println!("\nCalculating hashes...");
     for _ in 0..n {
-        let _img = Hash::from_path(&path);
+        let _img = Hash::from_path(&path).unwrap();
     }
 
     let elapsed_time = now.elapsed();
@@ -22,10 +22,10 @@ fn main() {
     println!("{} hashes per second.", n / (elapsed_s));
 
     println!("\nComparing hashes...");
-    let hash = Hash::from_path(&path);
+    let hash = Hash::from_path(&path).unwrap();
     let now = Instant::now();
     for _ in 0..c {
-        let _hd = weighted_distance(&hash, &hash);
+        let _hd = weighted_distance(&hash, &hash).unwrap();
     }
     let elapsed_time = now.elapsed();
     let elapsed_s = elapsed_time.as_millis() / 1000;
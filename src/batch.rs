@@ -0,0 +1,276 @@
+//! Hashing many files without stopping at the first bad one.
+//!
+//! Every file goes through the stages read, decode and hash. Errors and panics (e.g. of a decoder
+//! on a malicious file) are caught per file and stage, so one file cannot end the whole batch.
+//! The failed files are collected as `Failure`s with the stage and the error message.
+//! Panics can only be caught if the binary is built with `panic = "unwind"` (the default).
+
+use std::any::Any;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::config::HashConfig;
+use crate::editing::decode_image_from_opened_file;
+use crate::error::Result;
+use crate::hash::Hash;
+use crate::limits::DecodeLimits;
+use crate::report::{csv_field, ReportFormat};
+
+/// Step of the pipeline in which a file failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Stage {
+    /// Finding the file, e.g. an unreadable directory entry
+    Walk,
+    /// Reading the file content
+    Read,
+    /// Decoding the image
+    Decode,
+    /// Calculating the hash of the decoded image
+    Hash,
+    /// Comparing the hash, e.g. with reference hashes of another grid size
+    Compare,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Walk => "walk",
+            Stage::Read => "read",
+            Stage::Decode => "decode",
+            Stage::Hash => "hash",
+            Stage::Compare => "compare",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A file that could not be hashed
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Failure {
+    pub path: PathBuf,
+    pub stage: Stage,
+    pub message: String,
+    /// True if the stage panicked instead of returning an error
+    pub panicked: bool,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let panicked = if self.panicked { "panic: " } else { "" };
+        write!(f, "{} [{}]: {}{}", self.path.display(), self.stage, panicked, self.message)
+    }
+}
+
+/// A successfully hashed file
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HashedFile {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hash: Hash,
+}
+
+/// Result of hashing many files. Both lists keep the order of the given paths.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    pub hashed: Vec<HashedFile>,
+    pub failures: Vec<Failure>,
+    /// Number of hashed files that were taken from a cache instead of being decoded
+    pub cached: usize,
+}
+
+impl Batch {
+    /// Paths and hashes of the hashed files
+    pub fn into_hashs(self) -> (Vec<PathBuf>, Vec<Hash>) {
+        self.hashed.into_iter().map(|file| (file.path, file.hash)).unzip()
+    }
+}
+
+/// Hashes one file with the default config of the grid size and the default `DecodeLimits`, catching errors and panics of every stage
+pub fn hash_file(path: &Path, size: u32) -> std::result::Result<HashedFile, Failure> {
+    hash_file_with_config(path, &HashConfig::default().with_size(size), &DecodeLimits::default())
+}
+
+/// Hashes one file. The file size is checked before the file is read, the dimensions before it is decoded.
+/// The file is read while decoding, so only the decoded image is kept in memory.
+/// Width and height are the ones of the image header, also if a JPEG is decoded at a reduced scale.
+pub fn hash_file_with_config(path: &Path, config: &HashConfig, limits: &DecodeLimits) -> std::result::Result<HashedFile, Failure> {
+    let (file, file_size) = run_stage(path, Stage::Read, || {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        limits.check_file_size(file_size)?;
+        Ok((file, file_size))
+    })?;
+    let decoded = run_stage(path, Stage::Decode, || decode_image_from_opened_file(file, path, limits, config))?;
+    let (width, height) = (decoded.width, decoded.height);
+    let hash = run_stage(path, Stage::Hash, || Hash::from_dynamic_image_with_config(decoded.image, config))?;
+
+    Ok(HashedFile { path: path.to_path_buf(), file_size, width, height, hash })
+}
+
+/// Hashes the files on all threads with the default config of the grid size and the default `DecodeLimits`
+pub fn hash_files(paths: &[PathBuf], size: u32) -> Batch {
+    hash_files_with_config(paths, &HashConfig::default().with_size(size), &DecodeLimits::default())
+}
+
+pub fn hash_files_with_config(paths: &[PathBuf], config: &HashConfig, limits: &DecodeLimits) -> Batch {
+    let results: Vec<std::result::Result<HashedFile, Failure>> = paths.par_iter()
+        .map(|path| hash_file_with_config(path, config, limits))
+        .collect();
+
+    let mut batch = Batch::default();
+    for result in results {
+        match result {
+            Ok(file) => batch.hashed.push(file),
+            Err(failure) => batch.failures.push(failure),
+        }
+    }
+    batch
+}
+
+fn run_stage<T, F>(path: &Path, stage: Stage, f: F) -> std::result::Result<T, Failure>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let failure = |message, panicked| Failure { path: path.to_path_buf(), stage, message, panicked };
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(failure(err.to_string(), false)),
+        Err(payload) => Err(failure(panic_message(payload), true)),
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+pub fn write_failures<W: Write>(writer: &mut W, failures: &[Failure], format: ReportFormat) -> Result<()> {
+    match format {
+        ReportFormat::Csv => {
+            writeln!(writer, "path,stage,message,panicked")?;
+            for failure in failures {
+                writeln!(writer, "{},{},{},{}",
+                         csv_field(&failure.path.to_string_lossy()),
+                         failure.stage,
+                         csv_field(&failure.message),
+                         failure.panicked
+                )?;
+            }
+        },
+        #[cfg(feature = "serde")]
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, failures)?;
+            writeln!(writer)?;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use super::*;
+
+    const BOATS: &str = "data/original/2017_China_Chongqing_Boats.jpg";
+
+    #[test]
+    fn test_hash_file() {
+        let file = hash_file(Path::new(BOATS), 16).unwrap();
+        assert_eq!(file.hash, Hash::from_path(Path::new(BOATS)).unwrap());
+        assert_eq!(file.file_size, std::fs::metadata(BOATS).unwrap().len());
+        assert_eq!((file.width, file.height), image::image_dimensions(BOATS).unwrap());
+
+        let failure = hash_file(Path::new("data/missing.jpg"), 16).unwrap_err();
+        assert_eq!(failure.stage, Stage::Read);
+        assert!(!failure.panicked);
+
+        let failure = hash_file(Path::new("Cargo.toml"), 16).unwrap_err();
+        assert_eq!(failure.stage, Stage::Decode);
+
+        let limits = DecodeLimits::none().with_max_file_size(1000);
+        let failure = hash_file_with_config(Path::new(BOATS), &HashConfig::default(), &limits).unwrap_err();
+        assert_eq!(failure.stage, Stage::Read);
+        assert!(failure.message.contains("file size limit"));
+        let limits = DecodeLimits::none().with_max_pixels(1000);
+        assert_eq!(hash_file_with_config(Path::new(BOATS), &HashConfig::default(), &limits).unwrap_err().stage, Stage::Decode);
+
+        let failure = hash_file(Path::new(BOATS), 12).unwrap_err();
+        assert_eq!(failure.stage, Stage::Hash);
+        assert_eq!(failure.to_string(), format!("{} [hash]: {}", BOATS, crate::ForbildError::InvalidGridSize(12)));
+
+        let config = HashConfig::default().with_size(8).with_threshold(crate::config::ThresholdStatistic::Mean);
+        let file = hash_file_with_config(Path::new(BOATS), &config, &DecodeLimits::default()).unwrap();
+        assert_eq!(file.hash, Hash::from_path_with_config(Path::new(BOATS), &config).unwrap());
+        assert_eq!(file.hash.config, config);
+    }
+
+    #[test]
+    fn test_run_stage_catches_panics() {
+        let path = Path::new("a.jpg");
+        assert_eq!(run_stage(path, Stage::Decode, || Ok(1)), Ok(1));
+
+        let failure = run_stage(path, Stage::Decode, || -> Result<()> { panic!("bad {}", "image") }).unwrap_err();
+        assert_eq!(failure, Failure {
+            path: path.to_path_buf(),
+            stage: Stage::Decode,
+            message: "bad image".to_string(),
+            panicked: true,
+        });
+        assert_eq!(failure.to_string(), "a.jpg [decode]: panic: bad image");
+    }
+
+    #[test]
+    fn test_hash_files() {
+        let paths = vec![PathBuf::from(BOATS), PathBuf::from("Cargo.toml"), PathBuf::from(BOATS)];
+        let batch = hash_files(&paths, 16);
+
+        assert_eq!(batch.hashed.len(), 2);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].path, PathBuf::from("Cargo.toml"));
+
+        let (paths, hashs) = batch.into_hashs();
+        assert_eq!(paths, vec![PathBuf::from(BOATS), PathBuf::from(BOATS)]);
+        assert_eq!(hashs[0], hashs[1]);
+    }
+
+    #[test]
+    fn test_write_failures() {
+        let failures = vec![
+            Failure { path: PathBuf::from("a, b.jpg"), stage: Stage::Read, message: "missing".to_string(), panicked: false },
+            Failure { path: PathBuf::from("c.jpg"), stage: Stage::Decode, message: "index 3, len 2".to_string(), panicked: true },
+        ];
+
+        let mut csv = Vec::new();
+        write_failures(&mut csv, &failures, ReportFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(),
+                   "path,stage,message,panicked\n\"a, b.jpg\",read,missing,false\nc.jpg,decode,\"index 3, len 2\",true\n");
+
+        #[cfg(feature = "serde")]
+        {
+            let mut json = Vec::new();
+            write_failures(&mut json, &failures, ReportFormat::Json).unwrap();
+            let parsed: Vec<Failure> = serde_json::from_slice(&json).unwrap();
+            assert_eq!(parsed, failures);
+            assert!(String::from_utf8(json).unwrap().contains("\"stage\": \"decode\""));
+        }
+    }
+}
@@ -0,0 +1,4 @@
+#[cfg(not(tarpaulin_include))]
+fn main() {
+    std::process::exit(forbild_hashing::cli::run(std::env::args_os()));
+}
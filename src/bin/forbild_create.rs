@@ -1,12 +1,8 @@
-use forbild_hashing::parse_args_to_paths;
-use forbild_hashing::hash::Hash;
+//! Compatibility wrapper for `forbild hash`
 
 #[cfg(not(tarpaulin_include))]
 fn main() {
-    let paths = parse_args_to_paths();
-
-    for path in &paths {
-        let hash = Hash::from_path(path);
-        println!("{};{}", path.to_str().unwrap(), hash.to_string());
-    }
-}
\ No newline at end of file
+    let args = ["forbild", "hash"].iter().map(|arg| arg.into())
+        .chain(std::env::args_os().skip(1));
+    std::process::exit(forbild_hashing::cli::run(args));
+}
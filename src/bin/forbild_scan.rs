@@ -0,0 +1,8 @@
+//! Compatibility wrapper for `forbild scan --recursive`
+
+#[cfg(not(tarpaulin_include))]
+fn main() {
+    let args = ["forbild", "scan", "--recursive"].iter().map(|arg| arg.into())
+        .chain(std::env::args_os().skip(1));
+    std::process::exit(forbild_hashing::cli::run(args));
+}
@@ -0,0 +1,443 @@
+//! On-disk cache of hashes, so that unchanged files are not decoded again.
+//!
+//! Entries are keyed by the canonical path and the hash config, and are only used while the file
+//! size and modification time are unchanged. With `with_content_hash`, the SHA-256 of the content is
+//! stored as well and has to match too, which means every file is read (but not decoded) again.
+//!
+//! The cache file contains one JSON `CacheEntry` per line (NDJSON). Entries created by another
+//! version of the hashing algorithm are dropped when loading.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::batch::{hash_file_with_config, Batch, Failure, HashedFile};
+use crate::config::HashConfig;
+use crate::error::Result;
+use crate::hash::Hash;
+use crate::limits::DecodeLimits;
+use crate::record::ALGORITHM_VERSION;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Canonical path of the file
+    pub path: PathBuf,
+    pub file_size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u64,
+    /// SHA-256 of the content as lowercase hex string, if the cache was used with content hashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub algorithm_version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub hash: Hash,
+}
+
+/// State of a cache entry compared with its file on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Valid,
+    /// The file does not exist any more or cannot be read
+    Missing,
+    /// The size or modification time of the file changed
+    Changed,
+    /// Size and modification time are the same, but the content is different
+    ContentChanged,
+}
+
+impl CacheStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheStatus::Valid => "valid",
+            CacheStatus::Missing => "missing",
+            CacheStatus::Changed => "changed",
+            CacheStatus::ContentChanged => "content changed",
+        }
+    }
+}
+
+/// Canonical path, size and modification time of a file
+struct FileState {
+    path: PathBuf,
+    file_size: u64,
+    modified: u64,
+}
+
+impl FileState {
+    fn read(path: &Path) -> std::io::Result<FileState> {
+        let metadata = fs::metadata(path)?;
+        // Files modified before 1970 get 0, so only their size tells changes apart
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(FileState { path: fs::canonicalize(path)?, file_size: metadata.len(), modified })
+    }
+}
+
+impl CacheEntry {
+    fn matches(&self, state: &FileState) -> bool {
+        self.file_size == state.file_size && self.modified == state.modified
+    }
+
+    fn to_hashed_file(&self, path: &Path) -> HashedFile {
+        HashedFile {
+            path: path.to_path_buf(),
+            file_size: self.file_size,
+            width: self.width,
+            height: self.height,
+            hash: self.hash.clone(),
+        }
+    }
+
+    /// Compares the entry with its file, including the content if the entry has a SHA-256
+    pub fn status(&self) -> CacheStatus {
+        let state = match FileState::read(&self.path) {
+            Ok(state) => state,
+            Err(_) => return CacheStatus::Missing,
+        };
+        if !self.matches(&state) {
+            return CacheStatus::Changed;
+        }
+        match &self.sha256 {
+            Some(sha256) => match sha256_of_file(&self.path) {
+                Ok(current) if current == *sha256 => CacheStatus::Valid,
+                Ok(_) => CacheStatus::ContentChanged,
+                Err(_) => CacheStatus::Missing,
+            },
+            None => CacheStatus::Valid,
+        }
+    }
+}
+
+/// A cache entry that is not valid any more
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaleEntry {
+    pub path: PathBuf,
+    pub status: CacheStatus,
+}
+
+enum Lookup {
+    Hit(HashedFile),
+    Miss(std::result::Result<HashedFile, Failure>, Option<CacheEntry>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HashCache {
+    entries: HashMap<(PathBuf, HashConfig), CacheEntry>,
+    content_hash: bool,
+}
+
+impl HashCache {
+    pub fn new() -> HashCache {
+        HashCache::default()
+    }
+
+    /// Also compare the SHA-256 of the content before a cached hash is used
+    pub fn with_content_hash(mut self, content_hash: bool) -> HashCache {
+        self.content_hash = content_hash;
+        self
+    }
+
+    /// Loads the cache file, an empty cache if it does not exist yet
+    pub fn load(path: &Path) -> Result<HashCache> {
+        let mut cache = HashCache::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(err) => return Err(err.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: CacheEntry = serde_json::from_str(&line)?;
+            if entry.algorithm_version == ALGORITHM_VERSION {
+                cache.insert(entry);
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Writes the cache sorted by path and line. The file is replaced only after all entries were written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut lines: Vec<(&Path, String)> = self.entries.values()
+            .map(|entry| Ok((entry.path.as_path(), serde_json::to_string(entry)?)))
+            .collect::<Result<_>>()?;
+        lines.sort();
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for (_, line) in lines {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &CacheEntry> {
+        self.entries.values()
+    }
+
+    /// Adds the entry, replacing the entry of the same file and hash config
+    pub fn insert(&mut self, entry: CacheEntry) {
+        self.entries.insert((entry.path.clone(), entry.hash.config), entry);
+    }
+
+    /// Returns the cached hash of the file if the file is unchanged
+    pub fn get(&self, path: &Path, config: &HashConfig) -> Option<HashedFile> {
+        let state = FileState::read(path).ok()?;
+        self.get_unchanged(path, config, &state)
+    }
+
+    fn get_unchanged(&self, path: &Path, config: &HashConfig, state: &FileState) -> Option<HashedFile> {
+        let entry = self.entries.get(&(state.path.clone(), *config))?;
+        if !entry.matches(state) {
+            return None;
+        }
+        if self.content_hash && entry.sha256 != Some(sha256_of_file(path).ok()?) {
+            return None;
+        }
+        Some(entry.to_hashed_file(path))
+    }
+
+    /// Hashes the files on all threads like `batch::hash_files_with_config`, but takes the hashes of
+    /// unchanged files from the cache. The new hashes are added to the cache.
+    pub fn hash_files(&mut self, paths: &[PathBuf], config: &HashConfig, limits: &DecodeLimits) -> Batch {
+        let lookups: Vec<Lookup> = paths.par_iter()
+            .map(|path| self.lookup(path, config, limits))
+            .collect();
+
+        let mut batch = Batch::default();
+        for lookup in lookups {
+            match lookup {
+                Lookup::Hit(file) => {
+                    batch.hashed.push(file);
+                    batch.cached += 1;
+                },
+                Lookup::Miss(result, entry) => {
+                    if let Some(entry) = entry {
+                        self.insert(entry);
+                    }
+                    match result {
+                        Ok(file) => batch.hashed.push(file),
+                        Err(failure) => batch.failures.push(failure),
+                    }
+                },
+            }
+        }
+        batch
+    }
+
+    fn lookup(&self, path: &Path, config: &HashConfig, limits: &DecodeLimits) -> Lookup {
+        // The state is read before hashing, so that a file changed while hashing is hashed again next time
+        let state = FileState::read(path).ok();
+        if let Some(file) = state.as_ref().and_then(|state| self.get_unchanged(path, config, state)) {
+            return Lookup::Hit(file);
+        }
+
+        // Like the state, the content hash is read before hashing. A file that cannot be read is not cached.
+        let sha256 = if self.content_hash { sha256_of_file(path).ok() } else { None };
+        match hash_file_with_config(path, config, limits) {
+            Ok(file) => {
+                let entry = state.filter(|_| sha256.is_some() || !self.content_hash).map(|state| CacheEntry {
+                    path: state.path,
+                    file_size: state.file_size,
+                    modified: state.modified,
+                    sha256,
+                    algorithm_version: ALGORITHM_VERSION,
+                    width: file.width,
+                    height: file.height,
+                    hash: file.hash.clone(),
+                });
+                Lookup::Miss(Ok(file), entry)
+            },
+            Err(failure) => Lookup::Miss(Err(failure), None),
+        }
+    }
+
+    /// Compares all entries with their files and returns the entries that are not valid any more, sorted by path
+    pub fn verify(&self) -> Vec<StaleEntry> {
+        let mut stale: Vec<StaleEntry> = self.entries.par_iter()
+            .map(|(_, entry)| StaleEntry { path: entry.path.clone(), status: entry.status() })
+            .filter(|stale| stale.status != CacheStatus::Valid)
+            .collect();
+        stale.sort_by(|a, b| a.path.cmp(&b.path));
+        stale.dedup();
+        stale
+    }
+
+    /// Removes the entries that are not valid any more and returns them
+    pub fn prune(&mut self) -> Vec<StaleEntry> {
+        let stale = self.verify();
+        let paths: HashSet<&Path> = stale.iter().map(|entry| entry.path.as_path()).collect();
+        self.entries.retain(|(path, _), _| !paths.contains(path.as_path()));
+        stale
+    }
+}
+
+fn sha256_of_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(sha256_hex(hasher.finalize()))
+}
+
+fn sha256_hex(digest: impl AsRef<[u8]>) -> String {
+    digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+
+    const BOATS: &str = "data/original/2017_China_Chongqing_Boats.jpg";
+    const TRAFFIC: &str = "data/original/2015_Japan_Tokyo_Traffic.jpg";
+
+    /// Creates an empty temporary directory for a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("forbild_cache_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Changes the content but keeps size and modification time
+    fn replace_content(path: &Path, source: &str) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        let mut bytes = fs::read(source).unwrap();
+        bytes.resize(fs::metadata(path).unwrap().len() as usize, 0);
+        fs::write(path, &bytes).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_hash_files() {
+        let dir = temp_dir("hash_files");
+        let paths = vec![dir.join("a.jpg"), dir.join("b.jpg"), dir.join("missing.jpg")];
+        fs::copy(BOATS, &paths[0]).unwrap();
+        fs::copy(TRAFFIC, &paths[1]).unwrap();
+
+        let mut cache = HashCache::new();
+        let batch = cache.hash_files(&paths, &HashConfig::default(), &DecodeLimits::default());
+        assert_eq!((batch.hashed.len(), batch.failures.len(), batch.cached), (2, 1, 0));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(batch.hashed[0].hash, Hash::from_path(Path::new(BOATS)).unwrap());
+
+        let again = cache.hash_files(&paths, &HashConfig::default(), &DecodeLimits::default());
+        assert_eq!(again.cached, 2);
+        assert_eq!(again.hashed, batch.hashed);
+
+        // Other grid sizes and configs are separate entries
+        let batch = cache.hash_files(&paths[..1], &HashConfig::default().with_size(8), &DecodeLimits::default());
+        assert_eq!(batch.cached, 0);
+        assert_eq!(cache.len(), 3);
+        let config = HashConfig::default().with_threshold(crate::config::ThresholdStatistic::Mean);
+        let batch = cache.hash_files(&paths[..1], &config, &DecodeLimits::default());
+        assert_eq!(batch.cached, 0);
+        assert_eq!(batch.hashed[0].hash.config, config);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.get(&paths[0], &config).unwrap().hash, batch.hashed[0].hash);
+
+        // Changing the file invalidates the entry
+        fs::copy(TRAFFIC, &paths[0]).unwrap();
+        assert_eq!(cache.get(&paths[0], &HashConfig::default()), None);
+        let batch = cache.hash_files(&paths[..1], &HashConfig::default(), &DecodeLimits::default());
+        assert_eq!(batch.cached, 0);
+        assert_eq!(batch.hashed[0].hash, Hash::from_path(Path::new(TRAFFIC)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let dir = temp_dir("content_hash");
+        let path = dir.join("a.jpg");
+        fs::copy(TRAFFIC, &path).unwrap();
+
+        let mut cache = HashCache::new().with_content_hash(true);
+        cache.hash_files(std::slice::from_ref(&path), &HashConfig::default(), &DecodeLimits::default());
+        let entry = cache.entries().next().unwrap().clone();
+        assert_eq!(entry.sha256, Some(sha256_of_file(Path::new(TRAFFIC)).unwrap()));
+        assert!(cache.get(&path, &HashConfig::default()).is_some());
+
+        // Only the content hash notices the change
+        replace_content(&path, BOATS);
+        assert!(cache.get(&path, &HashConfig::default()).is_none());
+        assert!(cache.clone().with_content_hash(false).get(&path, &HashConfig::default()).is_some());
+        assert_eq!(entry.status(), CacheStatus::ContentChanged);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = temp_dir("save_and_load");
+        let path = dir.join("a.jpg");
+        fs::copy(BOATS, &path).unwrap();
+        let cache_path = dir.join("cache.ndjson");
+        assert!(HashCache::load(&cache_path).unwrap().is_empty());
+
+        let mut cache = HashCache::new().with_content_hash(true);
+        cache.hash_files(std::slice::from_ref(&path), &HashConfig::default(), &DecodeLimits::default());
+        cache.save(&cache_path).unwrap();
+        assert!(!dir.join("cache.ndjson.tmp").exists());
+
+        let mut loaded = HashCache::load(&cache_path).unwrap();
+        assert_eq!(loaded.entries().collect::<Vec<_>>(), cache.entries().collect::<Vec<_>>());
+        assert_eq!(loaded.hash_files(&[path], &HashConfig::default(), &DecodeLimits::default()).cached, 1);
+
+        // Entries of other algorithm versions are dropped
+        let text = fs::read_to_string(&cache_path).unwrap()
+            .replace(&format!("\"algorithm_version\":{}", ALGORITHM_VERSION), "\"algorithm_version\":0");
+        fs::write(&cache_path, text).unwrap();
+        assert!(HashCache::load(&cache_path).unwrap().is_empty());
+
+        fs::write(&cache_path, "no json").unwrap();
+        assert!(HashCache::load(&cache_path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_prune() {
+        let dir = temp_dir("verify_and_prune");
+        let paths = vec![dir.join("a.jpg"), dir.join("b.jpg"), dir.join("c.jpg")];
+        for path in &paths {
+            fs::copy(BOATS, path).unwrap();
+        }
+        let mut cache = HashCache::new();
+        cache.hash_files(&paths, &HashConfig::default(), &DecodeLimits::default());
+        cache.hash_files(&paths[..1], &HashConfig::default().with_size(8), &DecodeLimits::default());
+        assert!(cache.verify().is_empty());
+
+        let canonical_b = fs::canonicalize(&paths[1]).unwrap();
+        let canonical_c = fs::canonicalize(&paths[2]).unwrap();
+        fs::remove_file(&paths[1]).unwrap();
+        fs::write(&paths[2], "changed").unwrap();
+        let stale = vec![
+            StaleEntry { path: canonical_b, status: CacheStatus::Missing },
+            StaleEntry { path: canonical_c, status: CacheStatus::Changed },
+        ];
+        assert_eq!(cache.verify(), stale);
+
+        assert_eq!(cache.prune(), stale);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.verify().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+//! Checkpoints of long-running batch jobs, so that a killed job can be resumed.
+//!
+//! A checkpoint file is NDJSON: the first line identifies the job, every further line holds the
+//! outcome (hash or failure) of one file. The outcomes are appended and synced to disk after every
+//! `interval` files. When resuming, files with an outcome are skipped, and a last line cut off by the
+//! kill is dropped. The files are recognised by their canonical path, so they may be spelled differently.
+//! The resulting `Batch` is in the order of the given paths, so it is the same as the one of an uninterrupted run.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::{Batch, Failure, HashedFile};
+use crate::error::{ForbildError, Result};
+
+/// Version of the checkpoint file format
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// Default number of files after which the outcomes are written
+pub const DEFAULT_INTERVAL: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    checkpoint_version: u32,
+    job: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Hashed(HashedFile),
+    Failed(Failure),
+}
+
+impl Outcome {
+    fn path(&self) -> &Path {
+        match self {
+            Outcome::Hashed(file) => &file.path,
+            Outcome::Failed(failure) => &failure.path,
+        }
+    }
+
+    fn with_path(mut self, path: &Path) -> Outcome {
+        match &mut self {
+            Outcome::Hashed(file) => file.path = path.to_path_buf(),
+            Outcome::Failed(failure) => failure.path = path.to_path_buf(),
+        }
+        self
+    }
+}
+
+/// Key of a file in `Checkpoint::done`, its canonical path if it exists
+fn key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+pub struct Checkpoint {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    done: HashMap<PathBuf, Outcome>,
+    interval: usize,
+}
+
+impl Checkpoint {
+    /// Starts a new checkpoint file. `job` identifies the job, e.g. its arguments, so that a checkpoint
+    /// is not resumed by another job. An existing file is not replaced (`ForbildError::InvalidCheckpoint`),
+    /// so that the outcomes of a killed job are not lost by accident, see `replace`.
+    pub fn create(path: &Path, job: &str) -> Result<Checkpoint> {
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => Checkpoint::start(file, path, job),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(ForbildError::InvalidCheckpoint(format!("{} exists already", path.display())))
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Starts a new checkpoint file like `create`, replacing an existing one
+    pub fn replace(path: &Path, job: &str) -> Result<Checkpoint> {
+        Checkpoint::start(File::create(path)?, path, job)
+    }
+
+    fn start(mut file: File, path: &Path, job: &str) -> Result<Checkpoint> {
+        let header = Header { checkpoint_version: CHECKPOINT_VERSION, job: job.to_string() };
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.sync_data()?;
+        Ok(Checkpoint { path: path.to_path_buf(), writer: BufWriter::new(file), done: HashMap::new(), interval: DEFAULT_INTERVAL })
+    }
+
+    /// Loads the outcomes of an existing checkpoint file to continue the job,
+    /// or starts a new one if it does not exist (or was killed before the job was written).
+    pub fn resume(path: &Path, job: &str) -> Result<Checkpoint> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Checkpoint::create(path, job),
+            Err(err) => return Err(err.into()),
+        };
+
+        // Only lines ending with a line break were written completely
+        let mut lines = Vec::new();
+        let mut complete_len = 0;
+        while let Some(end) = data[complete_len..].iter().position(|byte| *byte == b'\n') {
+            lines.push(&data[complete_len..complete_len + end]);
+            complete_len += end + 1;
+        }
+        let (header, outcomes) = match lines.split_first() {
+            Some((header, outcomes)) => (header, outcomes),
+            None => return Checkpoint::replace(path, job),
+        };
+
+        let header: Header = serde_json::from_slice(header)?;
+        if header.checkpoint_version != CHECKPOINT_VERSION {
+            return Err(ForbildError::UnsupportedVersion { found: header.checkpoint_version, supported: CHECKPOINT_VERSION });
+        }
+        if header.job != job {
+            return Err(ForbildError::InvalidCheckpoint(format!("{} belongs to another job: {}", path.display(), header.job)));
+        }
+        let mut done = HashMap::new();
+        for line in outcomes.iter().filter(|line| !line.is_empty()) {
+            let outcome: Outcome = serde_json::from_slice(line)?;
+            done.insert(key(outcome.path()), outcome);
+        }
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(complete_len as u64)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Checkpoint { path: path.to_path_buf(), writer: BufWriter::new(file), done, interval: DEFAULT_INTERVAL })
+    }
+
+    /// Number of files after which the outcomes are written, at least 1
+    pub fn with_interval(mut self, interval: usize) -> Checkpoint {
+        self.interval = interval.max(1);
+        self
+    }
+
+    /// Number of files with an outcome
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// Hashes the files without an outcome with `hash`, `interval` files at a time, and writes their
+    /// outcomes. Returns the outcomes of all paths in their order. `Batch::cached` only counts the
+    /// files hashed by this call.
+    pub fn run<F>(&mut self, paths: &[PathBuf], mut hash: F) -> Result<Batch>
+    where
+        F: FnMut(&[PathBuf]) -> Batch,
+    {
+        let keys: Vec<PathBuf> = paths.iter().map(|path| key(path)).collect();
+        let pending: Vec<PathBuf> = paths.iter().zip(&keys)
+            .filter(|(_, key)| !self.done.contains_key(*key))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut cached = 0;
+        for chunk in pending.chunks(self.interval) {
+            let batch = hash(chunk);
+            cached += batch.cached;
+            let outcomes = batch.hashed.into_iter().map(Outcome::Hashed)
+                .chain(batch.failures.into_iter().map(Outcome::Failed));
+            for outcome in outcomes {
+                writeln!(self.writer, "{}", serde_json::to_string(&outcome)?)?;
+                self.done.insert(key(outcome.path()), outcome);
+            }
+            self.writer.flush()?;
+            self.writer.get_ref().sync_data()?;
+        }
+
+        let mut batch = Batch { cached, ..Batch::default() };
+        for (path, key) in paths.iter().zip(&keys) {
+            // The outcome is reported with the spelling of this run
+            match self.done.get(key).map(|outcome| outcome.clone().with_path(path)) {
+                Some(Outcome::Hashed(file)) => batch.hashed.push(file),
+                Some(Outcome::Failed(failure)) => batch.failures.push(failure),
+                None => {},
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Deletes the checkpoint file once the job is done
+    pub fn remove(self) -> Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use super::*;
+    use crate::batch::hash_files;
+
+    const BOATS: &str = "data/original/2017_China_Chongqing_Boats.jpg";
+    const TRAFFIC: &str = "data/original/2015_Japan_Tokyo_Traffic.jpg";
+
+    fn create_paths() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from(TRAFFIC),
+            PathBuf::from("data/missing.jpg"),
+            PathBuf::from(BOATS),
+            PathBuf::from("data/original/out/01_gray.jpg"),
+        ]
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("forbild_checkpoint_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_resume() {
+        let path = temp_path("resume.ndjson");
+        let paths = create_paths();
+        let expected = hash_files(&paths, 8);
+
+        // The job is killed after the first two files, in the middle of writing the third
+        let mut checkpoint = Checkpoint::create(&path, "job").unwrap().with_interval(1);
+        let batch = checkpoint.run(&paths[..2], |chunk| hash_files(chunk, 8)).unwrap();
+        assert_eq!(batch.hashed.len() + batch.failures.len(), 2);
+        drop(checkpoint);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"hashed\":{\"path\":\"data/orig").unwrap();
+        drop(file);
+
+        let mut checkpoint = Checkpoint::resume(&path, "job").unwrap().with_interval(1);
+        assert_eq!(checkpoint.len(), 2);
+        let mut hashed = Vec::new();
+        let batch = checkpoint.run(&paths, |chunk| {
+            hashed.extend_from_slice(chunk);
+            hash_files(chunk, 8)
+        }).unwrap();
+        assert_eq!(hashed, paths[2..].to_vec());
+        assert_eq!(batch, expected);
+
+        // Everything is done
+        drop(checkpoint);
+        let mut checkpoint = Checkpoint::resume(&path, "job").unwrap();
+        assert_eq!(checkpoint.len(), 4);
+        assert_eq!(checkpoint.run(&paths, |_| panic!("nothing to hash")).unwrap(), expected);
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_resume_other_spelling() {
+        let path = temp_path("spelling.ndjson");
+        let mut checkpoint = Checkpoint::create(&path, "job").unwrap();
+        checkpoint.run(&[PathBuf::from(BOATS)], |chunk| hash_files(chunk, 8)).unwrap();
+        drop(checkpoint);
+
+        // The file is not hashed again and reported with the new spelling
+        let other = PathBuf::from("data/./original/../original/2017_China_Chongqing_Boats.jpg");
+        let mut checkpoint = Checkpoint::resume(&path, "job").unwrap();
+        let batch = checkpoint.run(std::slice::from_ref(&other), |_| panic!("nothing to hash")).unwrap();
+        assert_eq!(batch.hashed[0].path, other);
+        checkpoint.remove().unwrap();
+    }
+
+    #[test]
+    fn test_resume_other_job() {
+        let path = temp_path("other_job.ndjson");
+        assert!(Checkpoint::resume(&path, "job").unwrap().is_empty());
+        assert!(matches!(Checkpoint::resume(&path, "other"), Err(ForbildError::InvalidCheckpoint(_))));
+
+        // A new checkpoint only replaces the old one if asked to
+        assert!(matches!(Checkpoint::create(&path, "other"), Err(ForbildError::InvalidCheckpoint(_))));
+        let mut checkpoint = Checkpoint::replace(&path, "other").unwrap();
+        checkpoint.run(&create_paths()[1..2], |chunk| hash_files(chunk, 8)).unwrap();
+        drop(checkpoint);
+        assert_eq!(Checkpoint::resume(&path, "other").unwrap().len(), 1);
+
+        std::fs::write(&path, "{\"checkpoint_version\":2,\"job\":\"other\"}\n").unwrap();
+        assert!(matches!(Checkpoint::resume(&path, "other"), Err(ForbildError::UnsupportedVersion { found: 2, .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1033 @@
+//! Command line interface of the `forbild` binary.
+//!
+//! The older binaries (`forbild_create`, `forbild_compare_all`, `forbild_scan`, `forbild_image`)
+//! call `run` with the matching subcommand.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::batch::{hash_files_with_config, Batch, Failure, Stage};
+use crate::cache::HashCache;
+use crate::checkpoint::{Checkpoint, DEFAULT_INTERVAL};
+use crate::config::{Averaging, HashConfig, JpegDecoding, LumaCoefficients, ResizeFilter, ThresholdStatistic};
+use crate::editing::{self, Normalization};
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::hashmath::WeightedMode;
+use crate::index::BkTree;
+use crate::limits::DecodeLimits;
+use crate::matcher::{Matcher, DEFAULT_HAMMING_THRESHOLD, DEFAULT_WEIGHTED_THRESHOLD};
+use crate::record::{records_to_json, HashRecord};
+use crate::report::{compare_all, csv_field, for_each_pair, group_duplicates, PairReport, ReportFormat, ReportWriter};
+use crate::scan::{ReferenceSet, ScanHit, ScanReport, Scanner, EXIT_ERRORS};
+use crate::walk::{SkipReason, Skipped, WalkOptions, Walker};
+
+/// Exit code if everything worked. `scan` and `index query` use the exit codes of `scan` instead,
+/// all commands exit with `scan::EXIT_ERRORS` if any image or file could not be processed.
+pub const EXIT_SUCCESS: i32 = 0;
+
+#[derive(Debug, Parser)]
+#[command(name = "forbild", version, about = "Perceptual image hashing with the ForBild algorithm")]
+pub struct Cli {
+    /// Number of worker threads, 0 means one per CPU core
+    #[arg(short, long, global = true, default_value_t = 0)]
+    pub threads: usize,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Hash images and print the hashes
+    Hash {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Compare all images with each other and print the matching pairs
+    Compare {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+        #[command(flatten)]
+        matcher: MatcherArgs,
+        /// Print all pairs with their distances, not only the matching ones
+        #[arg(short, long)]
+        all: bool,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Match images against a file of known hashes (exit code 0: no hits, 1: hits, 2: errors)
+    Scan {
+        /// Hash database, JSON/NDJSON record file or text file with id;hash lines
+        references: PathBuf,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+        #[command(flatten)]
+        matcher: MatcherArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Build or query a BK-tree index of hashes
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+    /// Print groups of duplicate images
+    Dedup {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+        #[command(flatten)]
+        matcher: MatcherArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Check or clean up a hash cache file
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Save the intermediate images of the hashing steps
+    DebugImage {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Directory the images are written to
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        #[arg(long, default_value_t = crate::DEFAULT_SIZE)]
+        size: u32,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// Hash images and save them as index file
+    Build {
+        /// Index file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+    },
+    /// Find the indexed images matching the given images (exit code 0: no hits, 1: hits, 2: errors)
+    Query {
+        /// Index file built by `index build`
+        index: PathBuf,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        hash: HashArgs,
+        #[command(flatten)]
+        batch: BatchArgs,
+        /// Hamming distance of the candidates that are compared with the matcher
+        #[arg(long, default_value_t = 2*DEFAULT_HAMMING_THRESHOLD)]
+        radius: usize,
+        #[command(flatten)]
+        matcher: MatcherArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Remove the entries of missing or changed files
+    Prune {
+        /// Cache file written with `--cache`
+        cache: PathBuf,
+    },
+    /// Print the entries of missing or changed files (exit code 2 if there are any)
+    Verify {
+        /// Cache file written with `--cache`
+        cache: PathBuf,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+/// Images to process
+#[derive(Debug, Args)]
+pub struct InputArgs {
+    /// Image files and directories
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+    /// Search the directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+    /// Only take the files of the directories matching this glob pattern (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Skip the files of the directories matching this glob pattern (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+/// Options of the hash calculation, see `config::HashConfig`. `scan` and `index query` start from
+/// the config of the references, the other commands from the default config.
+#[derive(Debug, Args)]
+pub struct HashArgs {
+    /// Hash config as JSON, e.g. the "config" of a JSON hash record. The other options change single fields of it.
+    #[arg(long, value_name = "JSON")]
+    pub config: Option<String>,
+    /// Width and height of the hash grid, a multiple of 8 [default: 16]
+    #[arg(long)]
+    pub size: Option<u32>,
+    /// Filter used to downsample the grayscale image [default: gaussian]
+    #[arg(long, value_enum)]
+    pub filter: Option<ResizeFilter>,
+    /// Only use integer arithmetic after decoding (area filter, sRGB averaging), so that the hashes are the same on every platform
+    #[arg(long, conflicts_with_all = ["filter", "averaging"])]
+    pub bit_exact: bool,
+    /// Weights of the color channels for the grayscale conversion [default: rec709]
+    #[arg(long, value_enum)]
+    pub luma: Option<LumaCoefficients>,
+    /// Values averaged when downsampling [default: srgb]
+    #[arg(long, value_enum)]
+    pub averaging: Option<Averaging>,
+    /// Threshold of each subarea for the binary hash [default: median]
+    #[arg(long, value_enum)]
+    pub threshold_statistic: Option<ThresholdStatistic>,
+    /// How the image is flipped before hashing, so that mirrored images get the same hash [default: brightest_pixel]
+    #[arg(long, value_enum)]
+    pub normalization: Option<Normalization>,
+    /// How JPEG files are decoded, `scaled` and `dc` decode at a reduced size and are much faster [default: full]
+    #[arg(long, value_enum)]
+    pub jpeg_decoding: Option<JpegDecoding>,
+}
+
+/// Options for hashing many images
+#[derive(Debug, Args)]
+pub struct BatchArgs {
+    /// Cache file of hashes, unchanged files are not hashed again
+    #[arg(long, value_name = "FILE")]
+    pub cache: Option<PathBuf>,
+    /// Only take hashes from the cache if the SHA-256 of the file content is unchanged
+    #[arg(long, requires = "cache")]
+    pub content_hash: bool,
+    /// Checkpoint file the progress is saved to, it is deleted when all images are hashed
+    #[arg(long, value_name = "FILE")]
+    pub checkpoint: Option<PathBuf>,
+    /// Continue from the checkpoint file of a killed run with the same input
+    #[arg(long, requires = "checkpoint")]
+    pub resume: bool,
+    /// Replace an existing checkpoint file, without it the run does not start if the file exists
+    #[arg(long, requires = "checkpoint", conflicts_with = "resume")]
+    pub overwrite_checkpoint: bool,
+    /// Number of images after which the checkpoint is saved
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_INTERVAL)]
+    pub checkpoint_interval: usize,
+    /// Images wider than this are not decoded [default: unlimited]
+    #[arg(long, value_name = "PIXELS")]
+    pub max_width: Option<u32>,
+    /// Images higher than this are not decoded [default: unlimited]
+    #[arg(long, value_name = "PIXELS")]
+    pub max_height: Option<u32>,
+    /// Images with more pixels than this are not decoded [default: 268435456]
+    #[arg(long, value_name = "PIXELS")]
+    pub max_pixels: Option<u64>,
+    /// Estimated bytes of the decoded image and its copies per thread, larger images are not decoded [default: 1073741824]
+    #[arg(long, value_name = "BYTES")]
+    pub max_alloc: Option<u64>,
+    /// Larger files are not decoded [default: 1073741824]
+    #[arg(long, value_name = "BYTES")]
+    pub max_file_size: Option<u64>,
+}
+
+impl BatchArgs {
+    /// The default `DecodeLimits` with the limits of the arguments
+    pub fn limits(&self) -> DecodeLimits {
+        let defaults = DecodeLimits::default();
+        DecodeLimits {
+            max_width: self.max_width.or(defaults.max_width),
+            max_height: self.max_height.or(defaults.max_height),
+            max_pixels: self.max_pixels.or(defaults.max_pixels),
+            max_alloc: self.max_alloc.or(defaults.max_alloc),
+            max_file_size: self.max_file_size.or(defaults.max_file_size),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct MatcherArgs {
+    /// Hamming distance up to which two images are the same
+    #[arg(long, alias = "hamming", default_value_t = DEFAULT_HAMMING_THRESHOLD)]
+    pub threshold_hamming: usize,
+    /// Weighted distance up to which two images are the same
+    #[arg(long, alias = "weighted", default_value_t = DEFAULT_WEIGHTED_THRESHOLD)]
+    pub threshold_weighted: f64,
+    /// Use the symmetric weighted distance
+    #[arg(long)]
+    pub symmetric: bool,
+    /// Also match images that are rotated by 90°, 180° or 270° or mirrored
+    #[arg(long)]
+    pub rotation_invariant: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Format of the results printed to stdout
+    #[arg(short = 'f', long, alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+impl InputArgs {
+    fn walk_options(&self) -> WalkOptions {
+        WalkOptions { recursive: self.recursive, include: self.include.clone(), exclude: self.exclude.clone() }
+    }
+}
+
+impl HashArgs {
+    /// The config of `--config` or `base`, with the fields of the other options replaced
+    fn config(&self, base: HashConfig) -> Result<HashConfig> {
+        let mut config = match &self.config {
+            Some(json) => serde_json::from_str(json)?,
+            None => base,
+        };
+        if let Some(size) = self.size {
+            config.size = size;
+        }
+        if let Some(filter) = self.filter {
+            config.filter = filter;
+        }
+        if self.bit_exact {
+            config.filter = ResizeFilter::Area;
+            config.averaging = Averaging::Srgb;
+        }
+        if let Some(luma) = self.luma {
+            config.luma = luma;
+        }
+        if let Some(averaging) = self.averaging {
+            config.averaging = averaging;
+        }
+        if let Some(threshold) = self.threshold_statistic {
+            config.threshold = threshold;
+        }
+        if let Some(normalization) = self.normalization {
+            config.normalization = normalization;
+        }
+        if let Some(jpeg) = self.jpeg_decoding {
+            config.jpeg = jpeg;
+        }
+        crate::check_size(config.size)?;
+        Ok(config)
+    }
+}
+
+impl MatcherArgs {
+    fn matcher(&self) -> Matcher {
+        let mode = if self.symmetric { WeightedMode::Symmetric } else { WeightedMode::Directional };
+        Matcher::new(self.threshold_hamming, self.threshold_weighted)
+            .with_weighted_mode(mode)
+            .with_rotation_invariance(self.rotation_invariant)
+    }
+}
+
+impl OutputFormat {
+    fn report_format(self) -> ReportFormat {
+        match self {
+            OutputFormat::Text | OutputFormat::Csv => ReportFormat::Csv,
+            OutputFormat::Json => ReportFormat::Json,
+        }
+    }
+}
+
+/// Parses the arguments (including the program name), runs the command and returns the exit code.
+/// Results are printed to stdout, progress and errors to stderr.
+pub fn run<I, T>(args: I) -> i32
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(err) => {
+            // Prints the help and version as well
+            let _ = err.print();
+            return if err.use_stderr() { EXIT_ERRORS } else { EXIT_SUCCESS };
+        }
+    };
+
+    // The global pool can only be built once, which only fails if `run` is called again
+    let _ = rayon::ThreadPoolBuilder::new().num_threads(cli.threads).build_global();
+
+    let stdout = std::io::stdout();
+    let mut writer = std::io::BufWriter::new(stdout.lock());
+    let result = execute(cli.command, &mut writer).and_then(|code| {
+        writer.flush()?;
+        Ok(code)
+    });
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            EXIT_ERRORS
+        }
+    }
+}
+
+/// Runs the command, writing its results to `out`
+pub fn execute<W: Write>(command: Command, out: &mut W) -> Result<i32> {
+    match command {
+        Command::Hash { input, hash: hash_args, batch, output } => {
+            hash(&input, &hash_args.config(HashConfig::default())?, &batch, output.output_format, out)
+        },
+        Command::Compare { input, hash, batch, matcher, all, output } => {
+            compare(&input, &hash.config(HashConfig::default())?, &batch, &matcher.matcher(), all, output.output_format, out)
+        },
+        Command::Scan { references, input, hash, batch, matcher, output } => {
+            let references = ReferenceSet::load(&references)?;
+            let config = hash.config(references.hash_config())?;
+            let scanner = Scanner::new(references, matcher.matcher()).with_hash_config(config)?;
+            eprintln!("Loaded {} reference hashes.", scanner.references().len());
+            let report = scanner.scan_batch(hash_batch(&input, scanner.hash_config(), &batch)?);
+            write_scan_report(&report, output.output_format, out)
+        },
+        Command::Index { command: IndexCommand::Build { output, input, hash, batch } } => {
+            let (paths, hashs, failures) = hash_images(&input, &hash.config(HashConfig::default())?, &batch)?;
+            let tree: BkTree<String> = paths.iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .zip(hashs)
+                .map(|(id, hash)| (hash, id))
+                .collect();
+            tree.save_json(&output)?;
+            eprintln!("Saved {} hashes to {}.", tree.len(), output.display());
+            Ok(finish(&failures))
+        },
+        Command::Index { command: IndexCommand::Query { index, input, hash, batch, radius, matcher, output } } => {
+            let tree: BkTree<String> = BkTree::load_json(&index)?;
+            let tree_config = tree.iter().next().map(|(hash, _)| hash.config);
+            let config = hash.config(tree_config.unwrap_or_default())?;
+            if let Some(expected) = tree_config.filter(|expected| !expected.is_comparable(&config)) {
+                return Err(ForbildError::ConfigMismatch { expected, found: config });
+            }
+            let report = query_index(&tree, hash_batch(&input, &config, &batch)?, radius, &matcher.matcher());
+            write_scan_report(&report, output.output_format, out)
+        },
+        Command::Dedup { input, hash, batch, matcher, output } => {
+            dedup(&input, &hash.config(HashConfig::default())?, &batch, &matcher.matcher(), output.output_format, out)
+        },
+        Command::Cache { command: CacheCommand::Prune { cache } } => prune_cache(&cache, out),
+        Command::Cache { command: CacheCommand::Verify { cache, output } } => verify_cache(&cache, output.output_format, out),
+        Command::DebugImage { paths, output_dir, size } => debug_image(&paths, &output_dir, size, out),
+    }
+}
+
+/// Finds the images of the input. Entries that could not be read are returned as well,
+/// other skipped entries (e.g. no image) are left out silently.
+fn find_images(input: &InputArgs) -> Result<(Vec<PathBuf>, Vec<Skipped>)> {
+    let walk = Walker::new(input.walk_options()).walk(&input.paths)?;
+    let errors = walk.errors().cloned().collect();
+    Ok((walk.paths(), errors))
+}
+
+fn skipped_message(reason: SkipReason) -> String {
+    match reason {
+        SkipReason::Unreadable(message) => message,
+        reason => reason.to_string(),
+    }
+}
+
+fn walk_failure(skipped: Skipped) -> Failure {
+    Failure { path: skipped.path, stage: Stage::Walk, message: skipped_message(skipped.reason), panicked: false }
+}
+
+/// Finds and hashes the images on all threads, using the cache and checkpoint of the arguments.
+/// Images that cannot be found or hashed are skipped and returned as failures.
+fn hash_batch(input: &InputArgs, config: &HashConfig, args: &BatchArgs) -> Result<Batch> {
+    crate::check_size(config.size)?;
+    eprintln!("Hash config: {}", config);
+    let (paths, skipped) = find_images(input)?;
+    let limits = args.limits();
+    let mut cache = match &args.cache {
+        Some(cache_path) => Some(HashCache::load(cache_path)?.with_content_hash(args.content_hash)),
+        None => None,
+    };
+    let mut hash = |chunk: &[PathBuf]| match &mut cache {
+        Some(cache) => cache.hash_files(chunk, config, &limits),
+        None => hash_files_with_config(chunk, config, &limits),
+    };
+
+    let mut batch = match &args.checkpoint {
+        Some(checkpoint_path) => {
+            let job = checkpoint_job(input, config);
+            let checkpoint = if args.resume {
+                Checkpoint::resume(checkpoint_path, &job)?
+            } else if args.overwrite_checkpoint {
+                Checkpoint::replace(checkpoint_path, &job)?
+            } else {
+                Checkpoint::create(checkpoint_path, &job)?
+            };
+            let mut checkpoint = checkpoint.with_interval(args.checkpoint_interval);
+            if args.resume {
+                eprintln!("Resuming after {} of {} images.", checkpoint.len(), paths.len());
+            }
+            let batch = checkpoint.run(&paths, &mut hash)?;
+            checkpoint.remove()?;
+            batch
+        },
+        None => hash(&paths),
+    };
+
+    if let (Some(cache), Some(cache_path)) = (&cache, &args.cache) {
+        cache.save(cache_path)?;
+        eprintln!("Took {} of {} hashes from the cache.", batch.cached, paths.len());
+    }
+    let mut failures: Vec<Failure> = skipped.into_iter().map(walk_failure).collect();
+    failures.append(&mut batch.failures);
+    batch.failures = failures;
+    Ok(batch)
+}
+
+/// Identifies the job of a checkpoint, it can only be continued with the same input and config.
+/// The order and spelling of the paths and the order of the globs do not matter.
+fn checkpoint_job(input: &InputArgs, config: &HashConfig) -> String {
+    let mut paths: Vec<String> = input.paths.iter()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()).to_string_lossy().into_owned())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    let sorted = |globs: &[String]| {
+        let mut globs = globs.to_vec();
+        globs.sort();
+        globs.dedup();
+        globs
+    };
+
+    serde_json::json!({
+        "config": config,
+        "paths": paths,
+        "recursive": input.recursive,
+        "include": sorted(&input.include),
+        "exclude": sorted(&input.exclude),
+    }).to_string()
+}
+
+fn hash_images(input: &InputArgs, config: &HashConfig, args: &BatchArgs) -> Result<(Vec<PathBuf>, Vec<Hash>, Vec<Failure>)> {
+    let mut batch = hash_batch(input, config, args)?;
+    let failures = std::mem::take(&mut batch.failures);
+    let (paths, hashs) = batch.into_hashs();
+    Ok((paths, hashs, failures))
+}
+
+/// Prints the summary of the failed files and returns the exit code
+fn finish(failures: &[Failure]) -> i32 {
+    if failures.is_empty() {
+        return EXIT_SUCCESS;
+    }
+    eprintln!("{} files failed:", failures.len());
+    for failure in failures {
+        eprintln!("  {}", failure);
+    }
+    EXIT_ERRORS
+}
+
+fn hash<W: Write>(input: &InputArgs, config: &HashConfig, args: &BatchArgs, format: OutputFormat, out: &mut W) -> Result<i32> {
+    let batch = hash_batch(input, config, args)?;
+    let failures = batch.failures;
+    let records: Vec<HashRecord> = batch.hashed.into_iter().map(HashRecord::from).collect();
+
+    match format {
+        // The text format can be loaded as reference file by `scan`
+        OutputFormat::Text => {
+            for record in &records {
+                writeln!(out, "{};{}", record.path.display(), record.hash)?;
+            }
+        },
+        OutputFormat::Csv => {
+            writeln!(out, "path,hash,config")?;
+            for record in &records {
+                writeln!(out, "{},{},{}", csv_field(&record.path.to_string_lossy()), record.hash.to_string_hex(),
+                         csv_field(&record.hash.config.to_string()))?;
+            }
+        },
+        OutputFormat::Json => writeln!(out, "{}", records_to_json(&records)?)?,
+    }
+    Ok(finish(&failures))
+}
+
+fn compare<W: Write>(input: &InputArgs, config: &HashConfig, args: &BatchArgs, matcher: &Matcher, all: bool, format: OutputFormat, out: &mut W) -> Result<i32> {
+    let (paths, hashs, failures) = hash_images(input, config, args)?;
+    eprintln!("Comparing {} images...", paths.len());
+
+    // With `all`, there are too many pairs to keep them in memory
+    match format {
+        OutputFormat::Text => for_each_pair(&paths, &hashs, matcher, all, |pair| {
+            writeln!(out, "{} ~ {}: {}", pair.path1.display(), pair.path2.display(), describe(&pair))?;
+            Ok(())
+        })?,
+        format => {
+            let mut report = ReportWriter::new(&mut *out, format.report_format())?;
+            for_each_pair(&paths, &hashs, matcher, all, |pair| report.write(&pair))?;
+            report.finish()?;
+        },
+    }
+    Ok(finish(&failures))
+}
+
+fn describe(pair: &PairReport) -> String {
+    match pair.weighted {
+        Some(weighted) => format!("{} (hamming {}, weighted {:.2})", pair.verdict, pair.hamming, weighted),
+        None => format!("{} (hamming {})", pair.verdict, pair.hamming),
+    }
+}
+
+fn dedup<W: Write>(input: &InputArgs, config: &HashConfig, args: &BatchArgs, matcher: &Matcher, format: OutputFormat, out: &mut W) -> Result<i32> {
+    let (paths, hashs, failures) = hash_images(input, config, args)?;
+    let groups = group_duplicates(&paths, &compare_all(&paths, &hashs, matcher, false)?);
+
+    match format {
+        OutputFormat::Text => {
+            for (i, group) in groups.iter().enumerate() {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                for path in group {
+                    writeln!(out, "{}", path.display())?;
+                }
+            }
+        },
+        OutputFormat::Csv => {
+            writeln!(out, "group,path")?;
+            for (i, group) in groups.iter().enumerate() {
+                for path in group {
+                    writeln!(out, "{},{}", i, csv_field(&path.to_string_lossy()))?;
+                }
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &groups)?;
+            writeln!(out)?;
+        },
+    }
+    Ok(finish(&failures))
+}
+
+fn prune_cache<W: Write>(cache_path: &Path, out: &mut W) -> Result<i32> {
+    let mut cache = HashCache::load(cache_path)?;
+    let stale = cache.prune();
+    cache.save(cache_path)?;
+    for entry in &stale {
+        writeln!(out, "{}", entry.path.display())?;
+    }
+    eprintln!("Removed {} entries, {} are left.", stale.len(), cache.len());
+    Ok(EXIT_SUCCESS)
+}
+
+fn verify_cache<W: Write>(cache_path: &Path, format: OutputFormat, out: &mut W) -> Result<i32> {
+    let cache = HashCache::load(cache_path)?;
+    let stale = cache.verify();
+
+    match format {
+        OutputFormat::Text => {
+            for entry in &stale {
+                writeln!(out, "{}: {}", entry.path.display(), entry.status.name())?;
+            }
+        },
+        OutputFormat::Csv => {
+            writeln!(out, "path,status")?;
+            for entry in &stale {
+                writeln!(out, "{},{}", csv_field(&entry.path.to_string_lossy()), entry.status.name())?;
+            }
+        },
+        OutputFormat::Json => {
+            let entries: Vec<serde_json::Value> = stale.iter()
+                .map(|entry| serde_json::json!({ "path": entry.path, "status": entry.status.name() }))
+                .collect();
+            serde_json::to_writer_pretty(&mut *out, &entries)?;
+            writeln!(out)?;
+        },
+    }
+    eprintln!("Verified {} entries: {} are missing or changed.", cache.len(), stale.len());
+    Ok(if stale.is_empty() { EXIT_SUCCESS } else { EXIT_ERRORS })
+}
+
+fn query_index(tree: &BkTree<String>, batch: Batch, radius: usize, matcher: &Matcher) -> ScanReport {
+    let mut report = ScanReport { files: batch.hashed.len() + batch.failures.len(), ..ScanReport::default() };
+    for file in &batch.hashed {
+        report.hits.extend(tree.find_matches(&file.hash, radius, matcher).into_iter().map(|ranked| ScanHit {
+            path: file.path.clone(),
+            reference: ranked.value.clone(),
+            hamming: ranked.verdict.hamming_distance(),
+            weighted: ranked.weighted,
+            stage: ranked.verdict.name().to_string(),
+            flip: file.hash.flip,
+            reference_flip: ranked.hash.flip,
+            config: file.hash.config,
+        }));
+    }
+    report.errors = batch.failures;
+    report
+}
+
+fn write_scan_report<W: Write>(report: &ScanReport, format: OutputFormat, out: &mut W) -> Result<i32> {
+    for failure in &report.errors {
+        eprintln!("ERROR: {}", failure);
+    }
+    eprintln!("Scanned {} images: {} hits, {} errors.", report.files, report.hits.len(), report.errors.len());
+
+    match format {
+        OutputFormat::Text => {
+            for hit in &report.hits {
+                let weighted = hit.weighted.map(|w| format!(", weighted {:.2}", w)).unwrap_or_default();
+                writeln!(out, "{} matches {}: {} (hamming {}{})",
+                         hit.path.display(), hit.reference, hit.stage, hit.hamming, weighted)?;
+            }
+        },
+        format => report.write_hits(out, format.report_format())?,
+    }
+    Ok(report.exit_code())
+}
+
+fn debug_image<W: Write>(paths: &[PathBuf], output_dir: &Path, size: u32, out: &mut W) -> Result<i32> {
+    crate::check_size(size)?;
+    for (i, path) in paths.iter().enumerate() {
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let save = |step: &str, img: &DynamicImage| -> Result<()> {
+            let target = output_dir.join(format!("{}_{}_{}.png", i, stem, step));
+            img.save(&target)?;
+            writeln!(std::io::stderr(), "Saved {}", target.display())?;
+            Ok(())
+        };
+
+        let img = editing::import_image_from_file(path)?;
+        let img = editing::color_to_grayscale(img);
+        save("1_gray", &img)?;
+
+        // The small images are enlarged to make them visible
+        let img = editing::downsample(img, size);
+        save("2_downsampled", &img.resize(100, 100, FilterType::Nearest))?;
+
+        let mut img = editing::grayscale_to_luma(img);
+        let img = editing::mirror_by_brightest_pixel(&mut img).clone();
+        save("3_mirrored", &DynamicImage::ImageLuma8(img.clone()).resize(100, 100, FilterType::Nearest))?;
+
+        let img = editing::to_binary_image_by_quadrant(img);
+        save("4_binary", &DynamicImage::ImageLuma8(img).resize(100, 100, FilterType::Nearest))?;
+
+        writeln!(out, "{}: {}", i, path.display())?;
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    const BOATS: &str = "data/original/2017_China_Chongqing_Boats.jpg";
+    const TRAFFIC: &str = "data/original/2015_Japan_Tokyo_Traffic.jpg";
+
+    fn run_command(args: &[&str]) -> (i32, String) {
+        let cli = Cli::try_parse_from(std::iter::once("forbild").chain(args.iter().copied())).unwrap();
+        let mut out = Vec::new();
+        let code = execute(cli.command, &mut out).unwrap();
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("forbild_cli_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = Cli::try_parse_from([
+            "forbild", "-t", "2", "compare", "data", "-r", "--include", "*.jpg", "--exclude", "out/*",
+            "--hamming", "5", "--symmetric", "--rotation-invariant", "--output-format", "json",
+        ]).unwrap();
+        assert_eq!(cli.threads, 2);
+        match cli.command {
+            Command::Compare { input, hash, batch, matcher, all, output } => {
+                assert_eq!(hash.config(HashConfig::default()).unwrap(), HashConfig::default());
+                assert_eq!(batch.cache, None);
+                assert_eq!(input.walk_options(), WalkOptions {
+                    recursive: true,
+                    include: vec!["*.jpg".to_string()],
+                    exclude: vec!["out/*".to_string()],
+                });
+                assert_eq!(matcher.matcher(), Matcher::new(5, DEFAULT_WEIGHTED_THRESHOLD)
+                    .with_weighted_mode(WeightedMode::Symmetric)
+                    .with_rotation_invariance(true));
+                assert!(!all);
+                assert_eq!(output.output_format, OutputFormat::Json);
+            },
+            command => panic!("Unexpected command {:?}", command),
+        }
+
+        let cli = Cli::try_parse_from([
+            "forbild", "hash", "data", "--size", "8", "--filter", "catmull_rom", "--luma", "rec601", "--normalization", "centroid",
+            "--jpeg-decoding", "dc",
+        ]).unwrap();
+        match cli.command {
+            Command::Hash { hash, .. } => {
+                assert_eq!(hash.config(HashConfig::default()).unwrap(), HashConfig::default()
+                    .with_size(8)
+                    .with_filter(ResizeFilter::CatmullRom)
+                    .with_luma(LumaCoefficients::Rec601)
+                    .with_normalization(Normalization::Centroid)
+                    .with_jpeg_decoding(JpegDecoding::Dc));
+            },
+            command => panic!("Unexpected command {:?}", command),
+        }
+
+        assert!(Cli::try_parse_from(["forbild", "hash"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "hash", "data", "--filter", "bicubic"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "hash", "data", "--jpeg-decoding", "half"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "hash", "data", "--bit-exact", "--filter", "gaussian"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "scan", "references.txt"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "compare", "data", "--output-format", "xml"]).is_err());
+        assert!(Cli::try_parse_from(["forbild", "hash", "data", "--content-hash"]).is_err());
+    }
+
+    #[test]
+    fn test_hash_and_scan() {
+        let (code, text) = run_command(&["hash", BOATS]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert!(text.starts_with(&format!("{};", BOATS)));
+
+        let references = temp_path("references.txt");
+        std::fs::write(&references, &text).unwrap();
+        let (code, text) = run_command(&["scan", references.to_str().unwrap(), "data/original", "-f", "csv"]);
+        assert_eq!(code, crate::scan::EXIT_HITS);
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.lines().nth(1).unwrap().contains(",identical,"));
+        std::fs::remove_file(&references).unwrap();
+
+        let (code, text) = run_command(&["hash", BOATS, "--output-format", "csv", "--size", "8", "--bit-exact"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        let line = text.lines().nth(1).unwrap();
+        assert_eq!(line.split(',').nth(1).unwrap().len(), 16);
+        assert!(line.ends_with(&format!(",{}", HashConfig::bit_exact().with_size(8))));
+    }
+
+    #[test]
+    fn test_hash_config() {
+        let (code, text) = run_command(&["hash", BOATS, "--filter", "area", "--threshold-statistic", "mean", "-f", "json"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        let records = crate::record::records_from_json(&text).unwrap();
+        let config = HashConfig::default().with_filter(ResizeFilter::Area).with_threshold(ThresholdStatistic::Mean);
+        assert_eq!(records[0].hash.config, config);
+
+        let json = serde_json::to_string(&config).unwrap();
+        let (_, text) = run_command(&["hash", BOATS, "--config", &json, "--size", "8", "-f", "json"]);
+        assert_eq!(crate::record::records_from_json(&text).unwrap()[0].hash.config, config.with_size(8));
+        let cli = Cli::try_parse_from(["forbild", "hash", BOATS, "--config", "{}"]).unwrap();
+        assert!(matches!(execute(cli.command, &mut Vec::new()), Err(ForbildError::Json(_))));
+
+        let (_, text) = run_command(&["hash", BOATS, "--normalization", "none", "-f", "json"]);
+        let hash = &crate::record::records_from_json(&text).unwrap()[0].hash;
+        assert_eq!(hash.config.normalization, Normalization::None);
+        assert_eq!(hash.flip, crate::orientation::Orientation::Identity);
+
+        // The images are hashed with the config of the references, other configs are rejected
+        let references = temp_path("config_references.json");
+        let (_, text) = run_command(&["hash", BOATS, "--filter", "area", "--threshold-statistic", "mean", "-f", "json"]);
+        std::fs::write(&references, &text).unwrap();
+        let (code, _) = run_command(&["scan", references.to_str().unwrap(), BOATS]);
+        assert_eq!(code, crate::scan::EXIT_HITS);
+        let cli = Cli::try_parse_from(["forbild", "scan", references.to_str().unwrap(), BOATS, "--filter", "gaussian"]).unwrap();
+        assert!(matches!(execute(cli.command, &mut Vec::new()), Err(ForbildError::ConfigMismatch { .. })));
+        std::fs::remove_file(&references).unwrap();
+
+        let (_, text) = run_command(&["hash", BOATS, "--bit-exact", "--size", "8", "-f", "json"]);
+        assert_eq!(crate::record::records_from_json(&text).unwrap()[0].hash.config, HashConfig::bit_exact().with_size(8));
+
+        // The records keep the dimensions of the image header when the JPEG is decoded at a reduced size
+        let (_, text) = run_command(&["hash", BOATS, "--jpeg-decoding", "dc", "-f", "json"]);
+        let record = &crate::record::records_from_json(&text).unwrap()[0];
+        assert_eq!(record.hash.config.jpeg, JpegDecoding::Dc);
+        assert_eq!((record.width, record.height), (900, 600));
+    }
+
+    #[test]
+    fn test_compare_and_dedup() {
+        // The intermediate images of the traffic image are hashed as the original image
+        let (code, text) = run_command(&["compare", "data", "-r", "--exclude", "*Boats*"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert!(text.lines().any(|line| line.contains("01_gray.jpg") && line.contains("identical")));
+
+        let (code, text) = run_command(&["dedup", "data", "-r", "-f", "csv"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(text.lines().next(), Some("group,path"));
+        assert!(text.contains("2015_Japan_Tokyo_Traffic.jpg"));
+        assert!(!text.contains("2017_China_Chongqing_Boats.jpg"));
+    }
+
+    #[test]
+    fn test_index() {
+        let index = temp_path("index.json");
+        let (code, _) = run_command(&["index", "build", "-o", index.to_str().unwrap(), "data/original"]);
+        assert_eq!(code, EXIT_SUCCESS);
+
+        let (code, text) = run_command(&["index", "query", index.to_str().unwrap(), BOATS]);
+        assert_eq!(code, crate::scan::EXIT_HITS);
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains("identical"));
+        std::fs::remove_file(&index).unwrap();
+    }
+
+    #[test]
+    fn test_debug_image() {
+        let dir = temp_path("debug");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (code, _) = run_command(&["debug-image", BOATS, "-o", dir.to_str().unwrap()]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let (code, text) = run_command(&["hash", BOATS, "--max-width", "800"]);
+        assert_eq!((code, text.as_str()), (EXIT_ERRORS, ""));
+        let (code, _) = run_command(&["hash", BOATS, "--max-width", "900", "--max-height", "600"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        let (code, _) = run_command(&["hash", BOATS, "--max-file-size", "1000"]);
+        assert_eq!(code, EXIT_ERRORS);
+
+        let cli = Cli::try_parse_from(["forbild", "hash", "data", "--max-pixels", "1000", "--max-alloc", "5000"]).unwrap();
+        match cli.command {
+            Command::Hash { batch, .. } => assert_eq!(batch.limits(), DecodeLimits::default().with_max_pixels(1000).with_max_alloc(5000)),
+            command => panic!("Unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_missing_path() {
+        let cli = Cli::try_parse_from(["forbild", "hash", "data/original/NoImageToBeFoundHere.jpg"]).unwrap();
+        assert_eq!(execute(cli.command, &mut Vec::new()).unwrap(), EXIT_ERRORS);
+
+        let cli = Cli::try_parse_from(["forbild", "hash", "--include", "[", "data"]).unwrap();
+        assert!(execute(cli.command, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_broken_file() {
+        // A truncated JPEG is found as image, but cannot be decoded
+        let broken = temp_path("broken.jpg");
+        std::fs::write(&broken, &std::fs::read(BOATS).unwrap()[..1000]).unwrap();
+
+        let (code, text) = run_command(&["hash", broken.to_str().unwrap(), BOATS]);
+        assert_eq!(code, EXIT_ERRORS);
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.starts_with(&format!("{};", BOATS)));
+
+        let copy = temp_path("copy.jpg");
+        std::fs::copy(BOATS, &copy).unwrap();
+        let (code, text) = run_command(&["compare", BOATS, broken.to_str().unwrap(), copy.to_str().unwrap()]);
+        assert_eq!(code, EXIT_ERRORS);
+        assert!(text.contains("identical"));
+        std::fs::remove_file(&broken).unwrap();
+        std::fs::remove_file(&copy).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = temp_path("cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("a.jpg");
+        std::fs::copy(BOATS, &image).unwrap();
+        let cache = dir.join("cache.ndjson");
+        let (image, cache) = (image.to_str().unwrap(), cache.to_str().unwrap());
+
+        let (code, hashed) = run_command(&["hash", image, "--cache", cache, "--content-hash"]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(run_command(&["hash", image, "--cache", cache]), (EXIT_SUCCESS, hashed));
+        assert_eq!(run_command(&["cache", "verify", cache]), (EXIT_SUCCESS, String::new()));
+
+        std::fs::write(image, "changed").unwrap();
+        let (code, text) = run_command(&["cache", "verify", cache, "-f", "csv"]);
+        assert_eq!(code, EXIT_ERRORS);
+        assert!(text.lines().nth(1).unwrap().ends_with("a.jpg,changed"));
+
+        let (code, text) = run_command(&["cache", "prune", cache]);
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(text.lines().count(), 1);
+        assert!(std::fs::read_to_string(cache).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = temp_path("resume");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(BOATS, dir.join("a.jpg")).unwrap();
+        std::fs::copy(TRAFFIC, dir.join("b.jpg")).unwrap();
+        std::fs::write(dir.join("c.jpg"), &std::fs::read(BOATS).unwrap()[..1000]).unwrap();
+        let checkpoint = dir.join("checkpoint.ndjson");
+        let (input, checkpoint) = (dir.to_str().unwrap(), checkpoint.to_str().unwrap());
+        let expected = run_command(&["hash", input]);
+        assert_eq!(expected.0, EXIT_ERRORS);
+
+        let args = ["forbild", "hash", input, "--checkpoint", checkpoint, "--resume"];
+        assert_eq!(run_command(&args[1..]), expected);
+        assert!(!Path::new(checkpoint).exists());
+
+        // A run killed after the first image
+        let job = match Cli::try_parse_from(args).unwrap().command {
+            Command::Hash { input, hash, .. } => checkpoint_job(&input, &hash.config(HashConfig::default()).unwrap()),
+            _ => unreachable!(),
+        };
+        let mut killed = Checkpoint::create(Path::new(checkpoint), &job).unwrap();
+        killed.run(&[dir.join("a.jpg")], |chunk| hash_files_with_config(chunk, &HashConfig::default(), &DecodeLimits::default())).unwrap();
+        drop(killed);
+        assert_eq!(run_command(&args[1..]), expected);
+
+        // A checkpoint of other arguments is not continued
+        let mut other = Checkpoint::create(Path::new(checkpoint), "other").unwrap();
+        other.run(&[], |_| Batch::default()).unwrap();
+        drop(other);
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(execute(cli.command, &mut Vec::new()), Err(crate::ForbildError::InvalidCheckpoint(_))));
+
+        // An existing checkpoint is only replaced if asked to
+        let cli = Cli::try_parse_from(["forbild", "hash", input, "--checkpoint", checkpoint]).unwrap();
+        assert!(matches!(execute(cli.command, &mut Vec::new()), Err(crate::ForbildError::InvalidCheckpoint(_))));
+        assert!(Path::new(checkpoint).exists());
+        assert_eq!(run_command(&["hash", input, "--checkpoint", checkpoint, "--overwrite-checkpoint"]), expected);
+        assert!(Cli::try_parse_from(["forbild", "hash", input, "--checkpoint", checkpoint, "--resume", "--overwrite-checkpoint"]).is_err());
+
+        // The same job with other spellings and orders of the arguments
+        let job = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Command::Hash { input, hash, .. } => checkpoint_job(&input, &hash.config(HashConfig::default()).unwrap()),
+            _ => unreachable!(),
+        };
+        let dot = format!("{}/.", input);
+        assert_eq!(
+            job(&["forbild", "hash", input, BOATS, "--include", "*.jpg", "--include", "*.png"]),
+            job(&["forbild", "hash", BOATS, &dot, "--include", "*.png", "--include", "*.jpg"])
+        );
+        assert_ne!(job(&["forbild", "hash", input]), job(&["forbild", "hash", input, "--size", "8"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Configuration of the preprocessing and hashing steps.
+//!
+//! The default `HashConfig` is the published algorithm: Rec. 709 luma, downsampling the gamma-encoded
+//! values with the Gaussian filter, quadrant median thresholds and mirroring by the brightest pixel.
+//! Every hash keeps the config it was created with (`Hash::config`), and it is written into every
+//! hash record, so that results can be reproduced. Only hashes of the same config should be compared.
+//!
+//! The floating point filters of `image` may round differently on other CPUs, compilers or `image`
+//! versions. `ResizeFilter::Area` with `Averaging::Srgb` only uses integer arithmetic after decoding,
+//! so its hashes are bit-exact everywhere (see `HashConfig::bit_exact`).
+
+use std::fmt;
+use image::imageops::FilterType;
+
+use crate::editing::Normalization;
+use crate::DEFAULT_SIZE;
+
+/// Filter used to downsample the grayscale image to the hash grid
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    #[default]
+    Gaussian,
+    Lanczos3,
+    /// Integer area average (`editing::area_downsample`), together with an integer grayscale conversion
+    Area,
+}
+
+impl ResizeFilter {
+    /// All filters, in the order of their index in the database header (see `db`)
+    pub const ALL: [ResizeFilter; 6] = [
+        ResizeFilter::Nearest,
+        ResizeFilter::Triangle,
+        ResizeFilter::CatmullRom,
+        ResizeFilter::Gaussian,
+        ResizeFilter::Lanczos3,
+        ResizeFilter::Area,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmull_rom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+            ResizeFilter::Area => "area",
+        }
+    }
+
+    /// The filter of `image`, `None` for the integer area average
+    pub fn filter_type(&self) -> Option<FilterType> {
+        match self {
+            ResizeFilter::Nearest => Some(FilterType::Nearest),
+            ResizeFilter::Triangle => Some(FilterType::Triangle),
+            ResizeFilter::CatmullRom => Some(FilterType::CatmullRom),
+            ResizeFilter::Gaussian => Some(FilterType::Gaussian),
+            ResizeFilter::Lanczos3 => Some(FilterType::Lanczos3),
+            ResizeFilter::Area => None,
+        }
+    }
+}
+
+/// Weights of the red, green and blue channel for the conversion to grayscale
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum LumaCoefficients {
+    /// 0.299, 0.587, 0.114 (SDTV)
+    Rec601,
+    /// 0.2126, 0.7152, 0.0722 (HDTV and sRGB), as used by `DynamicImage::grayscale`
+    #[default]
+    Rec709,
+}
+
+impl LumaCoefficients {
+    /// All coefficients, in the order of their index in the database header (see `db`)
+    pub const ALL: [LumaCoefficients; 2] = [LumaCoefficients::Rec601, LumaCoefficients::Rec709];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LumaCoefficients::Rec601 => "rec601",
+            LumaCoefficients::Rec709 => "rec709",
+        }
+    }
+
+    pub fn weights(&self) -> [f32; 3] {
+        match self {
+            LumaCoefficients::Rec601 => [0.299, 0.587, 0.114],
+            LumaCoefficients::Rec709 => [0.2126, 0.7152, 0.0722],
+        }
+    }
+
+    /// The weights in units of 1/10000, used by the integer grayscale conversion
+    pub fn integer_weights(&self) -> [u32; 3] {
+        match self {
+            LumaCoefficients::Rec601 => [2990, 5870, 1140],
+            LumaCoefficients::Rec709 => [2126, 7152, 722],
+        }
+    }
+}
+
+/// Which values are averaged when downsampling
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum Averaging {
+    /// The gamma-encoded sRGB values are averaged directly
+    #[default]
+    Srgb,
+    /// The values are converted to linear light before averaging and encoded again afterwards
+    Linear,
+}
+
+impl Averaging {
+    /// All averagings, in the order of their index in the database header (see `db`)
+    pub const ALL: [Averaging; 2] = [Averaging::Srgb, Averaging::Linear];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Averaging::Srgb => "srgb",
+            Averaging::Linear => "linear",
+        }
+    }
+}
+
+/// Threshold of each subarea for the binary hash. It is stored in `Hash::subarea_medians`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum ThresholdStatistic {
+    /// Half of the pixels of a subarea become 1 bits
+    #[default]
+    Median,
+    /// Mean of the subarea, rounded down
+    Mean,
+}
+
+impl ThresholdStatistic {
+    /// All statistics, in the order of their index in the database header (see `db`)
+    pub const ALL: [ThresholdStatistic; 2] = [ThresholdStatistic::Median, ThresholdStatistic::Mean];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThresholdStatistic::Median => "median",
+            ThresholdStatistic::Mean => "mean",
+        }
+    }
+}
+
+/// How JPEG files are decoded by the constructors that read an encoded image (`Hash::from_path_with_config` etc.).
+/// The hash only needs a few pixels, so decoding at a reduced scale skips most of the work of the inverse DCT.
+/// The reduced scale images are slightly different, so the hashes may differ in a few bits from the full decode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum JpegDecoding {
+    /// The image is decoded at full resolution
+    #[default]
+    Full,
+    /// The image is decoded with the smallest scaled IDCT (1/2, 1/4 or 1/8) that keeps at least
+    /// `SCALED_OVERSAMPLING` pixels per hash pixel in the longer direction
+    Scaled,
+    /// Only the DC coefficient of every 8x8 block is decoded, which gives an image scaled by 1/8
+    Dc,
+}
+
+/// Pixels per hash pixel that `JpegDecoding::Scaled` keeps at least
+pub const SCALED_OVERSAMPLING: u32 = 4;
+
+impl JpegDecoding {
+    /// All decodings, in the order of their index in the database header (see `db`)
+    pub const ALL: [JpegDecoding; 3] = [JpegDecoding::Full, JpegDecoding::Scaled, JpegDecoding::Dc];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JpegDecoding::Full => "full",
+            JpegDecoding::Scaled => "scaled",
+            JpegDecoding::Dc => "dc",
+        }
+    }
+
+    /// The size to request from the scaled JPEG decoder for a hash grid of size x size, `None` for the full decode
+    pub fn requested_size(&self, size: u32) -> Option<(u16, u16)> {
+        match self {
+            JpegDecoding::Full => None,
+            JpegDecoding::Scaled => {
+                let requested = (size * SCALED_OVERSAMPLING).min(u16::MAX as u32) as u16;
+                Some((requested, requested))
+            },
+            JpegDecoding::Dc => Some((1, 1)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HashConfig {
+    /// Width and height of the hash grid, a positive multiple of 8
+    pub size: u32,
+    pub filter: ResizeFilter,
+    pub luma: LumaCoefficients,
+    pub averaging: Averaging,
+    pub threshold: ThresholdStatistic,
+    pub normalization: Normalization,
+    /// Only used by the constructors that decode the image themselves. Missing in records written
+    /// before it was added, which were all decoded in full.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub jpeg: JpegDecoding,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        HashConfig {
+            size: DEFAULT_SIZE,
+            filter: ResizeFilter::default(),
+            luma: LumaCoefficients::default(),
+            averaging: Averaging::default(),
+            threshold: ThresholdStatistic::default(),
+            normalization: Normalization::default(),
+            jpeg: JpegDecoding::default(),
+        }
+    }
+}
+
+impl HashConfig {
+    /// The default config with the integer area average, which gives the same hash on every platform
+    pub fn bit_exact() -> HashConfig {
+        HashConfig::default().with_filter(ResizeFilter::Area)
+    }
+
+    /// Whether hashes of both configs can be compared, i.e. all fields except the JPEG decoding are equal.
+    /// Decoding at a reduced scale only changes a few bits.
+    pub fn is_comparable(&self, other: &HashConfig) -> bool {
+        self.with_jpeg_decoding(JpegDecoding::Full) == other.with_jpeg_decoding(JpegDecoding::Full)
+    }
+
+    /// Whether the preprocessing only uses integer arithmetic
+    pub fn is_bit_exact(&self) -> bool {
+        self.filter == ResizeFilter::Area && self.averaging == Averaging::Srgb
+    }
+
+    pub fn with_size(mut self, size: u32) -> HashConfig {
+        self.size = size;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: ResizeFilter) -> HashConfig {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_luma(mut self, luma: LumaCoefficients) -> HashConfig {
+        self.luma = luma;
+        self
+    }
+
+    pub fn with_averaging(mut self, averaging: Averaging) -> HashConfig {
+        self.averaging = averaging;
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: ThresholdStatistic) -> HashConfig {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_normalization(mut self, normalization: Normalization) -> HashConfig {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn with_jpeg_decoding(mut self, jpeg: JpegDecoding) -> HashConfig {
+        self.jpeg = jpeg;
+        self
+    }
+}
+
+impl fmt::Display for HashConfig {
+    /// Writes all fields as `key=value` pairs separated by spaces, with the names used in JSON
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "size={} filter={} luma={} averaging={} threshold={} normalization={} jpeg={}",
+               self.size, self.filter.name(), self.luma.name(), self.averaging.name(),
+               self.threshold.name(), self.normalization.name(), self.jpeg.name())
+    }
+}
@@ -0,0 +1,764 @@
+//! Compact binary container for large hash databases.
+//!
+//! All integers are stored little-endian. A database file consists of three parts:
+//!
+//! ```text
+//! Header (40 bytes)
+//!   magic                "FORBILDB"      8 bytes
+//!   version              u16             2 bytes
+//!   grid size            u16             2 bytes
+//!   flags                u32             4 bytes  (1: grayscale image, 2: subarea medians, 4: flip)
+//!   record count         u64             8 bytes
+//!   string table offset  u64             8 bytes
+//!   hash config          6 x u8          6 bytes  (index in `ALL` of filter, luma, averaging, threshold,
+//!                                                  normalization and JPEG decoding, see `HashConfig`)
+//!   reserved             2 bytes, 0
+//! Records (record count * record size bytes)
+//!   binary hash          size*size/8 bytes, 64 bit words as in `Hash::binary256`
+//!   grayscale image      size*size bytes   (only with flag 1, hashes without grayscale data are rejected)
+//!   subarea medians      4 bytes           (only with flag 2, order: [0][0], [0][1], [1][0], [1][1])
+//!   flip                 1 byte            (only with flag 4, index of `Hash::flip` in `Orientation::ALL`)
+//!   id offset            u64               (relative to the string table)
+//!   id length            u32
+//! String table
+//!   UTF-8 identifiers (e.g. paths) of all records, concatenated
+//! ```
+//!
+//! All hashes of a database have the config of the first hash added, `DbWriter` rejects others.
+//! Since all records have the same size, `DbView` can access any record of a memory-mapped
+//! file directly without parsing the records before it.
+
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::check_size;
+use crate::config::{Averaging, HashConfig, JpegDecoding, LumaCoefficients, ResizeFilter, ThresholdStatistic};
+use crate::editing::Normalization;
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::orientation::Orientation;
+
+pub const MAGIC: [u8; 8] = *b"FORBILDB";
+pub const VERSION: u16 = 2;
+pub const HEADER_LEN: usize = 40;
+
+pub const FLAG_GRAYSCALE: u32 = 1;
+pub const FLAG_MEDIANS: u32 = 2;
+pub const FLAG_FLIP: u32 = 4;
+
+/// Size and content of the fixed-size records of a database
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordLayout {
+    pub size: u32,
+    pub flags: u32,
+}
+
+impl RecordLayout {
+    pub fn new(size: u32, grayscale: bool, medians: bool) -> Result<RecordLayout> {
+        check_size(size)?;
+        if size > u16::MAX as u32 {
+            return Err(ForbildError::InvalidGridSize(size));
+        }
+
+        let mut flags = 0;
+        if grayscale { flags |= FLAG_GRAYSCALE; }
+        if medians { flags |= FLAG_MEDIANS; }
+        Ok(RecordLayout { size, flags })
+    }
+
+    /// Stores the flip of the normalisation as well
+    pub fn with_flip(mut self) -> RecordLayout {
+        self.flags |= FLAG_FLIP;
+        self
+    }
+
+    pub fn has_grayscale(&self) -> bool {
+        self.flags & FLAG_GRAYSCALE != 0
+    }
+
+    pub fn has_medians(&self) -> bool {
+        self.flags & FLAG_MEDIANS != 0
+    }
+
+    pub fn has_flip(&self) -> bool {
+        self.flags & FLAG_FLIP != 0
+    }
+
+    fn hashlen(&self) -> usize {
+        (self.size*self.size) as usize
+    }
+
+    fn binary_len(&self) -> usize {
+        self.hashlen()/8
+    }
+
+    fn grayscale_len(&self) -> usize {
+        if self.has_grayscale() { self.hashlen() } else { 0 }
+    }
+
+    fn medians_len(&self) -> usize {
+        if self.has_medians() { 4 } else { 0 }
+    }
+
+    fn flip_len(&self) -> usize {
+        if self.has_flip() { 1 } else { 0 }
+    }
+
+    /// Number of bytes of a single record
+    pub fn record_len(&self) -> usize {
+        self.binary_len() + self.grayscale_len() + self.medians_len() + self.flip_len() + 12
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub layout: RecordLayout,
+    pub record_count: u64,
+    pub string_table_offset: u64,
+    /// Config of all hashes, its size is the grid size of the layout
+    pub config: HashConfig,
+}
+
+/// Index of a config value in its `ALL` list
+fn config_index<T: PartialEq>(all: &[T], value: T) -> u8 {
+    all.iter().position(|v| *v == value).unwrap_or(0) as u8
+}
+
+fn config_value<T: Copy>(all: &[T], index: u8) -> Result<T> {
+    all.get(index as usize).copied()
+        .ok_or_else(|| ForbildError::InvalidDatabase(format!("Unknown hash config value {}", index)))
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10..12].copy_from_slice(&(self.layout.size as u16).to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.layout.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.record_count.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.string_table_offset.to_le_bytes());
+
+        let config = self.config;
+        bytes[32] = config_index(&ResizeFilter::ALL, config.filter);
+        bytes[33] = config_index(&LumaCoefficients::ALL, config.luma);
+        bytes[34] = config_index(&Averaging::ALL, config.averaging);
+        bytes[35] = config_index(&ThresholdStatistic::ALL, config.threshold);
+        bytes[36] = config_index(&Normalization::ALL, config.normalization);
+        bytes[37] = config_index(&JpegDecoding::ALL, config.jpeg);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Header> {
+        if bytes.len() < HEADER_LEN || bytes[0..8] != MAGIC {
+            return Err(ForbildError::InvalidDatabase("Missing database header".to_string()));
+        }
+
+        let version = u16::from_le_bytes(bytes[8..10].try_into().unwrap());
+        if version != VERSION {
+            return Err(ForbildError::UnsupportedVersion { found: version as u32, supported: VERSION as u32 });
+        }
+
+        let size = u16::from_le_bytes(bytes[10..12].try_into().unwrap()) as u32;
+        let flags = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        if flags & !(FLAG_GRAYSCALE | FLAG_MEDIANS | FLAG_FLIP) != 0 {
+            return Err(ForbildError::InvalidDatabase(format!("Unknown flags {:#x}", flags)));
+        }
+        let mut layout = RecordLayout::new(size, false, false)?;
+        layout.flags = flags;
+
+        let config = HashConfig {
+            size,
+            filter: config_value(&ResizeFilter::ALL, bytes[32])?,
+            luma: config_value(&LumaCoefficients::ALL, bytes[33])?,
+            averaging: config_value(&Averaging::ALL, bytes[34])?,
+            threshold: config_value(&ThresholdStatistic::ALL, bytes[35])?,
+            normalization: config_value(&Normalization::ALL, bytes[36])?,
+            jpeg: config_value(&JpegDecoding::ALL, bytes[37])?,
+        };
+
+        let header = Header {
+            version,
+            layout,
+            record_count: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            string_table_offset: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            config,
+        };
+
+        let records_end = (layout.record_len() as u64)
+            .checked_mul(header.record_count)
+            .and_then(|len| len.checked_add(HEADER_LEN as u64));
+        if records_end != Some(header.string_table_offset) {
+            return Err(ForbildError::InvalidDatabase("Record count does not match the string table offset".to_string()));
+        }
+        Ok(header)
+    }
+}
+
+/// Writes a database record by record, starting at the beginning of the writer.
+/// The identifiers are kept in memory until `finish` writes the string table and the final header.
+pub struct DbWriter<W: Write + Seek> {
+    writer: W,
+    layout: RecordLayout,
+    /// Config of the first hash added
+    config: Option<HashConfig>,
+    record_count: u64,
+    strings: Vec<u8>,
+    record: Vec<u8>,
+}
+
+impl<W: Write + Seek> DbWriter<W> {
+    pub fn new(mut writer: W, layout: RecordLayout) -> Result<DbWriter<W>> {
+        // Placeholder header, overwritten by finish()
+        let header = Header {
+            version: VERSION,
+            layout,
+            record_count: 0,
+            string_table_offset: HEADER_LEN as u64,
+            config: HashConfig::default().with_size(layout.size),
+        };
+        writer.write_all(&header.to_bytes())?;
+
+        Ok(DbWriter {
+            writer,
+            layout,
+            config: None,
+            record_count: 0,
+            strings: Vec::new(),
+            record: Vec::with_capacity(layout.record_len()),
+        })
+    }
+
+    pub fn add(&mut self, id: &str, hash: &Hash) -> Result<()> {
+        if hash.size != self.layout.size {
+            return Err(ForbildError::InvalidGridSize(hash.size));
+        }
+        if self.layout.has_grayscale() && !hash.has_grayscale {
+            return Err(ForbildError::MissingGrayscale);
+        }
+        let config = *self.config.get_or_insert(hash.config);
+        if !config.is_comparable(&hash.config) {
+            return Err(ForbildError::ConfigMismatch { expected: config, found: hash.config });
+        }
+
+        self.record.clear();
+        for word in hash.binary256.iter() {
+            self.record.extend_from_slice(&word.to_le_bytes());
+        }
+        if self.layout.has_grayscale() {
+            self.record.extend_from_slice(&hash.grayimage256);
+        }
+        if self.layout.has_medians() {
+            let medians = hash.subarea_medians;
+            self.record.extend_from_slice(&[medians[0][0], medians[0][1], medians[1][0], medians[1][1]]);
+        }
+        if self.layout.has_flip() {
+            let index = Orientation::ALL.iter().position(|o| *o == hash.flip).unwrap_or(0);
+            self.record.push(index as u8);
+        }
+        self.record.extend_from_slice(&(self.strings.len() as u64).to_le_bytes());
+        self.record.extend_from_slice(&(id.len() as u32).to_le_bytes());
+        self.writer.write_all(&self.record)?;
+
+        self.strings.extend_from_slice(id.as_bytes());
+        self.record_count += 1;
+        Ok(())
+    }
+
+    /// Writes the string table and the header and returns the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&self.strings)?;
+
+        let header = Header {
+            version: VERSION,
+            layout: self.layout,
+            record_count: self.record_count,
+            string_table_offset: (HEADER_LEN + self.layout.record_len()*self.record_count as usize) as u64,
+            config: self.config.unwrap_or_else(|| HashConfig::default().with_size(self.layout.size)),
+        };
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header.to_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Random access to the records of a database in memory, e.g. a memory-mapped file
+pub struct DbView<'a> {
+    header: Header,
+    records: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> DbView<'a> {
+    pub fn new(data: &'a [u8]) -> Result<DbView<'a>> {
+        let header = Header::from_bytes(data)?;
+        let offset = header.string_table_offset as usize;
+        if data.len() < offset {
+            return Err(ForbildError::InvalidDatabase("Database is truncated".to_string()));
+        }
+
+        Ok(DbView {
+            header,
+            records: &data[HEADER_LEN..offset],
+            strings: &data[offset..],
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.record_count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn record(&self, i: usize) -> Option<DbRecord<'a>> {
+        let record_len = self.header.layout.record_len();
+        let start = i.checked_mul(record_len)?;
+        let bytes = self.records.get(start..start + record_len)?;
+        Some(DbRecord { layout: self.header.layout, config: self.header.config, bytes, strings: self.strings })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = DbRecord<'a>> + '_ {
+        (0..self.len()).filter_map(move |i| self.record(i))
+    }
+}
+
+/// A record borrowed from a `DbView`
+#[derive(Clone, Copy)]
+pub struct DbRecord<'a> {
+    layout: RecordLayout,
+    config: HashConfig,
+    bytes: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> DbRecord<'a> {
+    pub fn id(&self) -> Result<&'a str> {
+        let (offset, len) = self.id_span();
+        let (offset, len) = (offset as usize, len as usize);
+
+        let id = offset.checked_add(len)
+            .and_then(|end| self.strings.get(offset..end))
+            .ok_or_else(|| ForbildError::InvalidDatabase("Identifier outside of the string table".to_string()))?;
+        std::str::from_utf8(id)
+            .map_err(|_| ForbildError::InvalidDatabase("Identifier is not valid UTF-8".to_string()))
+    }
+
+    /// Offset of the identifier relative to the string table and its length
+    fn id_span(&self) -> (u64, u64) {
+        let tail = &self.bytes[self.bytes.len() - 12..];
+        let offset = u64::from_le_bytes(tail[0..8].try_into().unwrap());
+        let len = u32::from_le_bytes(tail[8..12].try_into().unwrap()) as u64;
+        (offset, len)
+    }
+
+    fn word(&self, i: usize) -> u64 {
+        u64::from_le_bytes(self.bytes[8*i..8*i + 8].try_into().unwrap())
+    }
+
+    /// Hamming distance to a hash of the same grid size, without creating a `Hash` first.
+    ///
+    /// # Panics
+    /// If the grid sizes differ. `checked_hamming_distance` returns an error instead.
+    pub fn hamming_distance(&self, hash: &Hash) -> usize {
+        self.checked_hamming_distance(hash).expect("Cannot compare hashes of different grid sizes")
+    }
+
+    /// Hamming distance to a hash, `ForbildError::InvalidLength` if the grid sizes differ
+    pub fn checked_hamming_distance(&self, hash: &Hash) -> Result<usize> {
+        if hash.size != self.layout.size {
+            let expected = (self.layout.size * self.layout.size) as usize;
+            return Err(ForbildError::InvalidLength { expected, found: hash.len() });
+        }
+
+        Ok(hash.binary256.iter()
+            .enumerate()
+            .map(|(i, word)| (self.word(i) ^ word).count_ones() as usize)
+            .sum())
+    }
+
+    pub fn to_hash(&self) -> Hash {
+        let mut hash = Hash::with_size(self.layout.size).unwrap();
+        hash.config = self.config;
+        for i in 0..hash.binary256.len() {
+            hash.binary256[i] = self.word(i);
+        }
+
+        let mut offset = self.layout.binary_len();
+        if self.layout.has_grayscale() {
+            hash.grayimage256.copy_from_slice(&self.bytes[offset..offset + self.layout.grayscale_len()]);
+            // The weighted distance needs the medians as well
+            hash.has_grayscale = self.layout.has_medians();
+            offset += self.layout.grayscale_len();
+        }
+        if self.layout.has_medians() {
+            let m = &self.bytes[offset..offset + 4];
+            hash.subarea_medians = [[m[0], m[1]], [m[2], m[3]]];
+            offset += self.layout.medians_len();
+        }
+        if self.layout.has_flip() {
+            hash.flip = Orientation::ALL.get(self.bytes[offset] as usize).copied().unwrap_or_default();
+        }
+        hash
+    }
+}
+
+/// Number of records `DbReader` reads at once
+const BATCH_RECORDS: u64 = 1024;
+
+/// Reads a database record by record from a stream. The records are read in batches of `BATCH_RECORDS`
+/// together with their identifiers, so the memory use does not grow with the database.
+pub struct DbReader<R: Read + Seek> {
+    reader: R,
+    header: Header,
+    strings_len: u64,
+    position: u64,
+    records: Vec<u8>,
+    ids: Vec<String>,
+    batch_start: u64,
+}
+
+impl<R: Read + Seek> DbReader<R> {
+    pub fn new(mut reader: R) -> Result<DbReader<R>> {
+        let mut header_bytes = [0; HEADER_LEN];
+        reader.read_exact(&mut header_bytes)
+            .map_err(|_| ForbildError::InvalidDatabase("Missing database header".to_string()))?;
+        let header = Header::from_bytes(&header_bytes)?;
+
+        let strings_len = reader.seek(SeekFrom::End(0))?
+            .checked_sub(header.string_table_offset)
+            .ok_or_else(|| ForbildError::InvalidDatabase("Database is truncated".to_string()))?;
+
+        Ok(DbReader {
+            reader,
+            header,
+            strings_len,
+            position: 0,
+            records: Vec::new(),
+            ids: Vec::new(),
+            batch_start: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Reads the records starting at `position` and their identifiers
+    fn read_batch(&mut self) -> Result<()> {
+        let record_len = self.header.layout.record_len();
+        let count = BATCH_RECORDS.min(self.header.record_count - self.position) as usize;
+        self.reader.seek(SeekFrom::Start(HEADER_LEN as u64 + self.position * record_len as u64))?;
+        self.records.resize(count * record_len, 0);
+        self.reader.read_exact(&mut self.records)?;
+
+        let (layout, config) = (self.header.layout, self.header.config);
+        let spans: Vec<(u64, u64)> = self.records.chunks_exact(record_len)
+            .map(|bytes| DbRecord { layout, config, bytes, strings: &[] }.id_span())
+            .collect();
+        if !spans.iter().all(|(offset, len)| matches!(offset.checked_add(*len), Some(end) if end <= self.strings_len)) {
+            return Err(ForbildError::InvalidDatabase("Identifier outside of the string table".to_string()));
+        }
+
+        // The identifiers written by `DbWriter` follow each other, so they are read at once
+        let start = spans.iter().map(|(offset, _)| *offset).min().unwrap_or(0);
+        let end = spans.iter().map(|(offset, len)| offset + len).max().unwrap_or(0);
+        let total: u64 = spans.iter().map(|(_, len)| len).sum();
+        let mut strings = Vec::new();
+        if end - start <= total {
+            strings.resize((end - start) as usize, 0);
+            self.reader.seek(SeekFrom::Start(self.header.string_table_offset + start))?;
+            self.reader.read_exact(&mut strings)?;
+        }
+
+        self.ids.clear();
+        for (offset, len) in spans {
+            let id = if end - start <= total {
+                strings[(offset - start) as usize..(offset - start + len) as usize].to_vec()
+            } else {
+                let mut id = vec![0; len as usize];
+                self.reader.seek(SeekFrom::Start(self.header.string_table_offset + offset))?;
+                self.reader.read_exact(&mut id)?;
+                id
+            };
+            let id = String::from_utf8(id)
+                .map_err(|_| ForbildError::InvalidDatabase("Identifier is not valid UTF-8".to_string()))?;
+            self.ids.push(id);
+        }
+        self.batch_start = self.position;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Iterator for DbReader<R> {
+    type Item = Result<(String, Hash)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.header.record_count {
+            return None;
+        }
+        if self.position == self.batch_start + self.ids.len() as u64 {
+            if let Err(err) = self.read_batch() {
+                // A broken database is not read any further
+                self.position = self.header.record_count;
+                return Some(Err(err));
+            }
+        }
+
+        let i = (self.position - self.batch_start) as usize;
+        self.position += 1;
+        let record_len = self.header.layout.record_len();
+        let bytes = &self.records[i*record_len..(i + 1)*record_len];
+        let record = DbRecord { layout: self.header.layout, config: self.header.config, bytes, strings: &[] };
+        Some(Ok((std::mem::take(&mut self.ids[i]), record.to_hash())))
+    }
+}
+
+/// A memory-mapped database file
+#[cfg(feature = "mmap")]
+pub struct MmapDb {
+    mmap: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapDb {
+    pub fn open(path: &Path) -> Result<MmapDb> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: The mapping is read-only. Modifying the file while it is mapped is not supported.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        DbView::new(&mmap)?;
+        Ok(MmapDb { mmap })
+    }
+
+    pub fn view(&self) -> DbView<'_> {
+        DbView::new(&self.mmap).unwrap()
+    }
+}
+
+/// Writes all hashes with their identifiers to a database file
+pub fn write_db_file(path: &Path, layout: RecordLayout, hashes: &[(String, Hash)]) -> Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut writer = DbWriter::new(file, layout)?;
+    for (id, hash) in hashes {
+        writer.add(id, hash)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod db_tests {
+    use std::io::Cursor;
+    use crate::hashmath::{hamming_distance, weighted_distance};
+    use super::*;
+
+    fn create_hashes() -> Vec<(String, Hash)> {
+        ["./data/original/2017_China_Chongqing_Boats.jpg", "./data/original/2015_Japan_Tokyo_Traffic.jpg"]
+            .iter()
+            .map(|p| (p.to_string(), Hash::from_path(Path::new(p)).unwrap()))
+            .collect()
+    }
+
+    fn write_db(layout: RecordLayout, hashes: &[(String, Hash)]) -> Vec<u8> {
+        let mut writer = DbWriter::new(Cursor::new(Vec::new()), layout).unwrap();
+        for (id, hash) in hashes {
+            writer.add(id, hash).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let layout = RecordLayout::new(16, true, true).unwrap();
+        assert_eq!(layout.record_len(), 32 + 256 + 4 + 12);
+
+        let layout = RecordLayout::new(8, false, false).unwrap();
+        assert_eq!(layout.record_len(), 8 + 12);
+        assert_eq!(layout.with_flip().record_len(), 8 + 1 + 12);
+
+        assert!(RecordLayout::new(12, false, false).is_err());
+    }
+
+    #[test]
+    fn test_view_roundtrip() {
+        let hashes = create_hashes();
+        let data = write_db(RecordLayout::new(16, true, true).unwrap().with_flip(), &hashes);
+
+        let expected_len = HEADER_LEN + 2*(32 + 256 + 4 + 1 + 12) + hashes[0].0.len() + hashes[1].0.len();
+        assert_eq!(data.len(), expected_len);
+
+        let view = DbView::new(&data).unwrap();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.header().layout.size, 16);
+
+        for (i, (id, hash)) in hashes.iter().enumerate() {
+            let record = view.record(i).unwrap();
+            assert_eq!(record.id().unwrap(), id);
+            assert_eq!(&record.to_hash(), hash);
+            assert_eq!(record.hamming_distance(&hashes[0].1), hamming_distance(hash, &hashes[0].1));
+        }
+        assert!(view.record(2).is_none());
+        let small = Hash::with_size(8).unwrap();
+        assert!(matches!(view.record(0).unwrap().checked_hamming_distance(&small), Err(ForbildError::InvalidLength { .. })));
+
+        let restored: Vec<Hash> = view.iter().map(|r| r.to_hash()).collect();
+        assert_eq!(
+            weighted_distance(&restored[0], &restored[1]).unwrap(),
+            weighted_distance(&hashes[0].1, &hashes[1].1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reader_roundtrip() {
+        let hashes = create_hashes();
+        let data = write_db(RecordLayout::new(16, false, false).unwrap(), &hashes);
+
+        let reader = DbReader::new(Cursor::new(data)).unwrap();
+        assert_eq!(reader.header().record_count, 2);
+
+        let restored: Vec<(String, Hash)> = reader.collect::<Result<_>>().unwrap();
+        for ((id, hash), (restored_id, restored_hash)) in hashes.iter().zip(&restored) {
+            assert_eq!(id, restored_id);
+            assert_eq!(hash.binary256, restored_hash.binary256);
+            assert_eq!(restored_hash.grayimage256, vec![0; 256]);
+            assert!(!restored_hash.has_grayscale);
+        }
+    }
+
+    #[test]
+    fn test_grayscale_flag() {
+        // A black image has an all zero grayscale image, but still grayscale data
+        let black = Hash::from_dynamic_image(image::DynamicImage::new_luma8(64, 64)).unwrap();
+        assert!(black.has_grayscale);
+        let hashes = vec![("black".to_string(), black)];
+
+        let data = write_db(RecordLayout::new(16, true, true).unwrap(), &hashes);
+        assert!(DbView::new(&data).unwrap().record(0).unwrap().to_hash().has_grayscale);
+
+        // Without the medians the weighted distance cannot be calculated
+        let data = write_db(RecordLayout::new(16, true, false).unwrap(), &hashes);
+        assert!(!DbView::new(&data).unwrap().record(0).unwrap().to_hash().has_grayscale);
+
+        let mut writer = DbWriter::new(Cursor::new(Vec::new()), RecordLayout::new(16, true, true).unwrap()).unwrap();
+        assert!(matches!(writer.add("hex", &Hash::new()), Err(ForbildError::MissingGrayscale)));
+    }
+
+    #[test]
+    fn test_reader_batches() {
+        let hash = Hash::from_path_with_size(Path::new("./data/original/2017_China_Chongqing_Boats.jpg"), 8).unwrap();
+        let hashes: Vec<(String, Hash)> = (0..2500).map(|i| (format!("id{}", i), hash.clone())).collect();
+        let data = write_db(RecordLayout::new(8, false, false).unwrap(), &hashes);
+
+        let ids: Vec<String> = DbReader::new(Cursor::new(data.clone())).unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(ids, hashes.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>());
+
+        // Identifiers that do not follow each other are read one by one
+        let mut gap = data.clone();
+        gap[HEADER_LEN + 16] = 2;
+        let mut reader = DbReader::new(Cursor::new(gap)).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().0, "id");
+        assert_eq!(reader.next().unwrap().unwrap().0, "id1");
+
+        let mut outside = data;
+        outside[HEADER_LEN + 15] = 0xFF;
+        let mut reader = DbReader::new(Cursor::new(outside)).unwrap();
+        assert!(matches!(reader.next(), Some(Err(ForbildError::InvalidDatabase(_)))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_config_roundtrip() {
+        use crate::config::{JpegDecoding, LumaCoefficients, ThresholdStatistic};
+
+        let path = Path::new("./data/original/2017_China_Chongqing_Boats.jpg");
+        let config = HashConfig::bit_exact()
+            .with_size(8)
+            .with_luma(LumaCoefficients::Rec601)
+            .with_threshold(ThresholdStatistic::Mean)
+            .with_normalization(Normalization::Centroid)
+            .with_jpeg_decoding(JpegDecoding::Dc);
+        let hash = Hash::from_path_with_config(path, &config).unwrap();
+        let data = write_db(RecordLayout::new(8, false, false).unwrap(), &[("boats".to_string(), hash.clone())]);
+
+        let view = DbView::new(&data).unwrap();
+        assert_eq!(view.header().config, config);
+        assert_eq!(view.record(0).unwrap().to_hash().config, config);
+        let (_, restored) = DbReader::new(Cursor::new(data.clone())).unwrap().next().unwrap().unwrap();
+        assert_eq!(restored.config, config);
+
+        // An empty database has the default config of its grid size
+        let empty = write_db(RecordLayout::new(8, false, false).unwrap(), &[]);
+        assert_eq!(DbView::new(&empty).unwrap().header().config, HashConfig::default().with_size(8));
+
+        let mut unknown = data;
+        unknown[32] = 200;
+        assert!(matches!(DbView::new(&unknown), Err(ForbildError::InvalidDatabase(_))));
+
+        let mut writer = DbWriter::new(Cursor::new(Vec::new()), RecordLayout::new(8, false, false).unwrap()).unwrap();
+        writer.add("boats", &hash).unwrap();
+        let other = Hash::from_path_with_size(path, 8).unwrap();
+        assert!(matches!(writer.add("default", &other), Err(ForbildError::ConfigMismatch { .. })));
+    }
+
+    #[test]
+    fn test_other_sizes() {
+        let path = Path::new("./data/original/2017_China_Chongqing_Boats.jpg");
+        for size in [8, 32].iter() {
+            let hash = Hash::from_path_with_size(path, *size).unwrap();
+            let data = write_db(RecordLayout::new(*size, true, true).unwrap().with_flip(), &[("boats".to_string(), hash.clone())]);
+
+            let view = DbView::new(&data).unwrap();
+            assert_eq!(view.record(0).unwrap().to_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn test_add_wrong_size() {
+        let mut writer = DbWriter::new(Cursor::new(Vec::new()), RecordLayout::new(8, false, false).unwrap()).unwrap();
+        assert!(matches!(writer.add("a", &Hash::new()), Err(ForbildError::InvalidGridSize(16))));
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        let hashes = create_hashes();
+        let data = write_db(RecordLayout::new(16, true, true).unwrap(), &hashes);
+
+        assert!(matches!(DbView::new(b"not a database"), Err(ForbildError::InvalidDatabase(_))));
+        assert!(matches!(DbView::new(&data[..100]), Err(ForbildError::InvalidDatabase(_))));
+
+        let mut newer = data.clone();
+        newer[8] = 3;
+        assert!(matches!(DbView::new(&newer), Err(ForbildError::UnsupportedVersion { found: 3, supported: 2 })));
+
+        let mut wrong_count = data;
+        wrong_count[16] = 200;
+        assert!(matches!(DbView::new(&wrong_count), Err(ForbildError::InvalidDatabase(_))));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap() {
+        let hashes = create_hashes();
+        let path = std::env::temp_dir().join("forbild_test_mmap.fbdb");
+        write_db_file(&path, RecordLayout::new(16, true, true).unwrap().with_flip(), &hashes).unwrap();
+
+        let db = MmapDb::open(&path).unwrap();
+        let view = db.view();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.record(1).unwrap().id().unwrap(), hashes[1].0);
+        assert_eq!(view.record(1).unwrap().to_hash(), hashes[1].1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,77 +1,428 @@
 use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
-use image::io::{Reader as ImageReader, Reader};
-use image::{DynamicImage, GrayImage, GenericImageView, ImageBuffer, Luma, ImageError};
-use image::imageops::{Gaussian, flip_vertical_in_place, flip_horizontal_in_place};
-use crate::SIZE;
-
-pub fn preprocess_image(path: &PathBuf) -> GrayImage {
-    let img = import_image_from_file(path);
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, GrayImage, GenericImageView, ImageBuffer, ImageError, ImageFormat, Luma, RgbImage};
+use image::error::{DecodingError, ImageFormatHint};
+use image::imageops::{self, Gaussian, flip_vertical_in_place, flip_horizontal_in_place};
+use jpeg_decoder::PixelFormat;
+use crate::DEFAULT_SIZE;
+use crate::config::{Averaging, HashConfig, LumaCoefficients};
+use crate::error::{ForbildError, Result};
+use crate::limits::DecodeLimits;
+use crate::orientation::Orientation;
+
+pub fn preprocess_image(path: &Path) -> Result<GrayImage> {
+    preprocess_image_with_size(path, DEFAULT_SIZE)
+}
+
+pub fn preprocess_image_with_size(path: &Path, size: u32) -> Result<GrayImage> {
+    let img = import_image_from_file(path)?;
+    Ok(preprocess_dynamic_image(img, size))
+}
+
+/// Converts a decoded image to the downsampled grayscale image used for hashing
+pub fn preprocess_dynamic_image(img: DynamicImage, size: u32) -> GrayImage {
     let img = color_to_grayscale(img);
-    let img = downsample(img);
-    let img = grayscale_to_luma(img);
-    img
+    let img = downsample(img, size);
+    grayscale_to_luma(img)
 }
 
-pub fn import_image_from_file(path: &Path) -> DynamicImage {
-    let img_reader = match ImageReader::open(path) {
-        Ok(reader) => reader,
-        Err(err) => {
-            eprintln!("ERROR: {}.", err);
-            eprintln!("Exiting program.");
-            std::process::exit(1);
-        }
+/// Converts a decoded image to the downsampled grayscale image with the luma coefficients,
+/// filter and averaging of the config. The default config gives the same image as `preprocess_dynamic_image`.
+pub fn preprocess_dynamic_image_with_config(img: DynamicImage, config: &HashConfig) -> GrayImage {
+    let filter = config.filter.filter_type();
+    // The decoded image is dropped as soon as the grayscale image exists, see `limits::PIPELINE_BYTES_PER_PIXEL`
+    let gray = match (filter, config.luma) {
+        // The integer area average converts to grayscale with integers too
+        (None, luma) => integer_grayscale(&{ img }, luma.integer_weights()),
+        (Some(_), LumaCoefficients::Rec709) => grayscale_to_luma(color_to_grayscale(img)),
+        (Some(_), luma) => weighted_grayscale(&{ img }, luma.weights()),
     };
 
-    match img_reader.decode() {
-        Ok(img) => img,
-        Err(err) => {
-            eprintln!("ERROR: {}", err);
-            eprintln!("Exiting program.");
-            std::process::exit(1);
+    let size = config.size;
+    match config.averaging {
+        Averaging::Srgb => match filter {
+            Some(filter) => imageops::resize(&gray, size, size, filter),
+            None => area_downsample(&gray, size),
+        },
+        Averaging::Linear => {
+            // 16 bit linear values keep the dark tones apart
+            let to_linear: Vec<u16> = (0..=255)
+                .map(|value| (srgb_to_linear(value as f32 / 255.0) * 65535.0).round() as u16)
+                .collect();
+            let linear: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_fn(gray.width(), gray.height(), |x, y| {
+                Luma([to_linear[gray.get_pixel(x, y)[0] as usize]])
+            });
+            let small = match filter {
+                Some(filter) => imageops::resize(&linear, size, size, filter),
+                None => {
+                    let averages = area_average(linear.as_raw(), linear.width(), linear.height(), size);
+                    ImageBuffer::from_raw(size, size, averages.into_iter().map(|v| v as u16).collect())
+                        .expect("area_average returns size x size values")
+                },
+            };
+            ImageBuffer::from_fn(size, size, |x, y| {
+                Luma([(linear_to_srgb(small.get_pixel(x, y)[0] as f32 / 65535.0) * 255.0).round() as u8])
+            })
+        },
+    }
+}
+
+fn weighted_grayscale(img: &DynamicImage, weights: [f32; 3]) -> GrayImage {
+    let rgb = img.to_rgb8();
+    ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let p = rgb.get_pixel(x, y);
+        Luma([(weights[0]*p[0] as f32 + weights[1]*p[1] as f32 + weights[2]*p[2] as f32) as u8])
+    })
+}
+
+/// Grayscale conversion with weights in units of 1/10000 (`LumaCoefficients::integer_weights`), rounded
+fn integer_grayscale(img: &DynamicImage, weights: [u32; 3]) -> GrayImage {
+    let rgb = img.to_rgb8();
+    ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let p = rgb.get_pixel(x, y);
+        let sum = weights[0]*p[0] as u32 + weights[1]*p[1] as u32 + weights[2]*p[2] as u32;
+        Luma([((sum + 5000) / 10000) as u8])
+    })
+}
+
+/// Resizes to size x size pixels with the area average: every pixel of the result is the mean of the
+/// source pixels it covers, weighted by the covered area and rounded. Only integers are used,
+/// so the result is the same on every platform.
+pub fn area_downsample(img: &GrayImage, size: u32) -> GrayImage {
+    let averages = area_average(img.as_raw(), img.width(), img.height(), size);
+    GrayImage::from_raw(size, size, averages.into_iter().map(|v| v as u8).collect())
+        .expect("area_average returns size x size values")
+}
+
+/// Area average of the row-major values of a width x height image, see `area_downsample`
+fn area_average<T: Copy + Into<u64>>(values: &[T], width: u32, height: u32, size: u32) -> Vec<u64> {
+    let columns = area_weights(width, size);
+    let rows = area_weights(height, size);
+    let (n_cols, n) = (width as usize, size as usize);
+
+    // The weighted sums are only divided at the end, so nothing is rounded in between
+    let mut row_sums = vec![0u64; height as usize * n];
+    for (y, row) in values.chunks_exact(n_cols.max(1)).enumerate() {
+        for &(src, dst, weight) in &columns {
+            row_sums[y*n + dst] += weight * row[src].into();
+        }
+    }
+    let mut sums = vec![0u64; n*n];
+    for &(src, dst, weight) in &rows {
+        for x in 0..n {
+            sums[dst*n + x] += weight * row_sums[src*n + x];
+        }
+    }
+
+    // The weights of every pixel of the result add up to width * height
+    let total = width as u64 * height as u64;
+    sums.into_iter()
+        .map(|sum| (sum + total/2).checked_div(total).unwrap_or(0))
+        .collect()
+}
+
+/// Overlaps of the source and destination pixels along one axis as (source, destination, weight).
+/// The axis is measured in units of 1/(src * dst), so a source pixel is dst and a destination pixel src units long.
+fn area_weights(src: u32, dst: u32) -> Vec<(usize, usize, u64)> {
+    let (src, dst) = (src as u64, dst as u64);
+    let mut weights = Vec::new();
+    for i in 0..src {
+        let (start, end) = (i*dst, (i + 1)*dst);
+        for o in start/src..end.div_ceil(src) {
+            let overlap = end.min((o + 1)*src) - start.max(o*src);
+            weights.push((i as usize, o as usize, overlap));
         }
     }
+    weights
+}
+
+/// sRGB transfer function, both values in 0..=1
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an image file with the default `DecodeLimits`. The format is guessed from the content
+/// and only if that fails from the extension.
+pub fn import_image_from_file(path: &Path) -> Result<DynamicImage> {
+    import_image_from_file_with_limits(path, &DecodeLimits::default())
+}
+
+pub fn import_image_from_file_with_limits(path: &Path, limits: &DecodeLimits) -> Result<DynamicImage> {
+    Ok(decode_with_limits(ImageReader::open(path)?.with_guessed_format()?, limits, None)?.image)
+}
+
+/// Decodes an image file with the default `DecodeLimits` and the `JpegDecoding` of the config.
+/// JPEGs decoded at a reduced scale are smaller than the image.
+pub fn import_image_from_file_with_config(path: &Path, config: &HashConfig) -> Result<DynamicImage> {
+    Ok(decode_image_from_file(path, &DecodeLimits::default(), config)?.image)
+}
+
+/// Decodes an image file like `import_image_from_file_with_config` and keeps the dimensions of the image header
+pub fn decode_image_from_file(path: &Path, limits: &DecodeLimits, config: &HashConfig) -> Result<DecodedImage> {
+    decode_image_from_opened_file(File::open(path)?, path, limits, config)
+}
+
+/// Decodes an image file that is already open, the extension of the path is used like in `decode_image_from_file`.
+/// The content is read while decoding, it is not kept in memory.
+pub fn decode_image_from_opened_file(file: File, path: &Path, limits: &DecodeLimits, config: &HashConfig) -> Result<DecodedImage> {
+    let mut img_reader = ImageReader::new(BufReader::new(file));
+    if let Ok(format) = ImageFormat::from_path(path) {
+        img_reader.set_format(format);
+    }
+    let requested = config.jpeg.requested_size(config.size);
+    decode_with_limits(img_reader.with_guessed_format()?, limits, requested)
+}
+
+/// Decodes an image from memory with the default `DecodeLimits`, guessing the format from the content
+pub fn import_image_from_bytes(bytes: &[u8]) -> Result<DynamicImage> {
+    import_image_from_bytes_with_limits(bytes, &DecodeLimits::default())
+}
+
+pub fn import_image_from_bytes_with_limits(bytes: &[u8], limits: &DecodeLimits) -> Result<DynamicImage> {
+    Ok(decode_with_limits(ImageReader::new(Cursor::new(bytes)).with_guessed_format()?, limits, None)?.image)
+}
+
+pub fn import_image_from_bytes_with_config(bytes: &[u8], config: &HashConfig) -> Result<DynamicImage> {
+    Ok(decode_image_from_bytes(bytes, &DecodeLimits::default(), config)?.image)
+}
+
+/// Decodes an image from memory like `import_image_from_bytes_with_config` and keeps the dimensions of the image header
+pub fn decode_image_from_bytes(bytes: &[u8], limits: &DecodeLimits, config: &HashConfig) -> Result<DecodedImage> {
+    let requested = config.jpeg.requested_size(config.size);
+    decode_with_limits(ImageReader::new(Cursor::new(bytes)).with_guessed_format()?, limits, requested)
+}
+
+/// Decodes an image from a reader with the default `DecodeLimits`, guessing the format from the content
+pub fn import_image_from_reader<R: Read + Seek>(reader: R) -> Result<DynamicImage> {
+    import_image_from_reader_with_limits(reader, &DecodeLimits::default())
+}
+
+pub fn import_image_from_reader_with_limits<R: Read + Seek>(reader: R, limits: &DecodeLimits) -> Result<DynamicImage> {
+    Ok(decode_with_limits(ImageReader::new(BufReader::new(reader)).with_guessed_format()?, limits, None)?.image)
+}
+
+pub fn import_image_from_reader_with_config<R: Read + Seek>(reader: R, config: &HashConfig) -> Result<DynamicImage> {
+    Ok(decode_image_from_reader(reader, &DecodeLimits::default(), config)?.image)
+}
+
+/// Decodes an image from a reader like `import_image_from_reader_with_config` and keeps the dimensions of the image header
+pub fn decode_image_from_reader<R: Read + Seek>(reader: R, limits: &DecodeLimits, config: &HashConfig) -> Result<DecodedImage> {
+    let requested = config.jpeg.requested_size(config.size);
+    decode_with_limits(ImageReader::new(BufReader::new(reader)).with_guessed_format()?, limits, requested)
+}
+
+/// A decoded image with the width and height of its header.
+/// JPEGs decoded at a reduced scale are smaller than `width` x `height`.
+#[derive(Clone, Debug)]
+pub struct DecodedImage {
+    pub image: DynamicImage,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Checks the size of the remaining data and the dimensions of the image header before decoding.
+/// With a requested size, JPEGs are decoded at the smallest scale that is at least that large (see `decode_jpeg_scaled`).
+fn decode_with_limits<R: BufRead + Seek>(img_reader: ImageReader<R>, limits: &DecodeLimits, requested: Option<(u16, u16)>) -> Result<DecodedImage> {
+    fn with_format<R: BufRead + Seek>(reader: R, format: Option<ImageFormat>) -> ImageReader<R> {
+        let mut img_reader = ImageReader::new(reader);
+        if let Some(format) = format {
+            img_reader.set_format(format);
+        }
+        img_reader
+    }
+    let format = img_reader.format();
+    let mut inner = img_reader.into_inner();
+
+    let start = inner.stream_position()?;
+    let end = inner.seek(SeekFrom::End(0))?;
+    limits.check_file_size(end.saturating_sub(start))?;
+    inner.seek(SeekFrom::Start(start))?;
+
+    let (width, height) = with_format(&mut inner, format).into_dimensions()?;
+    limits.check_dimensions(width, height)?;
+    inner.seek(SeekFrom::Start(start))?;
+
+    let image = match (format, requested) {
+        (Some(ImageFormat::Jpeg), Some((width, height))) => decode_jpeg_scaled(inner, width, height)?,
+        _ => with_format(inner, format).decode()?,
+    };
+    Ok(DecodedImage { image, width, height })
+}
+
+/// Decodes a JPEG with the smallest scaled IDCT of `jpeg_decoder` (1/8, 1/4, 1/2 or 1) that gives
+/// at least the requested width or height. At 1/8, only the DC coefficient of every block is used.
+/// CMYK is converted to RGB like `image` does.
+fn decode_jpeg_scaled<R: Read>(reader: R, width: u16, height: u16) -> Result<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(reader);
+    let (width, height) = decoder.scale(width, height)?;
+    let pixels = decoder.decode()?;
+    let (width, height) = (width as u32, height as u32);
+
+    let img = match decoder.info().map(|info| info.pixel_format) {
+        Some(PixelFormat::L8) => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        Some(PixelFormat::RGB24) => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        Some(PixelFormat::CMYK32) => {
+            let rgb = pixels.chunks_exact(4)
+                .flat_map(|p| {
+                    let k = 255 - p[3] as u16;
+                    (0..3).map(move |i| ((255 - p[i] as u16) * k / 255) as u8)
+                })
+                .collect();
+            RgbImage::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        },
+        None => None,
+    };
+    img.ok_or_else(|| ForbildError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Jpeg),
+        "the decoded data does not match the image size",
+    ))))
 }
 
 pub fn color_to_grayscale(img: DynamicImage) -> DynamicImage {
     img.grayscale()
 }
 
-pub fn downsample(img: DynamicImage) -> DynamicImage {
-    img.resize_exact(SIZE, SIZE, Gaussian)
+pub fn downsample(img: DynamicImage, size: u32) -> DynamicImage {
+    img.resize_exact(size, size, Gaussian)
 }
 
 pub fn grayscale_to_luma(img: DynamicImage) -> GrayImage {
-    return img.into_luma8()
+    img.into_luma8()
 }
 
+/// How the downsampled image is flipped before hashing, so that mirrored images get the same hash
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum), value(rename_all = "snake_case"))]
+pub enum Normalization {
+    /// The quadrant with the brightest pixel is flipped to the top left, as published.
+    /// A single noisy pixel can change the flip.
+    #[default]
+    BrightestPixel,
+    /// The quadrant with the highest mean brightness is flipped to the top left
+    BrightestQuadrant,
+    /// The image is flipped so that the centroid of its intensities is in the top left quadrant
+    Centroid,
+    /// The image is not flipped. A rotation invariant `Matcher` searches the flips when comparing instead.
+    None,
+}
+
+impl Normalization {
+    /// All normalizations, in the order of their index in the database header (see `db`)
+    pub const ALL: [Normalization; 4] = [
+        Normalization::BrightestPixel,
+        Normalization::BrightestQuadrant,
+        Normalization::Centroid,
+        Normalization::None,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Normalization::BrightestPixel => "brightest_pixel",
+            Normalization::BrightestQuadrant => "brightest_quadrant",
+            Normalization::Centroid => "centroid",
+            Normalization::None => "none",
+        }
+    }
+}
+
+/// Flips the image so that the quadrant with the brightest pixel is in the top left
 pub fn mirror_by_brightest_pixel(img: &mut GrayImage) -> &mut GrayImage {
-    let max_00 = brightest_pixel_of_quadrant(img, 0, 0);
-    let max_01 = brightest_pixel_of_quadrant(img, 0, 1);
-    let max_10 = brightest_pixel_of_quadrant(img, 1, 0);
-    let max_11 = brightest_pixel_of_quadrant(img, 1, 1);
+    normalize_flip(img, Normalization::BrightestPixel);
+    img
+}
+
+/// Flips the image as chosen by the normalisation and returns the applied flip
+/// (`Identity`, `FlipHorizontal`, `FlipVertical` or `Rotate180` for both)
+pub fn normalize_flip(img: &mut GrayImage, normalization: Normalization) -> Orientation {
+    let flip = match normalization {
+        Normalization::BrightestPixel => flip_to_top_left([
+            brightest_pixel_of_quadrant(img, 0, 0) as u64,
+            brightest_pixel_of_quadrant(img, 0, 1) as u64,
+            brightest_pixel_of_quadrant(img, 1, 0) as u64,
+            brightest_pixel_of_quadrant(img, 1, 1) as u64,
+        ]),
+        // All quadrants have the same number of pixels, so the sums compare like the means
+        Normalization::BrightestQuadrant => flip_to_top_left([
+            quadrant_sum(img, 0, 0),
+            quadrant_sum(img, 0, 1),
+            quadrant_sum(img, 1, 0),
+            quadrant_sum(img, 1, 1),
+        ]),
+        Normalization::Centroid => centroid_flip(img),
+        Normalization::None => Orientation::Identity,
+    };
 
-    // Finding out which subarea has the brightest pixel
+    match flip {
+        Orientation::FlipHorizontal => flip_horizontal_in_place(img),
+        Orientation::FlipVertical => flip_vertical_in_place(img),
+        Orientation::Rotate180 => {
+            flip_horizontal_in_place(img);
+            flip_vertical_in_place(img);
+        },
+        _ => {},
+    }
+    flip
+}
+
+/// The flip that moves the quadrant with the highest value to the top left. The values are ordered
+/// top left, bottom left, top right, bottom right, on ties the first quadrant wins.
+fn flip_to_top_left(values: [u64; 4]) -> Orientation {
     let mut max_index = 0;
-    let mut max_value: u8 = 0;
-    for (i, value) in [max_00, max_01, max_10, max_11].iter().enumerate() {
-        if *value > max_value {
-            max_value = *value;
+    for (i, value) in values.iter().enumerate() {
+        if *value > values[max_index] {
             max_index = i;
         }
     }
 
-    // Flipping the image, so that the brightest pixel is in the top left
-    if max_index == 1  {
-        flip_vertical_in_place(img);
-    } else if max_index == 2  {
-        flip_horizontal_in_place(img);
-    } else if max_index == 3 {
-        flip_horizontal_in_place(img);
-        flip_vertical_in_place(img);
+    match max_index {
+        1 => Orientation::FlipVertical,
+        2 => Orientation::FlipHorizontal,
+        3 => Orientation::Rotate180,
+        _ => Orientation::Identity,
+    }
+}
+
+fn quadrant_sum(img: &GrayImage, col: u32, row: u32) -> u64 {
+    let (half_x, half_y) = (img.width()/2, img.height()/2);
+    img.view(col*half_x, row*half_y, half_x, half_y)
+        .pixels()
+        .map(|(_x, _y, p)| p[0] as u64)
+        .sum()
+}
+
+/// The flip that moves the intensity centroid to the top left quadrant, the center itself is not flipped
+fn centroid_flip(img: &GrayImage) -> Orientation {
+    let (width, height) = img.dimensions();
+
+    // Moments relative to the center, doubled to stay integers
+    let (mut moment_x, mut moment_y) = (0i64, 0i64);
+    for (x, y, p) in img.enumerate_pixels() {
+        moment_x += (2*x as i64 - (width as i64 - 1)) * p[0] as i64;
+        moment_y += (2*y as i64 - (height as i64 - 1)) * p[0] as i64;
+    }
+
+    match (moment_x > 0, moment_y > 0) {
+        (true, true) => Orientation::Rotate180,
+        (true, false) => Orientation::FlipHorizontal,
+        (false, true) => Orientation::FlipVertical,
+        (false, false) => Orientation::Identity,
     }
-    img
 }
 
 fn brightest_pixel_of_quadrant(img: &GrayImage, col: u32, row: u32) -> u8 {
@@ -83,7 +434,7 @@ fn brightest_pixel_of_quadrant(img: &GrayImage, col: u32, row: u32) -> u8 {
         .pixels()
         .map(|(_x, _y, p)| p[0])
         .max()
-        .unwrap() as u8
+        .unwrap_or(0)
 }
 
 pub fn to_binary_image_by_quadrant(img: GrayImage) -> GrayImage {
@@ -104,7 +455,7 @@ pub fn to_binary_image_by_quadrant(img: GrayImage) -> GrayImage {
 
             // Finding the median
             let mut values: Vec<u8> = quadrant.pixels()
-                .map(|p| (p.2[0] as u8))
+                .map(|p| p.2[0])
                 .collect();
             values.sort_by(|a, b| a.partial_cmp(b).unwrap());
             let median = values[values.len() / 2];
@@ -130,7 +481,10 @@ pub fn to_binary_image_by_quadrant(img: GrayImage) -> GrayImage {
 
 #[cfg(test)]
 mod editing_tests {
+    use std::path::PathBuf;
     use image::Rgba;
+    use crate::ForbildError;
+    use crate::limits::Limit;
     use super::*;
 
     fn create_dynamicimage() -> DynamicImage {
@@ -206,6 +560,40 @@ mod editing_tests {
         let img = mirror_by_brightest_pixel(&mut img);
 
         assert_eq!(img.get_pixel(0, 0)[0], 15+15);
+
+        // Images too small for quadrants are left as they are
+        let mut img = GrayImage::from_pixel(1, 1, Luma([7]));
+        assert_eq!(mirror_by_brightest_pixel(&mut img).get_pixel(0, 0)[0], 7);
+    }
+
+    #[test]
+    fn test_normalize_flip() {
+        // The brightest pixel is in the top right quadrant, the brighter half in the bottom
+        let mut img = GrayImage::from_fn(16, 16, |_x, y| Luma([if y < 8 { 10 } else { 100 }]));
+        img.put_pixel(12, 2, Luma([255]));
+
+        let mut flipped = img.clone();
+        assert_eq!(normalize_flip(&mut flipped, Normalization::BrightestPixel), Orientation::FlipHorizontal);
+        assert_eq!(flipped.get_pixel(3, 2)[0], 255);
+        assert_eq!(normalize_flip(&mut img.clone(), Normalization::BrightestQuadrant), Orientation::FlipVertical);
+        assert_eq!(normalize_flip(&mut img.clone(), Normalization::Centroid), Orientation::Rotate180);
+        assert_eq!(normalize_flip(&mut img.clone(), Normalization::None), Orientation::Identity);
+
+        let mut flipped = img.clone();
+        normalize_flip(&mut flipped, Normalization::Centroid);
+        assert_eq!(flipped.get_pixel(3, 13)[0], 255);
+
+        // Every normalisation moves the top left quadrant to the same place for mirrored images
+        let img = create_grayimage();
+        for normalization in [Normalization::BrightestPixel, Normalization::BrightestQuadrant, Normalization::Centroid].iter() {
+            let mut expected = img.clone();
+            normalize_flip(&mut expected, *normalization);
+            for flip in [Orientation::FlipHorizontal, Orientation::FlipVertical, Orientation::Rotate180].iter() {
+                let mut mirrored = flip.apply(&DynamicImage::ImageLuma8(img.clone())).to_luma8();
+                normalize_flip(&mut mirrored, *normalization);
+                assert_eq!(mirrored, expected, "{:?} {}", normalization, flip);
+            }
+        }
     }
 
     #[test]
@@ -225,7 +613,7 @@ mod editing_tests {
     #[test]
     fn test_import_image() {
         let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
-        let img = import_image_from_file(&path);
+        let img = import_image_from_file(&path).unwrap();
 
         let pix = img.get_pixel(0, 0);
         assert_eq!(pix[0], 12);
@@ -233,24 +621,217 @@ mod editing_tests {
         assert_eq!(pix[2], 42);
     }
 
+    #[test]
+    fn test_import_image_missing_file() {
+        let path = PathBuf::from("./data/original/NoImageToBeFoundHere.jpg");
+        let result = import_image_from_file(&path);
+
+        assert!(matches!(result, Err(ForbildError::Io(_))));
+    }
+
+    #[test]
+    fn test_import_image_not_an_image() {
+        let path = PathBuf::from("./Cargo.toml");
+        let result = import_image_from_file(&path);
+
+        assert!(matches!(result, Err(ForbildError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_import_image_corrupt_jpeg() {
+        let bytes = std::fs::read("./data/original/2015_Japan_Tokyo_Traffic.jpg").unwrap();
+        let path = std::env::temp_dir().join("forbild_test_corrupt.jpg");
+        std::fs::write(&path, &bytes[..64]).unwrap();
+
+        let result = import_image_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(ForbildError::Decode(_))));
+    }
+
+    #[test]
+    fn test_import_image_from_bytes() {
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let bytes = std::fs::read(&path).unwrap();
+
+        let img = import_image_from_bytes(&bytes).unwrap();
+        assert_eq!(img, import_image_from_file(&path).unwrap());
+
+        let img = import_image_from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(img, import_image_from_file(&path).unwrap());
+
+        let result = import_image_from_bytes(b"This is not an image");
+        assert!(matches!(result, Err(ForbildError::UnsupportedFormat(_))));
+    }
+
+    /// GIF header declaring a 65535x65535 image without any image data
+    fn create_gif_bomb() -> Vec<u8> {
+        let mut bytes = b"GIF89a".to_vec();
+        bytes.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00]);
+        bytes.push(0x3B);
+        bytes
+    }
+
+    /// BMP header declaring a 60000x60000 image with 24 bit per pixel without any image data
+    fn create_bmp_bomb() -> Vec<u8> {
+        let mut bytes = b"BM".to_vec();
+        bytes.extend_from_slice(&54u32.to_le_bytes());      // file size
+        bytes.extend_from_slice(&[0; 4]);                   // reserved
+        bytes.extend_from_slice(&54u32.to_le_bytes());      // offset of the pixel data
+        bytes.extend_from_slice(&40u32.to_le_bytes());      // size of the info header
+        bytes.extend_from_slice(&60_000i32.to_le_bytes());  // width
+        bytes.extend_from_slice(&60_000i32.to_le_bytes());  // height
+        bytes.extend_from_slice(&1u16.to_le_bytes());       // planes
+        bytes.extend_from_slice(&24u16.to_le_bytes());      // bits per pixel
+        bytes.extend_from_slice(&[0; 24]);                  // no compression, default resolution and palette
+        bytes
+    }
+
+    #[test]
+    fn test_import_image_limits() {
+        let is_exceeded = |result: Result<DynamicImage>, expected: Limit| {
+            matches!(result, Err(ForbildError::LimitExceeded { limit, .. }) if limit == expected)
+        };
+
+        let gif = create_gif_bomb();
+        assert!(is_exceeded(import_image_from_bytes(&gif), Limit::Pixels));
+        let bmp = create_bmp_bomb();
+        assert!(is_exceeded(import_image_from_reader(Cursor::new(&bmp)), Limit::Pixels));
+        let pnm = b"P5\n60000 60000\n255\n";
+        assert!(is_exceeded(import_image_from_bytes(pnm), Limit::Pixels));
+
+        let path = std::env::temp_dir().join(format!("forbild_test_bomb_{}.bmp", std::process::id()));
+        std::fs::write(&path, &bmp).unwrap();
+        let result = import_image_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_exceeded(result, Limit::Pixels));
+
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let limits = DecodeLimits::none().with_max_dimensions(900, 600);
+        assert!(import_image_from_file_with_limits(&path, &limits).is_ok());
+        let limits = DecodeLimits::none().with_max_dimensions(899, 600);
+        assert!(is_exceeded(import_image_from_file_with_limits(&path, &limits), Limit::Width));
+        let limits = DecodeLimits::none().with_max_file_size(1000);
+        assert!(is_exceeded(import_image_from_file_with_limits(&path, &limits), Limit::FileSize));
+        let bytes = std::fs::read(&path).unwrap();
+        let limits = DecodeLimits::none().with_max_alloc(900 * 600 * 4);
+        assert!(is_exceeded(import_image_from_bytes_with_limits(&bytes, &limits), Limit::Allocation));
+    }
+
+    #[test]
+    fn test_import_image_wrong_extension() {
+        let bytes = std::fs::read("./data/original/2015_Japan_Tokyo_Traffic.jpg").unwrap();
+        let path = std::env::temp_dir().join("forbild_test_wrong_extension.png");
+        std::fs::write(&path, &bytes).unwrap();
+
+        let result = import_image_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap(), import_image_from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_import_image_with_config() {
+        use crate::config::JpegDecoding;
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let config = HashConfig::default();
+        assert_eq!(import_image_from_file_with_config(&path, &config).unwrap(), import_image_from_file(&path).unwrap());
+
+        // 1/8 for the DC coefficients, 1/4 to keep at least 4 pixels per hash pixel for a 32x32 grid
+        let img = import_image_from_file_with_config(&path, &config.with_jpeg_decoding(JpegDecoding::Dc)).unwrap();
+        assert_eq!(img.dimensions(), (113, 75));
+        let config = config.with_size(32).with_jpeg_decoding(JpegDecoding::Scaled);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(import_image_from_bytes_with_config(&bytes, &config).unwrap().dimensions(), (225, 150));
+        let img = import_image_from_reader_with_config(Cursor::new(&bytes), &config).unwrap();
+        assert_eq!(img.dimensions(), (225, 150));
+
+        // Grayscale JPEGs stay grayscale, other formats are decoded in full
+        let gray = import_image_from_file_with_config(Path::new("./data/original/out/01_gray.jpg"), &config).unwrap();
+        assert!(matches!(gray, DynamicImage::ImageLuma8(_)));
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma8(create_grayimage()).write_to(&mut png, ImageFormat::Png).unwrap();
+        let img = import_image_from_bytes_with_config(&png, &config.with_jpeg_decoding(JpegDecoding::Dc)).unwrap();
+        assert_eq!(img.dimensions(), (16, 16));
+
+        let result = import_image_from_bytes_with_config(&bytes[..bytes.len()/2], &config);
+        assert!(matches!(result, Err(ForbildError::Decode(_))));
+    }
+
     #[test]
     fn test_downsample() {
         let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
-        let img = import_image_from_file(&path);
-        let img = downsample(img);
+        let img = import_image_from_file(&path).unwrap();
+        let img = downsample(img, DEFAULT_SIZE);
 
         let pix = img.get_pixel(0, 0);
         assert_eq!(pix[0], 39);
         assert_eq!(pix[1], 41);
         assert_eq!(pix[2], 70);
+        assert_eq!(img.dimensions(), (16, 16));
+
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let img = import_image_from_file(&path).unwrap();
+        assert_eq!(downsample(img, 32).dimensions(), (32, 32));
     }
 
     #[test]
     fn test_preprocess_image() {
         let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
-        let img = preprocess_image(&path);
+        let img = preprocess_image(&path).unwrap();
 
         let pix = img.get_pixel(0, 0);
         assert_eq!(pix[0], 42);
+
+        let decoded = import_image_from_file(&path).unwrap();
+        assert_eq!(preprocess_dynamic_image_with_config(decoded, &HashConfig::default()), img);
+    }
+
+    #[test]
+    fn test_preprocess_with_config() {
+        let red = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(32, 32, image::Rgb([200, 0, 0])));
+        let img = preprocess_dynamic_image_with_config(red.clone(), &HashConfig::default().with_size(8));
+        assert_eq!(img.dimensions(), (8, 8));
+        assert_eq!(img.get_pixel(3, 3)[0], 42);
+        let config = HashConfig::default().with_size(8).with_luma(LumaCoefficients::Rec601);
+        assert_eq!(preprocess_dynamic_image_with_config(red, &config).get_pixel(3, 3)[0], 59);
+
+        // Black and white stripes average to a brighter gray in linear light
+        let stripes = DynamicImage::ImageLuma8(GrayImage::from_fn(32, 32, |x, _y| Luma([if x % 2 == 0 { 0 } else { 255 }])));
+        let config = HashConfig::default().with_size(8).with_filter(crate::config::ResizeFilter::Triangle);
+        let srgb = preprocess_dynamic_image_with_config(stripes.clone(), &config);
+        let linear = preprocess_dynamic_image_with_config(stripes, &config.with_averaging(Averaging::Linear));
+        assert_eq!(srgb.get_pixel(3, 3)[0], 128);
+        assert_eq!(linear.get_pixel(3, 3)[0], 188);
+
+        for value in [0, 1, 10, 128, 254, 255].iter() {
+            let srgb = *value as f32 / 255.0;
+            assert_eq!((linear_to_srgb(srgb_to_linear(srgb)) * 255.0).round() as u8, *value);
+        }
+    }
+
+    #[test]
+    fn test_area_downsample() {
+        let stripes = GrayImage::from_fn(32, 32, |x, _y| Luma([if x % 2 == 0 { 0 } else { 255 }]));
+        assert!(area_downsample(&stripes, 8).pixels().all(|p| p[0] == 128));
+
+        // The middle column is split between both pixels of the result
+        let columns = GrayImage::from_fn(3, 3, |x, _y| Luma([90 * x as u8]));
+        let small = area_downsample(&columns, 2);
+        assert_eq!(small.into_raw(), vec![30, 150, 30, 150]);
+
+        let uniform = GrayImage::from_pixel(37, 23, Luma([77]));
+        assert!(area_downsample(&uniform, 16).pixels().all(|p| p[0] == 77));
+        let single = GrayImage::from_pixel(1, 1, Luma([5]));
+        assert_eq!(area_downsample(&single, 4), GrayImage::from_pixel(4, 4, Luma([5])));
+
+        // Rec. 709 luma of (200, 0, 0) is 42.52, which the integer conversion rounds
+        let red = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(32, 32, image::Rgb([200, 0, 0])));
+        let img = preprocess_dynamic_image_with_config(red, &HashConfig::bit_exact().with_size(8));
+        assert!(img.pixels().all(|p| p[0] == 43));
+
+        let stripes = DynamicImage::ImageLuma8(stripes);
+        let config = HashConfig::bit_exact().with_size(8).with_averaging(Averaging::Linear);
+        assert_eq!(preprocess_dynamic_image_with_config(stripes, &config).get_pixel(3, 3)[0], 188);
     }
 }
\ No newline at end of file
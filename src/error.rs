@@ -0,0 +1,146 @@
+use std::fmt;
+use image::ImageError;
+
+use crate::config::HashConfig;
+use crate::limits::Limit;
+
+pub type Result<T> = std::result::Result<T, ForbildError>;
+
+#[derive(Debug)]
+pub enum ForbildError {
+    /// Reading the image (or any other file) failed
+    Io(std::io::Error),
+    /// The image data could not be decoded
+    Decode(ImageError),
+    /// The image format is unknown or not supported by the decoder
+    UnsupportedFormat(String),
+    /// A character of a hex hash is not a valid (uppercase) hexadecimal digit
+    InvalidHex(char),
+    /// A character of a binary hash is neither 0 nor 1
+    InvalidBinary(char),
+    /// A hash or hash part does not have the expected number of elements
+    InvalidLength { expected: usize, found: usize },
+    /// The hash grid size is not a positive multiple of 8
+    InvalidGridSize(u32),
+    /// A stored hash record has a format version this library cannot read
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The weighted distance needs grayscale data, but the hash has none
+    MissingGrayscale,
+    /// The weighted distance is undefined, because the variance of the identical hash bits is 0
+    ZeroVariance,
+    /// A binary hash database is malformed
+    InvalidDatabase(String),
+    /// A serialized search index is malformed
+    InvalidIndex(String),
+    /// A checkpoint file is malformed or belongs to another job
+    InvalidCheckpoint(String),
+    /// A glob pattern to select files is invalid
+    InvalidPattern(String),
+    /// Hashes of these configs cannot be compared, see `HashConfig::is_comparable`
+    ConfigMismatch { expected: HashConfig, found: HashConfig },
+    /// The image is larger than the `DecodeLimits` allow, it was not decoded
+    LimitExceeded { limit: Limit, value: u64, max: u64 },
+    /// A JSON hash record could not be (de)serialized
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ForbildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForbildError::Io(err) => write!(f, "I/O error: {}", err),
+            ForbildError::Decode(err) => write!(f, "Cannot decode image: {}", err),
+            ForbildError::UnsupportedFormat(msg) => write!(f, "Unsupported image format: {}", msg),
+            ForbildError::InvalidHex(c) => write!(f, "Invalid hexadecimal character: {:?}", c),
+            ForbildError::InvalidBinary(c) => write!(f, "Invalid binary character: {:?}", c),
+            ForbildError::InvalidLength { expected, found } => {
+                write!(f, "Invalid length: expected {}, found {}", expected, found)
+            },
+            ForbildError::InvalidGridSize(size) => {
+                write!(f, "Invalid hash grid size {}: it has to be a positive multiple of 8", size)
+            },
+            ForbildError::UnsupportedVersion { found, supported } => {
+                write!(f, "Unsupported format version {} (supported: {})", found, supported)
+            },
+            ForbildError::MissingGrayscale => write!(f, "The hash has no grayscale data"),
+            ForbildError::ZeroVariance => {
+                write!(f, "The weighted distance is undefined, the variance of the identical hash bits is 0")
+            },
+            ForbildError::InvalidDatabase(msg) => write!(f, "Invalid hash database: {}", msg),
+            ForbildError::InvalidIndex(msg) => write!(f, "Invalid search index: {}", msg),
+            ForbildError::InvalidCheckpoint(msg) => write!(f, "Invalid checkpoint: {}", msg),
+            ForbildError::InvalidPattern(msg) => write!(f, "Invalid glob pattern: {}", msg),
+            ForbildError::ConfigMismatch { expected, found } => {
+                write!(f, "Hash config mismatch: expected {:?}, found {:?}", expected, found)
+            },
+            ForbildError::LimitExceeded { limit, value, max } => {
+                write!(f, "Image {} limit exceeded: {} > {}", limit, value, max)
+            },
+            #[cfg(feature = "serde")]
+            ForbildError::Json(err) => write!(f, "JSON error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ForbildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ForbildError::Io(err) => Some(err),
+            ForbildError::Decode(err) => Some(err),
+            #[cfg(feature = "serde")]
+            ForbildError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ForbildError {
+    fn from(err: std::io::Error) -> Self {
+        ForbildError::Io(err)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for ForbildError {
+    fn from(err: serde_json::Error) -> Self {
+        ForbildError::Json(err)
+    }
+}
+
+impl From<ImageError> for ForbildError {
+    fn from(err: ImageError) -> Self {
+        // I/O errors raised by a decoder mean truncated or corrupt data, so they count as decoding errors
+        match err {
+            ImageError::Unsupported(err) => ForbildError::UnsupportedFormat(err.to_string()),
+            err => ForbildError::Decode(err),
+        }
+    }
+}
+
+impl From<jpeg_decoder::Error> for ForbildError {
+    /// Errors of the reduced scale JPEG decoding, mapped like those of `image`'s JPEG decoder
+    fn from(err: jpeg_decoder::Error) -> Self {
+        use image::error::{DecodingError, ImageFormatHint};
+        let format = ImageFormatHint::Exact(image::ImageFormat::Jpeg);
+        match err {
+            jpeg_decoder::Error::Unsupported(feature) => ForbildError::UnsupportedFormat(format!("JPEG {:?}", feature)),
+            jpeg_decoder::Error::Io(err) => ForbildError::Decode(ImageError::IoError(err)),
+            err => ForbildError::Decode(ImageError::Decoding(DecodingError::new(format, err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use image::error::{UnsupportedError, ImageFormatHint};
+    use super::*;
+
+    #[test]
+    fn test_from_image_error() {
+        let err = ImageError::Unsupported(UnsupportedError::from(ImageFormatHint::Unknown));
+        assert!(matches!(ForbildError::from(err), ForbildError::UnsupportedFormat(_)));
+
+        let err = ImageError::IoError(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(matches!(ForbildError::from(err), ForbildError::Decode(_)));
+    }
+}
@@ -1,77 +1,238 @@
-use std::path::PathBuf;
-use image::GrayImage;
-
-use crate::editing::{preprocess_image, mirror_by_brightest_pixel};
+use std::fmt;
+use std::io::{Read, Seek};
+use std::path::Path;
+use image::{DynamicImage, GrayImage};
+
+use crate::config::{HashConfig, ThresholdStatistic};
+use crate::editing::{
+    import_image_from_bytes, import_image_from_bytes_with_config, import_image_from_file,
+    import_image_from_file_with_config, import_image_from_reader, import_image_from_reader_with_config, normalize_flip, preprocess_dynamic_image_with_config, Normalization,
+};
+use crate::error::{ForbildError, Result};
 use crate::hashmath::hex_to_binary;
-use crate::SIZE;
-
-const HASHLEN: usize = (SIZE*SIZE) as usize;
+use crate::orientation::Orientation;
+use crate::{DEFAULT_SIZE, check_size};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::record::HashRepr", into = "crate::record::HashRepr"))]
 pub struct Hash {
-    pub grayimage256: [u8; HASHLEN],
-    pub binary256: [u8; HASHLEN],
+    /// Width and height of the hash grid, the hash has size*size bits
+    pub size: u32,
+    pub grayimage256: Vec<u8>,
+    /// False if grayimage256 and subarea_medians are not known, e.g. for hashes created from hex hashes.
+    /// Such hashes cannot be used as first hash of the weighted distance.
+    pub has_grayscale: bool,
+    /// Binary hash packed row by row, the first bit being the most significant bit of the first word
+    pub binary256: Vec<u64>,
+    /// Thresholds of the subareas, the medians unless the config chooses another `ThresholdStatistic`
     pub subarea_medians: [[u8; 2]; 2],
+    /// Flip applied to the downsampled image by the `Normalization`, `Identity` if it is not known
+    pub flip: Orientation,
+    /// Config the hash was created with, the default config if it is not known (e.g. for hex hashes)
+    pub config: HashConfig,
 }
 
 impl Hash {
     pub fn new() -> Hash {
-        Hash {
-            grayimage256: [0; HASHLEN],
-            binary256: [0; HASHLEN],
+        Hash::with_size(DEFAULT_SIZE).unwrap()
+    }
+
+    /// Creates an empty hash with a size x size grid. The size has to be a positive multiple of 8.
+    pub fn with_size(size: u32) -> Result<Hash> {
+        check_size(size)?;
+        let hashlen = (size*size) as usize;
+
+        Ok(Hash {
+            size,
+            grayimage256: vec![0; hashlen],
+            has_grayscale: false,
+            binary256: vec![0; hashlen/64],
             subarea_medians: [[0; 2]; 2],
-        }
+            flip: Orientation::Identity,
+            config: HashConfig::default().with_size(size),
+        })
     }
 
-    pub fn from_path(path: &PathBuf) -> Hash {
+    pub fn from_path(path: &Path) -> Result<Hash> {
+        Hash::from_path_with_size(path, DEFAULT_SIZE)
+    }
+
+    pub fn from_path_with_size(path: &Path, size: u32) -> Result<Hash> {
+        check_size(size)?;
+        Hash::from_dynamic_image_with_size(import_image_from_file(path)?, size)
+    }
+
+    /// Hashes the image with the decoding, preprocessing and hashing steps of the config
+    pub fn from_path_with_config(path: &Path, config: &HashConfig) -> Result<Hash> {
+        check_size(config.size)?;
+        Hash::from_dynamic_image_with_config(import_image_from_file_with_config(path, config)?, config)
+    }
+
+    /// Hashes an encoded image in memory, the format is guessed from its content
+    pub fn from_bytes(bytes: &[u8]) -> Result<Hash> {
+        Hash::from_bytes_with_size(bytes, DEFAULT_SIZE)
+    }
+
+    pub fn from_bytes_with_size(bytes: &[u8], size: u32) -> Result<Hash> {
+        check_size(size)?;
+        Hash::from_dynamic_image_with_size(import_image_from_bytes(bytes)?, size)
+    }
+
+    pub fn from_bytes_with_config(bytes: &[u8], config: &HashConfig) -> Result<Hash> {
+        check_size(config.size)?;
+        Hash::from_dynamic_image_with_config(import_image_from_bytes_with_config(bytes, config)?, config)
+    }
+
+    /// Hashes an encoded image read from e.g. an archive or network stream, the format is guessed from its content
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<Hash> {
+        Hash::from_reader_with_size(reader, DEFAULT_SIZE)
+    }
+
+    pub fn from_reader_with_size<R: Read + Seek>(reader: R, size: u32) -> Result<Hash> {
+        check_size(size)?;
+        Hash::from_dynamic_image_with_size(import_image_from_reader(reader)?, size)
+    }
+
+    pub fn from_reader_with_config<R: Read + Seek>(reader: R, config: &HashConfig) -> Result<Hash> {
+        check_size(config.size)?;
+        Hash::from_dynamic_image_with_config(import_image_from_reader_with_config(reader, config)?, config)
+    }
+
+    pub fn from_dynamic_image(img: DynamicImage) -> Result<Hash> {
+        Hash::from_dynamic_image_with_size(img, DEFAULT_SIZE)
+    }
+
+    pub fn from_dynamic_image_with_size(img: DynamicImage, size: u32) -> Result<Hash> {
+        Hash::from_dynamic_image_with_config(img, &HashConfig::default().with_size(size))
+    }
+
+    /// Hashes the image with another way of choosing the flip than the brightest pixel, see `Normalization`.
+    /// Only hashes with the same normalisation can be compared.
+    pub fn from_dynamic_image_with_normalization(img: DynamicImage, size: u32, normalization: Normalization) -> Result<Hash> {
+        Hash::from_dynamic_image_with_config(img, &HashConfig::default().with_size(size).with_normalization(normalization))
+    }
+
+    /// All other constructors for images end up here, so they produce identical hashes
+    pub fn from_dynamic_image_with_config(img: DynamicImage, config: &HashConfig) -> Result<Hash> {
+        check_size(config.size)?;
+
         // Processing raw image
-        let mut img = preprocess_image(path);
-        let img = mirror_by_brightest_pixel(&mut img);
+        let mut img = preprocess_dynamic_image_with_config(img, config);
+        let flip = normalize_flip(&mut img, config.normalization);
 
-        Hash::from_grayimage(img.to_owned())
+        let mut hash = Hash::from_grayimage_with_threshold(img, config.threshold)?;
+        hash.flip = flip;
+        hash.config = *config;
+        Ok(hash)
     }
 
-    pub fn from_grayimage(img: GrayImage) -> Hash {
-        let mut hash = Hash::new();
+    /// Creates the hash from an already downsampled and mirrored square grayscale image
+    pub fn from_grayimage(img: GrayImage) -> Result<Hash> {
+        Hash::from_grayimage_with_threshold(img, ThresholdStatistic::Median)
+    }
+
+    pub fn from_grayimage_with_threshold(img: GrayImage, threshold: ThresholdStatistic) -> Result<Hash> {
+        let (width, height) = img.dimensions();
+        if width != height {
+            return Err(ForbildError::InvalidLength { expected: width as usize, found: height as usize });
+        }
+        let mut hash = Hash::with_size(width)?;
 
         // Saving grayscale image to array (necessary for weighted distance calculation)
         hash.set_grayimage(img);
 
-        // Setting the subarea medians
-        hash.set_subarea_medians();
-        
+        // Setting the subarea thresholds
+        hash.set_subarea_thresholds(threshold);
+        hash.config.threshold = threshold;
+
         // Calculating Hash from grayscale image
         hash.set_binary_hash_from_grayimage();
 
-        hash
+        Ok(hash)
     }
 
-    pub fn from_hexhash(hexhash: &[char; HASHLEN/4]) -> Hash {
-        let mut binaryhash = [0; HASHLEN];
+    /// Creates a hash (without grayscale data) from its hex representation.
+    /// The grid size is derived from the number of hex characters, e.g. 64 characters for 16x16.
+    pub fn from_hexhash(hexhash: &[char]) -> Result<Hash> {
+        let size = grid_size_of(hexhash.len()*4).ok_or(ForbildError::InvalidLength {
+            expected: nearest_valid_bits(hexhash.len()*4)/4,
+            found: hexhash.len()
+        })?;
+        let mut hash = Hash::with_size(size)?;
 
         for (i, hexval) in hexhash.iter().enumerate() {
-            let binaries = hex_to_binary(hexval).unwrap();
+            let binaries = hex_to_binary(hexval).ok_or(ForbildError::InvalidHex(*hexval))?;
             for (j, b) in binaries.iter().enumerate() {
-                binaryhash[(i*4)+j] = *b;
+                hash.set_bit((i*4)+j, *b == 1);
             }
         }
 
-        let mut hash = Hash::new();
-        hash.binary256 = binaryhash;
-        hash
+        Ok(hash)
+    }
+
+    pub fn from_hex_str(hexhash: &str) -> Result<Hash> {
+        let chars: Vec<char> = hexhash.trim().chars().collect();
+        Hash::from_hexhash(&chars)
+    }
+
+    /// Creates a hash (without grayscale data) from its binary representation as written by `Display`.
+    /// The grid size is derived from the number of bits, e.g. 256 bits for 16x16.
+    pub fn from_binary_str(binary: &str) -> Result<Hash> {
+        let binary = binary.trim();
+        let size = grid_size_of(binary.len()).ok_or(ForbildError::InvalidLength {
+            expected: nearest_valid_bits(binary.len()),
+            found: binary.len()
+        })?;
+        let mut hash = Hash::with_size(size)?;
+
+        for (i, c) in binary.chars().enumerate() {
+            match c {
+                '0' => {},
+                '1' => hash.set_bit(i, true),
+                c => return Err(ForbildError::InvalidBinary(c)),
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Number of bits of the binary hash
+    pub fn len(&self) -> usize {
+        (self.size*self.size) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the bit at position i of the binary hash (0 or 1)
+    pub fn get_bit(&self, i: usize) -> u8 {
+        ((self.binary256[i/64] >> (63 - i%64)) & 1) as u8
+    }
+
+    pub fn set_bit(&mut self, i: usize, bit: bool) {
+        let mask = 1 << (63 - i%64);
+        match bit {
+            true => self.binary256[i/64] |= mask,
+            false => self.binary256[i/64] &= !mask,
+        }
     }
 
     fn set_grayimage(&mut self, img: GrayImage) {
         for (x, y, pix) in img.enumerate_pixels() {
-            self.grayimage256[(x + SIZE*y) as usize] = pix[0];
+            self.grayimage256[(x + self.size*y) as usize] = pix[0];
         }
+        self.has_grayscale = true;
     } 
 
     pub fn get_subarea(&self, i: usize) -> SubArea {
+        let hashlen = self.len();
+
         // Subarea top and bottom left
-        if (i as u32)%SIZE < SIZE/2 {
+        if (i as u32)%self.size < self.size/2 {
             // Subarea top left
-            if i < HASHLEN/2 {
+            if i < hashlen/2 {
                 SubArea::TopLeft
             }
             // Subarea bottom left
@@ -82,7 +243,7 @@ impl Hash {
         // Subarea top and bottom right
         else {
             // Subarea top right
-            if i < HASHLEN/2 {
+            if i < hashlen/2 {
                 SubArea::TopRight
             }
             // Subarea bottom right
@@ -93,10 +254,11 @@ impl Hash {
     }
 
     pub fn set_subarea_medians(&mut self) {
-        let mut top_left = Vec::with_capacity(64);
-        let mut top_right = Vec::with_capacity(64);
-        let mut bot_left = Vec::with_capacity(64);
-        let mut bot_right = Vec::with_capacity(64);
+        let quadlen = self.len()/4;
+        let mut top_left = Vec::with_capacity(quadlen);
+        let mut top_right = Vec::with_capacity(quadlen);
+        let mut bot_left = Vec::with_capacity(quadlen);
+        let mut bot_right = Vec::with_capacity(quadlen);
 
         for (i, val) in self.grayimage256.iter().enumerate() {
             match self.get_subarea(i) {
@@ -108,81 +270,126 @@ impl Hash {
         }
 
         // Sorting each subareas grayimage values
-        top_left.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        top_right.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        bot_left.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        bot_right.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        top_left.sort_unstable();
+        top_right.sort_unstable();
+        bot_left.sort_unstable();
+        bot_right.sort_unstable();
 
         // Setting the median value
-        self.subarea_medians[0][0] = top_left[32];
-        self.subarea_medians[1][0] = top_right[32];
-        self.subarea_medians[0][1] = bot_left[32];
-        self.subarea_medians[1][1] = bot_right[32];
+        let median = quadlen/2;
+        self.subarea_medians[0][0] = top_left[median];
+        self.subarea_medians[1][0] = top_right[median];
+        self.subarea_medians[0][1] = bot_left[median];
+        self.subarea_medians[1][1] = bot_right[median];
     }
 
-    pub fn set_binary_hash_from_grayimage(&mut self) {
+    /// Sets the thresholds of the subareas to the medians or means
+    pub fn set_subarea_thresholds(&mut self, threshold: ThresholdStatistic) {
+        match threshold {
+            ThresholdStatistic::Median => self.set_subarea_medians(),
+            ThresholdStatistic::Mean => self.set_subarea_means(),
+        }
+    }
+
+    fn set_subarea_means(&mut self) {
+        let mut sums = [[0u32; 2]; 2];
         for (i, val) in self.grayimage256.iter().enumerate() {
+            match self.get_subarea(i) {
+                SubArea::TopLeft => sums[0][0] += *val as u32,
+                SubArea::TopRight => sums[1][0] += *val as u32,
+                SubArea::BottomLeft => sums[0][1] += *val as u32,
+                SubArea::BottomRight => sums[1][1] += *val as u32,
+            }
+        }
+
+        let quadlen = (self.len()/4) as u32;
+        for (col, sums) in sums.iter().enumerate() {
+            for (row, sum) in sums.iter().enumerate() {
+                self.subarea_medians[col][row] = (sum/quadlen) as u8;
+            }
+        }
+    }
+
+    pub fn set_binary_hash_from_grayimage(&mut self) {
+        for i in 0..self.len() {
             let median = match self.get_subarea(i) {
                 SubArea::TopLeft => self.subarea_medians[0][0],
                 SubArea::TopRight => self.subarea_medians[1][0],
                 SubArea::BottomLeft => self.subarea_medians[0][1],
                 SubArea::BottomRight => self.subarea_medians[1][1],
             };
-            self.binary256[i] = match *val >= median {
-                true => 1,
-                false => 0
-            }; 
+            self.set_bit(i, self.grayimage256[i] >= median);
+        }
+    }
+
+    /// Returns the hash of the rotated or flipped grid. The grayscale image, the binary hash and the
+    /// subarea medians are moved along, so hashes without grayscale data can be oriented as well.
+    /// The result is not normalised again and keeps the flip of the normalisation.
+    pub fn oriented(&self, orientation: Orientation) -> Hash {
+        let mut oriented = self.clone();
+        if orientation == Orientation::Identity {
+            return oriented;
+        }
+
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let (new_x, new_y) = orientation.map(x, y, self.size);
+                let (i, new_i) = ((x + self.size*y) as usize, (new_x + self.size*new_y) as usize);
+                oriented.grayimage256[new_i] = self.grayimage256[i];
+                oriented.set_bit(new_i, self.get_bit(i) == 1);
+            }
+        }
+
+        // The subareas are moved as a whole, so every pixel keeps its median
+        let half = self.size/2;
+        for col in 0..2 {
+            for row in 0..2 {
+                let (new_x, new_y) = orientation.map(col*half, row*half, self.size);
+                oriented.subarea_medians[(new_x/half) as usize][(new_y/half) as usize] = self.subarea_medians[col as usize][row as usize];
+            }
         }
+        oriented
     }
 
-    pub fn to_string(&self) -> String {
+    pub fn to_hex(&self) -> Vec<char> {
+        self.to_string_hex().chars().collect()
+    }
+
+    pub fn to_string_hex(&self) -> String {
         self.binary256
             .iter()
-            .map(|b| b.to_string())
+            .map(|word| format!("{:016X}", word))
             .collect()
     }
+}
 
-    pub fn to_hex(&self) -> [char; HASHLEN/4] {
-        let mut hex_hash: [char; HASHLEN/4] = ['0'; HASHLEN/4];
-
-        for i in 0..(HASHLEN/4) {
-            let hexval = match self.binary256[(4*i)..(4*i+4)] {
-                [0, 0, 0, 0] => Some('0'),
-                [0, 0, 0, 1] => Some('1'),
-                [0, 0, 1, 0] => Some('2'),
-                [0, 0, 1, 1] => Some('3'),
-                [0, 1, 0 ,0] => Some('4'),
-                [0, 1, 0, 1] => Some('5'),
-                [0, 1, 1, 0] => Some('6'),
-                [0, 1, 1, 1] => Some('7'),
-                [1, 0, 0, 0] => Some('8'),
-                [1, 0, 0, 1] => Some('9'),
-                [1, 0, 1, 0] => Some('A'),
-                [1, 0, 1, 1] => Some('B'),
-                [1, 1, 0, 0] => Some('C'),
-                [1, 1, 0, 1] => Some('D'),
-                [1, 1, 1, 0] => Some('E'),
-                [1, 1, 1, 1] => Some('F'),
-                _ => None
-            };
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::new()
+    }
+}
 
-            if hexval.is_some() {
-            hex_hash[i] = hexval.unwrap();
-            } else {
-                eprintln!("ERROR: A part of the binary hash cannot be converted to hexadecimal.");
-                std::process::exit(1);
-            }
+impl fmt::Display for Hash {
+    /// Writes the binary hash as a string of 0s and 1s
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for word in self.binary256.iter() {
+            write!(f, "{:064b}", word)?;
         }
-        hex_hash
+        Ok(())
     }
+}
 
-    pub fn to_string_hex(&self) -> String {
-        let hash = self.to_hex();
-        hash.iter()
-            .map(|c| c.to_string())
-            .collect::<Vec<String>>()
-            .concat()
-    }
+/// Grid size of a hash with `bits` bits, `None` if no valid grid size has that many bits
+pub(crate) fn grid_size_of(bits: usize) -> Option<u32> {
+    let size = (bits as f64).sqrt() as u32;
+    ((size*size) as usize == bits && check_size(size).is_ok()).then_some(size)
+}
+
+/// Number of bits of the valid grid size nearest to `bits`, for the length errors of the parsers
+fn nearest_valid_bits(bits: usize) -> usize {
+    // Valid grid sizes are multiples of 8, i.e. 64*k*k bits
+    let k = ((bits/64) as f64).sqrt().max(1.0) as usize;
+    [k, k + 1].iter().map(|k| 64*k*k).min_by_key(|valid| valid.abs_diff(bits)).unwrap()
 }
 
 #[derive(Debug)]
@@ -196,9 +403,15 @@ pub enum SubArea {
 
 #[cfg(test)]
 mod hash_tests {
+    use std::path::PathBuf;
     use image::{ImageBuffer, Luma};
+    use crate::config::{Averaging, LumaCoefficients, ResizeFilter};
+    use crate::hashmath::pack_binary;
     use super::*;
 
+    const HASHLEN: usize = 256;
+    const WORDS: usize = HASHLEN/64;
+
     fn create_grayimage() -> GrayImage {
         let mut img: ImageBuffer<Luma<u8> , Vec<u8>> = ImageBuffer::new(16, 16);
 
@@ -213,22 +426,84 @@ mod hash_tests {
     fn test_hash_new() {
         let hash = Hash::new();
         assert_eq!(hash.grayimage256, [0; HASHLEN]);
-        assert_eq!(hash.binary256, [0; HASHLEN]);
+        assert!(!hash.has_grayscale);
+        assert_eq!(hash.binary256, [0; WORDS]);
         assert_eq!(hash.subarea_medians, [[0; 2]; 2]);
     }
 
     #[test]
     fn test_hash_from_path() {
         let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
-        let hash = Hash::from_path(&path);
+        let hash = Hash::from_path(&path).unwrap();
 
         let correct_binary = [0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 1, 1, 1, 1, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 1, 0, 0, 0, 1, 0, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0];
         let correct_grayimage = [42, 34, 33, 34, 36, 51, 77, 99, 103, 91, 90, 91, 86, 82, 69, 53, 102, 53, 46, 39, 45, 62, 74, 80, 80, 71, 71, 76, 78, 80, 78, 62, 117, 64, 54, 50, 78, 102, 84, 71, 65, 65, 67, 69, 71, 71, 76, 54, 107, 66, 62, 68, 113, 136, 92, 72, 63, 61, 57, 58, 57, 58, 73, 45, 91, 66, 68, 85, 145, 178, 101, 73, 66, 66, 63, 59, 46, 46, 53, 31, 76, 51, 51, 81, 136, 179, 94, 74, 69, 70, 66, 59, 43, 45, 44, 29, 59, 41, 40, 68, 100, 131, 85, 82, 81, 78, 69, 60, 45, 46, 39, 31, 42, 27, 42, 61, 78, 100, 85, 88, 116, 97, 70, 60, 51, 50, 41, 35, 37, 36, 49, 68, 83, 115, 112, 115, 139, 85, 64, 57, 52, 50, 42, 37, 39, 43, 62, 103, 123, 139, 146, 121, 106, 79, 61, 49, 51, 51, 47, 46, 38, 46, 70, 111, 130, 123, 88, 74, 63, 48, 45, 53, 68, 65, 57, 63, 41, 50, 48, 62, 67, 62, 81, 79, 56, 52, 60, 70, 69, 64, 66, 53, 56, 39, 21, 25, 50, 55, 45, 47, 53, 70, 71, 68, 61, 60, 59, 29, 51, 28, 26, 41, 75, 70, 40, 44, 65, 69, 67, 56, 60, 64, 32, 15, 61, 27, 24, 33, 53, 51, 52, 65, 65, 62, 54, 43, 53, 40, 17, 16, 37, 37, 26, 22, 27, 42, 63, 60, 51, 45, 37, 38, 43, 19, 14, 16];
         let correct_subarea_medians = [[74, 52], [65, 56]];
 
-        assert_eq!(hash.binary256, correct_binary);
+        assert_eq!(hash.binary256, pack_binary(&correct_binary));
         assert_eq!(hash.grayimage256, correct_grayimage);
         assert_eq!(hash.subarea_medians, correct_subarea_medians);
+        assert!(hash.has_grayscale);
+    }
+
+    #[test]
+    fn test_hash_jpeg_decoding() {
+        use crate::config::{JpegDecoding, SCALED_OVERSAMPLING};
+        use crate::editing::decode_image_from_file;
+        use crate::hashmath::hamming_distance;
+        use crate::limits::DecodeLimits;
+
+        for name in ["2015_Japan_Tokyo_Traffic", "2017_China_Chongqing_Boats"].iter() {
+            let path = PathBuf::from(format!("./data/original/{}.jpg", name));
+            let full = Hash::from_path(&path).unwrap();
+            for jpeg in [JpegDecoding::Scaled, JpegDecoding::Dc].iter() {
+                let config = HashConfig::default().with_jpeg_decoding(*jpeg);
+                let hash = Hash::from_path_with_config(&path, &config).unwrap();
+                assert_eq!(hash.config.jpeg, *jpeg);
+                // The reduced scale changes a few bits, the hashes still match by Hamming distance
+                assert!(hamming_distance(&full, &hash) <= 8, "{} {:?}", name, jpeg);
+
+                let bytes = std::fs::read(&path).unwrap();
+                assert_eq!(Hash::from_bytes_with_config(&bytes, &config).unwrap(), hash);
+
+                // The image really is decoded at a reduced size, the header dimensions are kept
+                let decoded = decode_image_from_file(&path, &DecodeLimits::default(), &config).unwrap();
+                let (width, height) = image::GenericImageView::dimensions(&decoded.image);
+                assert!(width < decoded.width && height < decoded.height, "{} {:?}", name, jpeg);
+                match jpeg {
+                    JpegDecoding::Dc => assert_eq!((width, height), (decoded.width.div_ceil(8), decoded.height.div_ceil(8))),
+                    _ => assert!(width.max(height) >= SCALED_OVERSAMPLING * config.size),
+                }
+            }
+        }
+    }
+
+    /// Pins the hashes of the sample images for the default filter and the bit-exact area average.
+    /// On a mismatch, all calculated lines are printed.
+    #[test]
+    fn test_golden_vectors() {
+        let path = PathBuf::from("./data/golden/hashes.csv");
+        let content = std::fs::read_to_string(&path).unwrap();
+
+        let mut expected = Vec::new();
+        let mut actual = Vec::new();
+        for line in content.lines().filter(|line| !line.starts_with('#') && !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields.len(), 5, "{}", line);
+            let filter = match fields[1] {
+                "gaussian" => ResizeFilter::Gaussian,
+                "area" => ResizeFilter::Area,
+                other => panic!("unknown filter {}", other),
+            };
+            let config = HashConfig::default().with_filter(filter).with_size(fields[2].parse().unwrap());
+
+            let hash = Hash::from_path_with_config(Path::new(fields[0]), &config).unwrap();
+            expected.push(line.to_string());
+            actual.push(format!("{},{},{},{},{}", fields[0], fields[1], fields[2], hash.flip, hash.to_string_hex()));
+        }
+
+        assert!(expected.len() >= 36);
+        assert!(expected == actual, "hashes differ from {:?}, calculated:\n{}", path, actual.join("\n"));
     }
 
     #[test]
@@ -247,9 +522,109 @@ mod hash_tests {
             hexhash[i] = h;
         }
 
-        let hash = Hash::from_hexhash(&hexhash);
+        let hash = Hash::from_hexhash(&hexhash).unwrap();
+        assert_eq!(hash.binary256, pack_binary(&binaryhash));
+        assert!(!hash.has_grayscale);
+
+        let hash = Hash::from_hex_str(&hexes).unwrap();
+        assert_eq!(hash.binary256, pack_binary(&binaryhash));
+    }
+
+    #[test]
+    fn test_hash_from_path_with_size() {
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+
+        for size in [8, 16, 32].iter() {
+            let hash = Hash::from_path_with_size(&path, *size).unwrap();
+            assert_eq!(hash.size, *size);
+            assert_eq!(hash.len(), (size*size) as usize);
+            assert_eq!(hash.grayimage256.len(), hash.len());
+            assert_eq!(hash.binary256.len(), hash.len()/64);
+            assert_eq!(hash.to_string().len(), hash.len());
+            assert_eq!(hash.to_string_hex().len(), hash.len()/4);
+
+            // At least half of the pixels of each subarea are as bright as its median or brighter, more on ties
+            let ones: u32 = hash.binary256.iter().map(|w| w.count_ones()).sum();
+            assert!(ones as usize >= hash.len()/2);
+
+            let from_hex = Hash::from_hex_str(&hash.to_string_hex()).unwrap();
+            assert_eq!(from_hex.size, *size);
+            assert_eq!(from_hex.binary256, hash.binary256);
+        }
+
+        let hash = Hash::from_path_with_size(&path, 16).unwrap();
+        assert_eq!(hash, Hash::from_path(&path).unwrap());
+    }
+
+    #[test]
+    fn test_hash_entry_points_identical() {
+        let path = PathBuf::from("./data/original/2015_Japan_Tokyo_Traffic.jpg");
+        let bytes = std::fs::read(&path).unwrap();
+        let hash = Hash::from_path(&path).unwrap();
+
+        assert_eq!(Hash::from_bytes(&bytes).unwrap(), hash);
+        assert_eq!(Hash::from_reader(std::io::Cursor::new(&bytes)).unwrap(), hash);
+        assert_eq!(Hash::from_reader(std::fs::File::open(&path).unwrap()).unwrap(), hash);
+        let img = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(Hash::from_dynamic_image(img.clone()).unwrap(), hash);
+
+        let hash = Hash::from_path_with_size(&path, 32).unwrap();
+        assert_eq!(Hash::from_bytes_with_size(&bytes, 32).unwrap(), hash);
+        assert_eq!(Hash::from_dynamic_image_with_size(img, 32).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_hash_from_bytes_invalid() {
+        assert!(matches!(Hash::from_bytes(b"GIF89a"), Err(ForbildError::Decode(_))));
+        assert!(matches!(Hash::from_bytes(&[]), Err(ForbildError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn test_hash_invalid_size() {
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
 
-        assert_eq!(hash.binary256, binaryhash);
+        assert!(matches!(Hash::with_size(12), Err(ForbildError::InvalidGridSize(12))));
+        assert!(matches!(Hash::with_size(0), Err(ForbildError::InvalidGridSize(0))));
+        assert!(matches!(Hash::from_path_with_size(&path, 10), Err(ForbildError::InvalidGridSize(10))));
+        assert!(matches!(Hash::from_grayimage(GrayImage::new(4, 4)), Err(ForbildError::InvalidGridSize(4))));
+    }
+
+    #[test]
+    fn test_hash_from_path_missing_file() {
+        let path = PathBuf::from("./data/original/NoImageToBeFoundHere.jpg");
+        assert!(matches!(Hash::from_path(&path), Err(ForbildError::Io(_))));
+    }
+
+    #[test]
+    fn test_hash_from_hexhash_invalid() {
+        let mut hexhash = ['0'; HASHLEN/4];
+        hexhash[3] = 'G';
+        assert!(matches!(Hash::from_hexhash(&hexhash), Err(ForbildError::InvalidHex('G'))));
+
+        assert!(matches!(
+            Hash::from_hex_str("195C079D"),
+            Err(ForbildError::InvalidLength { expected: 16, found: 8 })
+        ));
+        // The nearest valid length is reported, e.g. 256 characters for 32x32
+        assert!(matches!(
+            Hash::from_hex_str(&"0".repeat(250)),
+            Err(ForbildError::InvalidLength { expected: 256, found: 250 })
+        ));
+    }
+
+    #[test]
+    fn test_hash_from_binary_str() {
+        let binaries = "0001100101011100000001111001110101010011010100101100110010110110110101101010011001110011110011101110011001000110110010110101010000011010100010110010001011000101111011101110100010110011101010101000010111010111000110001101101010010111101010101011111111111101";
+        let hash = Hash::from_binary_str(binaries).unwrap();
+
+        assert_eq!(hash.to_string_hex(), "195C079D5352CCB6D6A673CEE646CB541A8B22C5EEE8B3AA85D718DA97AABFFD");
+        assert_eq!(hash.to_string(), binaries);
+        assert!(!hash.has_grayscale);
+
+        assert_eq!(Hash::from_binary_str(&"1".repeat(64)).unwrap().size, 8);
+        assert!(matches!(Hash::from_binary_str(&"2".repeat(64)), Err(ForbildError::InvalidBinary('2'))));
+        assert!(matches!(Hash::from_binary_str("0101"), Err(ForbildError::InvalidLength { expected: 64, found: 4 })));
+        assert!(matches!(Hash::from_binary_str(&"0".repeat(200)), Err(ForbildError::InvalidLength { expected: 256, found: 200 })));
     }
 
     #[test]
@@ -274,12 +649,19 @@ mod hash_tests {
         matches!(hash.get_subarea(250), SubArea::BottomRight);
         matches!(hash.get_subarea(17), SubArea::TopRight);
         matches!(hash.get_subarea(210), SubArea::BottomLeft);
+
+        let hash = Hash::with_size(8).unwrap();
+
+        assert!(matches!(hash.get_subarea(3), SubArea::TopLeft));
+        assert!(matches!(hash.get_subarea(4), SubArea::TopRight));
+        assert!(matches!(hash.get_subarea(32), SubArea::BottomLeft));
+        assert!(matches!(hash.get_subarea(63), SubArea::BottomRight));
     }
 
     #[test]
     fn test_set_subarea_medians() {
         let img = create_grayimage();
-        let mut hash = Hash::from_grayimage(img.clone());
+        let mut hash = Hash::from_grayimage(img.clone()).unwrap();
 
         assert_eq!(hash.subarea_medians, [[7, 15], [15, 23]]);
 
@@ -291,18 +673,18 @@ mod hash_tests {
     #[test]
     fn test_set_binary_hash_from_grayimage() {
         let img = create_grayimage();
-        let mut hash = Hash::from_grayimage(img);
+        let mut hash = Hash::from_grayimage(img).unwrap();
         let correct_binary = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
 
         hash.set_binary_hash_from_grayimage();
 
-        assert_eq!(hash.binary256, correct_binary);
+        assert_eq!(hash.binary256, pack_binary(&correct_binary));
     }
 
     #[test]
     fn test_to_string() {
         let img = create_grayimage();
-        let mut hash = Hash::from_grayimage(img);
+        let hash = Hash::from_grayimage(img).unwrap();
 
         let correct_string = "0000000100000001000000110000001100000111000001110000111100001111000111110001111100111111001111110111111101111111111111111111111100000001000000010000001100000011000001110000011100001111000011110001111100011111001111110011111101111111011111111111111111111111".to_string();
 
@@ -326,10 +708,102 @@ mod hash_tests {
         }
 
         let mut hash = Hash::new();
-        hash.binary256 = binaryhash;
+        hash.binary256 = pack_binary(&binaryhash);
 
         assert_eq!(hash.to_hex(), hexhash);
 
         assert_eq!(hash.to_string_hex(), hexes);
     }
+
+    #[test]
+    fn test_oriented() {
+        let img = create_grayimage();
+        let hash = Hash::from_grayimage(img.clone()).unwrap();
+        let hash_hex = Hash::from_hex_str(&hash.to_string_hex()).unwrap();
+
+        for orientation in Orientation::ALL.iter() {
+            let img = orientation.apply(&DynamicImage::ImageLuma8(img.clone())).to_luma8();
+            let expected = Hash::from_grayimage(img).unwrap();
+            assert_eq!(hash.oriented(*orientation), expected, "{}", orientation);
+            assert_eq!(hash_hex.oriented(*orientation).binary256, expected.binary256);
+        }
+        assert_eq!(hash.oriented(Orientation::Rotate90).oriented(Orientation::Rotate270), hash);
+    }
+
+    #[test]
+    fn test_hash_from_path_with_config() {
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let hash = Hash::from_path(&path).unwrap();
+        assert_eq!(hash.config, HashConfig::default());
+        assert_eq!(Hash::from_path_with_config(&path, &HashConfig::default()).unwrap(), hash);
+        assert_eq!(Hash::from_path_with_size(&path, 8).unwrap().config.size, 8);
+
+        let configs = [
+            HashConfig::default().with_filter(ResizeFilter::Lanczos3),
+            HashConfig::default().with_luma(LumaCoefficients::Rec601),
+            HashConfig::default().with_averaging(Averaging::Linear),
+            HashConfig::default().with_threshold(ThresholdStatistic::Mean),
+            HashConfig::default().with_normalization(Normalization::Centroid),
+        ];
+        for config in configs.iter() {
+            let hash_config = Hash::from_path_with_config(&path, config).unwrap();
+            assert_eq!(hash_config.config, *config);
+            assert_ne!(hash_config.grayimage256, vec![0; 256]);
+            // Every config still finds the image itself
+            assert!(crate::hashmath::hamming_distance(&hash, &hash_config) < 64, "{:?}", config);
+        }
+
+        let hash_mean = Hash::from_path_with_config(&path, &configs[3]).unwrap();
+        assert_eq!(hash_mean.grayimage256, hash.grayimage256);
+        assert_ne!(hash_mean.subarea_medians, hash.subarea_medians);
+        assert!(matches!(Hash::from_path_with_config(&path, &HashConfig::default().with_size(12)), Err(ForbildError::InvalidGridSize(12))));
+    }
+
+    #[test]
+    fn test_set_subarea_means() {
+        let mut hash = Hash::from_grayimage_with_threshold(create_grayimage(), ThresholdStatistic::Mean).unwrap();
+        assert_eq!(hash.config.threshold, ThresholdStatistic::Mean);
+        assert_eq!(hash.subarea_medians, [[7, 15], [15, 23]]);
+
+        hash.grayimage256[0] = 255;
+        hash.set_subarea_thresholds(ThresholdStatistic::Mean);
+        assert_eq!(hash.subarea_medians[0][0], 10);
+        hash.set_subarea_thresholds(ThresholdStatistic::Median);
+        assert_eq!(hash.subarea_medians[0][0], 7);
+    }
+
+    #[test]
+    fn test_hash_flip() {
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let img = image::open(&path).unwrap();
+        let hash = Hash::from_dynamic_image(img.clone()).unwrap();
+        assert_eq!(hash, Hash::from_dynamic_image_with_normalization(img.clone(), 16, Normalization::BrightestPixel).unwrap());
+
+        for normalization in [Normalization::BrightestPixel, Normalization::BrightestQuadrant, Normalization::Centroid].iter() {
+            let hash = Hash::from_dynamic_image_with_normalization(img.clone(), 16, *normalization).unwrap();
+            let mirrored = Hash::from_dynamic_image_with_normalization(img.fliph(), 16, *normalization).unwrap();
+            assert_eq!(mirrored.binary256, hash.binary256, "{:?}", normalization);
+            assert_ne!(mirrored.flip, hash.flip);
+        }
+
+        let hash = Hash::from_dynamic_image_with_normalization(img.flipv(), 16, Normalization::None).unwrap();
+        assert_eq!(hash.flip, Orientation::Identity);
+        assert!(!Hash::from_hex_str(&hash.to_string_hex()).unwrap().has_grayscale);
+    }
+
+    #[test]
+    fn test_get_and_set_bit() {
+        let mut hash = Hash::new();
+
+        hash.set_bit(0, true);
+        hash.set_bit(65, true);
+        assert_eq!(hash.binary256, [1 << 63, 1 << 62, 0, 0]);
+        assert_eq!(hash.get_bit(0), 1);
+        assert_eq!(hash.get_bit(1), 0);
+        assert_eq!(hash.get_bit(65), 1);
+
+        hash.set_bit(0, false);
+        assert_eq!(hash.get_bit(0), 0);
+        assert_eq!(hash.binary256, [0, 1 << 62, 0, 0]);
+    }
 }
\ No newline at end of file
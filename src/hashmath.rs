@@ -1,32 +1,76 @@
-use crate::SIZE;
+use crate::error::{ForbildError, Result};
 use crate::hash::{Hash, SubArea};
 
-const HASHLEN: usize = (SIZE*SIZE) as usize;
-
+/// Number of differing bits of both hashes.
+///
+/// # Panics
+/// If the grid sizes of the hashes differ. `checked_hamming_distance` returns an error instead.
 pub fn hamming_distance(hash1: &Hash, hash2: &Hash) -> usize {
-    let mut dist = 0;
-    for (bit1, bit2) in hash1.binary256.iter().zip(&hash2.binary256) {
-        if *bit1 != *bit2 {
-            dist += 1;
-        }
+    checked_hamming_distance(hash1, hash2).expect("Cannot compare hashes of different grid sizes")
+}
+
+/// Number of differing bits of both hashes, `ForbildError::InvalidLength` if their grid sizes differ
+pub fn checked_hamming_distance(hash1: &Hash, hash2: &Hash) -> Result<usize> {
+    check_same_size(hash1, hash2)?;
+
+    Ok(hash1.binary256.iter()
+        .zip(&hash2.binary256)
+        .map(|(word1, word2)| (word1 ^ word2).count_ones() as usize)
+        .sum())
+}
+
+/// `ForbildError::InvalidLength` if the hashes have different grid sizes
+pub(crate) fn check_same_size(hash1: &Hash, hash2: &Hash) -> Result<()> {
+    if hash1.size != hash2.size {
+        return Err(ForbildError::InvalidLength { expected: hash1.len(), found: hash2.len() });
     }
-    dist
+    Ok(())
 }
 
-pub fn weighted_distance(hash1: &Hash, hash2: &Hash) -> f64 {
-    // Get same and different indices of both hashes
-    let mut is_hashbit_identical = [false; HASHLEN];
-    let mut i = 0;
-    let mut identical_count = 0;
-    for (bit1, bit2) in hash1.binary256.iter().zip(&hash2.binary256) {
-        if *bit1 == *bit2 {
-            is_hashbit_identical[i] = true;
-            identical_count += 1;
-        } 
-        i += 1;
+/// How the weighted distance is calculated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightedMode {
+    /// Only uses the grayscale image of the first hash, as published. wd(a, b) != wd(b, a)
+    #[default]
+    Directional,
+    /// Mean of the weighted distances of both directions. wd(a, b) == wd(b, a)
+    Symmetric,
+}
+
+pub fn weighted_distance_with_mode(hash1: &Hash, hash2: &Hash, mode: WeightedMode) -> Result<f64> {
+    match mode {
+        WeightedMode::Directional => weighted_distance(hash1, hash2),
+        WeightedMode::Symmetric => weighted_distance_symmetric(hash1, hash2),
     }
+}
+
+/// Mean of the weighted distances in both directions, so the order of the arguments does not matter.
+/// Both hashes need grayscale data, unless their binary hashes are identical.
+pub fn weighted_distance_symmetric(hash1: &Hash, hash2: &Hash) -> Result<f64> {
+    Ok((weighted_distance(hash1, hash2)? + weighted_distance(hash2, hash1)?) / 2.0)
+}
 
-    if identical_count == HASHLEN { return 0.0; }
+/// Hamming distance weighted by the grayscale variances of the differing and identical bits of hash1.
+/// Hashes of different grid sizes give `ForbildError::InvalidLength`.
+///
+/// Identical binary hashes always have a distance of 0. Otherwise the distance cannot be calculated and an error
+/// is returned if
+/// - hash1 has no grayscale data (e.g. created from a hex hash): `ForbildError::MissingGrayscale`
+/// - the variance of the identical bits is 0 (e.g. flat images) or all bits differ: `ForbildError::ZeroVariance`
+pub fn weighted_distance(hash1: &Hash, hash2: &Hash) -> Result<f64> {
+    check_same_size(hash1, hash2)?;
+    let hashlen = hash1.len();
+
+    // Get same and different indices of both hashes
+    let differing: Vec<u64> = hash1.binary256.iter()
+        .zip(&hash2.binary256)
+        .map(|(word1, word2)| word1 ^ word2)
+        .collect();
+    let hdist: usize = differing.iter().map(|word| word.count_ones() as usize).sum();
+    let identical_count = hashlen - hdist;
+
+    if identical_count == hashlen { return Ok(0.0); }
+    if !hash1.has_grayscale { return Err(ForbildError::MissingGrayscale); }
 
     // Calculate variance
     let mut var_same = 0.0;
@@ -42,7 +86,8 @@ pub fn weighted_distance(hash1: &Hash, hash2: &Hash) -> f64 {
 
         let diff = ((median as i32) - (*value as i32)).pow(2) as f64;
 
-        match is_hashbit_identical[i] {
+        let is_hashbit_identical = (differing[i/64] >> (63 - i%64)) & 1 == 0;
+        match is_hashbit_identical {
             true => { 
                 var_same += diff;
             },
@@ -52,12 +97,27 @@ pub fn weighted_distance(hash1: &Hash, hash2: &Hash) -> f64 {
         }
     }
 
+    if identical_count == 0 || var_same == 0.0 { return Err(ForbildError::ZeroVariance); }
+
     var_same /= identical_count as f64;
-    var_diff /= (HASHLEN - identical_count) as f64;
+    var_diff /= hdist as f64;
 
-    var_diff / var_same * 1000.0 * (hamming_distance(hash1, hash2) as f64)
+    Ok(var_diff / var_same * 1000.0 * (hdist as f64))
 }
 
+/// Packs a binary hash with one bit per element (0 or 1) into the representation used by `Hash`.
+/// The number of bits has to be a multiple of 64.
+pub fn pack_binary(bits: &[u8]) -> Vec<u64> {
+    let mut packed = vec![0u64; bits.len()/64];
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit != 0 {
+            packed[i/64] |= 1 << (63 - i%64);
+        }
+    }
+    packed
+}
+
+/// Bits of a hex digit, upper or lower case
 pub fn hex_to_binary(hex: &char) -> Option<[u8; 4]> {
     match hex {
         '0' => Some([0, 0, 0, 0]),
@@ -70,12 +130,12 @@ pub fn hex_to_binary(hex: &char) -> Option<[u8; 4]> {
         '7' => Some([0, 1, 1, 1]),
         '8' => Some([1, 0, 0, 0]),
         '9' => Some([1, 0, 0, 1]),
-        'A' => Some([1, 0, 1, 0]),
-        'B' => Some([1, 0, 1, 1]),
-        'C' => Some([1, 1, 0, 0]),
-        'D' => Some([1, 1, 0, 1]),
-        'E' => Some([1, 1, 1, 0]),
-        'F' => Some([1, 1, 1, 1]),
+        'A' | 'a' => Some([1, 0, 1, 0]),
+        'B' | 'b' => Some([1, 0, 1, 1]),
+        'C' | 'c' => Some([1, 1, 0, 0]),
+        'D' | 'd' => Some([1, 1, 0, 1]),
+        'E' | 'e' => Some([1, 1, 1, 0]),
+        'F' | 'f' => Some([1, 1, 1, 1]),
         _ => None
     }
 }
@@ -85,8 +145,11 @@ pub fn hex_to_binary(hex: &char) -> Option<[u8; 4]> {
 mod hashmath_tests {
     use super::*;
 
+    const HASHLEN: usize = 256;
+    const WORDS: usize = HASHLEN/64;
+
     fn create_testing_binary_hash() -> [u8; 256] {
-        let hash = [
+        [
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
@@ -95,13 +158,12 @@ mod hashmath_tests {
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
             0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1,
-        ];
-        hash
+        ]
     }
 
     fn create_testing_hash_for_hamming_distance() -> Hash {
         let mut hash = Hash::new();
-        hash.binary256 = create_testing_binary_hash();
+        hash.binary256 = pack_binary(&create_testing_binary_hash());
         hash
     }
 
@@ -111,6 +173,7 @@ mod hashmath_tests {
         for i in 0..HASHLEN {
             hash.grayimage256[i] = i as u8;
         }
+        hash.has_grayscale = true;
         hash.set_subarea_medians();
         hash.set_binary_hash_from_grayimage();
 
@@ -124,16 +187,15 @@ mod hashmath_tests {
 
         assert_eq!(hamming_distance(&hash1, &hash2), 0);
 
-        hash2.binary256[0] = 1;
+        hash2.set_bit(0, true);
         assert_eq!(hamming_distance(&hash1, &hash2), 1);
 
-        hash2.binary256[1] = 0;
+        hash2.set_bit(1, false);
         assert_eq!(hamming_distance(&hash1, &hash2), 2);
 
         let mut hash3 = hash1.clone();
         for h in hash3.binary256.iter_mut() {
-            if *h == 1 { *h = 0; }
-            else if *h == 0 { *h = 1; }
+            *h = !*h;
         }
         assert_eq!(hamming_distance(&hash1, &hash3), 256);
     }
@@ -154,17 +216,170 @@ mod hashmath_tests {
         assert_eq!(hash1.subarea_medians[0][1], 192);
         assert_eq!(hash1.subarea_medians[1][1], 200);
 
-        println!("{}", hash1.to_string());
-        println!("{}", hash2.to_string());
+        println!("{}", hash1);
+        println!("{}", hash2);
 
         let hdist = hamming_distance(&hash1, &hash2);
         assert_eq!(hdist, 16);
 
-        let wdist = weighted_distance(&hash1, &hash2);
+        let wdist = weighted_distance(&hash1, &hash2).unwrap();
         let wdist_correct = 1000.0 * (hdist as f64) * (22552.0/16.0) / (328040.0/240.0); // This was calculated by using excel... >_>
         assert_eq!(wdist, wdist_correct);
     }
 
+    fn create_real_hashes() -> Vec<Hash> {
+        use image::imageops::FilterType;
+
+        let boats = std::path::Path::new("./data/original/2017_China_Chongqing_Boats.jpg");
+        let traffic = std::path::Path::new("./data/original/2015_Japan_Tokyo_Traffic.jpg");
+        let img = image::open(boats).unwrap();
+
+        vec![
+            Hash::from_path(boats).unwrap(),
+            Hash::from_path(traffic).unwrap(),
+            Hash::from_dynamic_image(img.resize(300, 200, FilterType::Triangle)).unwrap(),
+            Hash::from_dynamic_image(img.brighten(40)).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_weighted_distance_symmetric() {
+        let hashes = create_real_hashes();
+
+        for a in &hashes {
+            for b in &hashes {
+                assert_eq!(weighted_distance_symmetric(a, b).unwrap(), weighted_distance_symmetric(b, a).unwrap());
+                assert_eq!(
+                    weighted_distance_symmetric(a, b).unwrap(),
+                    (weighted_distance(a, b).unwrap() + weighted_distance(b, a).unwrap()) / 2.0
+                );
+            }
+        }
+
+        // The published weighted distance depends on the order of the arguments
+        assert_ne!(weighted_distance(&hashes[0], &hashes[1]).unwrap(), weighted_distance(&hashes[1], &hashes[0]).unwrap());
+        assert_eq!(weighted_distance_symmetric(&hashes[0], &hashes[0]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_weighted_distance_with_mode() {
+        let hashes = create_real_hashes();
+
+        assert_eq!(WeightedMode::default(), WeightedMode::Directional);
+        assert_eq!(
+            weighted_distance_with_mode(&hashes[1], &hashes[0], WeightedMode::Directional).unwrap(),
+            weighted_distance(&hashes[1], &hashes[0]).unwrap()
+        );
+        assert_eq!(
+            weighted_distance_with_mode(&hashes[1], &hashes[0], WeightedMode::Symmetric).unwrap(),
+            weighted_distance_with_mode(&hashes[0], &hashes[1], WeightedMode::Symmetric).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_distances_with_size() {
+        for size in [8, 32].iter() {
+            let mut hash1 = Hash::with_size(*size).unwrap();
+            for i in 0..hash1.len() {
+                hash1.grayimage256[i] = (i % 251) as u8;
+            }
+            hash1.has_grayscale = true;
+            hash1.set_subarea_medians();
+            hash1.set_binary_hash_from_grayimage();
+
+            let mut hash2 = hash1.clone();
+            assert_eq!(hamming_distance(&hash1, &hash2), 0);
+            assert_eq!(weighted_distance(&hash1, &hash2).unwrap(), 0.0);
+
+            for i in 0..4 {
+                let bit = hash2.get_bit(i) == 0;
+                hash2.set_bit(i, bit);
+            }
+            assert_eq!(hamming_distance(&hash1, &hash2), 4);
+            assert!(weighted_distance(&hash1, &hash2).unwrap() > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_weighted_distance_missing_grayscale() {
+        let hash = create_testing_hash_for_weighted_distance();
+        let mut hash_hex = Hash::from_hex_str(&hash.to_string_hex()).unwrap();
+        assert!(!hash_hex.has_grayscale);
+
+        // Identical binary hashes do not need grayscale data
+        assert_eq!(weighted_distance(&hash_hex, &hash).unwrap(), 0.0);
+        assert_eq!(weighted_distance_symmetric(&hash_hex, &hash).unwrap(), 0.0);
+
+        hash_hex.set_bit(0, hash.get_bit(0) == 0);
+        assert!(matches!(weighted_distance(&hash_hex, &hash), Err(ForbildError::MissingGrayscale)));
+        assert!(matches!(weighted_distance_symmetric(&hash, &hash_hex), Err(ForbildError::MissingGrayscale)));
+        assert!(matches!(weighted_distance_symmetric(&hash_hex, &hash), Err(ForbildError::MissingGrayscale)));
+
+        // Only the grayscale data of the first hash is used by the directional weighted distance
+        assert!(weighted_distance(&hash, &hash_hex).unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_weighted_distance_flat_image() {
+        let flat = Hash::from_grayimage(image::GrayImage::from_pixel(16, 16, image::Luma([100]))).unwrap();
+        let hash = create_testing_hash_for_weighted_distance();
+        assert_eq!(flat.to_string_hex(), "F".repeat(64));
+
+        assert!(matches!(weighted_distance(&flat, &hash), Err(ForbildError::ZeroVariance)));
+        assert!(matches!(weighted_distance_symmetric(&hash, &flat), Err(ForbildError::ZeroVariance)));
+        assert_eq!(weighted_distance(&flat, &flat).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_weighted_distance_zero_variance_of_identical_bits() {
+        // Half of the pixels are exactly the median (bit 1), the other half are darker (bit 0)
+        let mut img = image::GrayImage::new(16, 16);
+        for (x, _, pix) in img.enumerate_pixels_mut() {
+            pix[0] = if x % 2 == 0 { 110 } else { 100 };
+        }
+        let hash1 = Hash::from_grayimage(img).unwrap();
+        assert_eq!(hash1.subarea_medians, [[110; 2]; 2]);
+
+        // Only the dark pixels differ, so the identical bits have a variance of 0
+        let mut hash2 = hash1.clone();
+        for i in 0..hash2.len() {
+            hash2.set_bit(i, true);
+        }
+        assert!(matches!(weighted_distance(&hash1, &hash2), Err(ForbildError::ZeroVariance)));
+
+        // All bits differ
+        let mut hash3 = hash1.clone();
+        for w in hash3.binary256.iter_mut() {
+            *w = !*w;
+        }
+        assert!(matches!(weighted_distance(&hash1, &hash3), Err(ForbildError::ZeroVariance)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_distances_different_sizes() {
+        hamming_distance(&Hash::with_size(8).unwrap(), &Hash::new());
+    }
+
+    #[test]
+    fn test_checked_distances_different_sizes() {
+        let (small, large) = (Hash::with_size(8).unwrap(), Hash::new());
+        let is_invalid_length = |result: Result<_>| matches!(result, Err(ForbildError::InvalidLength { expected: 64, found: 256 }));
+
+        assert!(is_invalid_length(checked_hamming_distance(&small, &large)));
+        assert!(is_invalid_length(weighted_distance(&small, &large).map(|_| 0)));
+        assert_eq!(checked_hamming_distance(&large, &large).unwrap(), 0);
+        assert!(matches!(crate::matcher::Matcher::default().try_compare(&large, &small), Err(ForbildError::InvalidLength { .. })));
+        assert!(crate::matcher::Matcher::default().try_compare_with_weighted(&small, &small).unwrap().0.is_match());
+    }
+
+    #[test]
+    fn test_pack_binary() {
+        let packed = pack_binary(&create_testing_binary_hash());
+
+        assert_eq!(packed, [0x659B659B659B659B; WORDS]);
+    }
+
     #[test]
     fn test_hex_to_binary() {
         assert_eq!(hex_to_binary(&'0'), Some([0, 0, 0, 0]));
@@ -183,6 +398,7 @@ mod hashmath_tests {
         assert_eq!(hex_to_binary(&'D'), Some([1, 1, 0, 1]));
         assert_eq!(hex_to_binary(&'E'), Some([1, 1, 1, 0]));
         assert_eq!(hex_to_binary(&'F'), Some([1, 1, 1, 1]));
+        assert_eq!(hex_to_binary(&'f'), Some([1, 1, 1, 1]));
         assert_eq!(hex_to_binary(&'G'), None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,117 @@
+//! Search indexes to find similar hashes without comparing a hash with every other hash.
+//!
+//! The indexes search by Hamming distance. Their candidates can be re-ranked with the weighted
+//! distance by `rerank`, which also applies the two stage decision of a `Matcher`.
+
+pub mod bktree;
+pub mod mih;
+
+pub use bktree::BkTree;
+pub use mih::MultiIndex;
+
+use std::cmp::Ordering;
+
+use crate::hash::Hash;
+use crate::matcher::{MatchVerdict, Matcher};
+
+/// A hash found in an index together with its Hamming distance to the query
+#[derive(Debug, PartialEq)]
+pub struct Neighbour<'a, T> {
+    pub value: &'a T,
+    pub hash: &'a Hash,
+    pub hamming: usize,
+}
+
+/// A candidate that matches the query, see `rerank`
+#[derive(Debug, PartialEq)]
+pub struct RankedMatch<'a, T> {
+    pub value: &'a T,
+    pub hash: &'a Hash,
+    pub verdict: MatchVerdict,
+    /// The weighted distance to the query, if it can be calculated
+    pub weighted: Option<f64>,
+}
+
+/// Searches the candidates of every orientation of the query that the matcher compares with
+/// `find_within`. Each candidate is returned once, with the Hamming distance it was found with first.
+pub(crate) fn find_oriented<'a, T, F>(query: &Hash, matcher: &Matcher, find_within: F) -> Vec<Neighbour<'a, T>>
+where
+    F: Fn(&Hash) -> Vec<Neighbour<'a, T>>,
+{
+    let mut candidates = find_within(query);
+    for orientation in &matcher.orientations()[1..] {
+        for candidate in find_within(&query.oriented(*orientation)) {
+            if !candidates.iter().any(|found| std::ptr::eq(found.hash, candidate.hash)) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates
+}
+
+/// Compares the candidates with the query and keeps the matching ones, ordered by weighted distance.
+/// Candidates without a weighted distance come last, ties are ordered by Hamming distance.
+pub fn rerank<'a, T>(query: &Hash, candidates: Vec<Neighbour<'a, T>>, matcher: &Matcher) -> Vec<RankedMatch<'a, T>> {
+    let mut matches: Vec<RankedMatch<'a, T>> = candidates.into_iter()
+        .map(|candidate| {
+            let (verdict, weighted) = matcher.compare_with_weighted(query, candidate.hash);
+            RankedMatch { value: candidate.value, hash: candidate.hash, verdict, weighted }
+        })
+        .filter(|ranked| ranked.verdict.is_match())
+        .collect();
+
+    // The sort is stable, so equally ranked candidates keep their order
+    matches.sort_by(|a, b| {
+        let weighted = match (a.weighted, b.weighted) {
+            (Some(wa), Some(wb)) => wa.partial_cmp(&wb).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        weighted.then(a.verdict.hamming_distance().cmp(&b.verdict.hamming_distance()))
+    });
+    matches
+}
+
+#[cfg(test)]
+mod index_tests {
+    use crate::hashmath::hamming_distance;
+    use super::*;
+
+    #[test]
+    fn test_rerank() {
+        let mut query = Hash::new();
+        for i in 0..query.len() {
+            query.grayimage256[i] = i as u8;
+        }
+        query.has_grayscale = true;
+        query.set_subarea_medians();
+        query.set_binary_hash_from_grayimage();
+
+        let mut hash1 = query.clone();
+        let mut hash2 = query.clone();
+        for i in 0..12 {
+            hash1.set_bit(i, hash1.get_bit(i) == 0);
+            hash2.set_bit(100 + 2*i, hash2.get_bit(100 + 2*i) == 0);
+        }
+        let hash_hex = Hash::from_hex_str(&query.to_string_hex()).unwrap();
+
+        let values = ["hash1", "hash2", "hex"];
+        let hashs = [&hash1, &hash2, &hash_hex];
+        let candidates = || values.iter().zip(hashs.iter())
+            .map(|(value, hash)| Neighbour { value, hash, hamming: hamming_distance(&query, hash) })
+            .collect::<Vec<_>>();
+
+        // Only the weighted distance decides with a Hamming threshold of 0
+        let ranked = rerank(&query, candidates(), &Matcher::new(0, 1.0e9));
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(*ranked[0].value, "hex");
+        assert_eq!(ranked[0].verdict, MatchVerdict::Identical);
+        assert!(ranked[1].weighted.unwrap() < ranked[2].weighted.unwrap());
+
+        let threshold = ranked[1].weighted.unwrap();
+        let ranked = rerank(&query, candidates(), &Matcher::new(0, threshold));
+        assert_eq!(ranked.len(), 2);
+        assert!(ranked.iter().all(|r| r.verdict.is_match()));
+    }
+}
@@ -0,0 +1,339 @@
+//! BK-tree (Burkhard-Keller tree) over the Hamming distance of binary hashes.
+//!
+//! Every child of a node is stored under its distance to the node. Because the Hamming distance is a
+//! metric, a query with radius r only has to visit the children with a distance in [d - r, d + r],
+//! where d is the distance between the query and the node.
+
+use std::collections::BinaryHeap;
+
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::hashmath::hamming_distance;
+use crate::index::{find_oriented, rerank, Neighbour, RankedMatch};
+use crate::matcher::Matcher;
+
+/// BK-tree storing a value (e.g. the image path) for every hash.
+/// All hashes of a tree have the same grid size.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BkTree<T> {
+    /// Nodes in insertion order, the first one is the root
+    nodes: Vec<Node<T>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Node<T> {
+    hash: Hash,
+    value: T,
+    /// Distance to this node and index of the child node
+    children: Vec<(usize, usize)>,
+}
+
+impl<T> BkTree<T> {
+    pub fn new() -> BkTree<T> {
+        BkTree { nodes: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Grid size of the hashes in the tree, `None` if the tree is empty
+    pub fn size(&self) -> Option<u32> {
+        self.nodes.first().map(|node| node.hash.size)
+    }
+
+    /// Inserts the hash with its value. Identical hashes are all kept.
+    pub fn insert(&mut self, hash: Hash, value: T) -> Result<()> {
+        if let Some(root) = self.nodes.first() {
+            if root.hash.size != hash.size {
+                return Err(ForbildError::InvalidLength { expected: root.hash.len(), found: hash.len() });
+            }
+        }
+
+        let new_index = self.nodes.len();
+        if new_index > 0 {
+            let mut current = 0;
+            loop {
+                let distance = hamming_distance(&self.nodes[current].hash, &hash);
+                match self.child(current, distance) {
+                    Some(child) => current = child,
+                    None => {
+                        self.nodes[current].children.push((distance, new_index));
+                        break;
+                    }
+                }
+            }
+        }
+        self.nodes.push(Node { hash, value, children: Vec::new() });
+        Ok(())
+    }
+
+    fn child(&self, node: usize, distance: usize) -> Option<usize> {
+        self.nodes[node].children.iter()
+            .find(|(d, _)| *d == distance)
+            .map(|(_, child)| *child)
+    }
+
+    /// Returns all hashes within the Hamming distance `radius` of the query, ordered by distance.
+    /// Hashes of another grid size than the tree are never within any distance.
+    pub fn find_within(&self, query: &Hash, radius: usize) -> Vec<Neighbour<'_, T>> {
+        if self.size() != Some(query.size) {
+            return Vec::new();
+        }
+
+        let mut found = Vec::new();
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let distance = hamming_distance(&node.hash, query);
+            if distance <= radius {
+                found.push((distance, index));
+            }
+            stack.extend(node.children.iter()
+                .filter(|(d, _)| distance.saturating_sub(radius) <= *d && *d <= distance + radius)
+                .map(|(_, child)| *child));
+        }
+
+        found.sort_unstable();
+        found.into_iter().map(|(distance, index)| self.neighbour(index, distance)).collect()
+    }
+
+    /// Returns the `k` hashes nearest to the query, ordered by distance. Equally distant hashes are
+    /// ordered by insertion, so the result does not depend on the traversal.
+    pub fn find_nearest(&self, query: &Hash, k: usize) -> Vec<Neighbour<'_, T>> {
+        if k == 0 || self.size() != Some(query.size) {
+            return Vec::new();
+        }
+
+        // Max-heap of the k best (distance, index) pairs found so far, its top is the current radius
+        let mut best: BinaryHeap<(usize, usize)> = BinaryHeap::with_capacity(k + 1);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let distance = hamming_distance(&node.hash, query);
+            if best.len() < k || (distance, index) < *best.peek().unwrap() {
+                best.push((distance, index));
+                if best.len() > k {
+                    best.pop();
+                }
+            }
+
+            let radius = if best.len() < k { usize::MAX } else { best.peek().unwrap().0 };
+            stack.extend(node.children.iter()
+                .filter(|(d, _)| distance.saturating_sub(radius) <= *d && *d <= distance.saturating_add(radius))
+                .map(|(_, child)| *child));
+        }
+
+        best.into_sorted_vec().into_iter()
+            .map(|(distance, index)| self.neighbour(index, distance))
+            .collect()
+    }
+
+    /// Searches the candidates within the Hamming distance `radius` and re-ranks them by weighted
+    /// distance, see `index::rerank`. Only candidates the matcher accepts are returned.
+    /// A rotation invariant matcher searches with all orientations of the query.
+    /// The radius has to be larger than the Hamming threshold of the matcher to find matches by weighted distance.
+    pub fn find_matches(&self, query: &Hash, radius: usize, matcher: &Matcher) -> Vec<RankedMatch<'_, T>> {
+        rerank(query, find_oriented(query, matcher, |query| self.find_within(query, radius)), matcher)
+    }
+
+    /// Iterates over all hashes and values in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&Hash, &T)> {
+        self.nodes.iter().map(|node| (&node.hash, &node.value))
+    }
+
+    fn neighbour(&self, index: usize, hamming: usize) -> Neighbour<'_, T> {
+        let node = &self.nodes[index];
+        Neighbour { value: &node.value, hash: &node.hash, hamming }
+    }
+
+    /// Checks the structure of a tree that was not built by `insert`, e.g. a deserialized one
+    #[cfg(feature = "serde")]
+    fn validate(&self) -> Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.hash.size != self.nodes[0].hash.size {
+                return Err(ForbildError::InvalidIndex("Hashes have different grid sizes".to_string()));
+            }
+            for (distance, child) in &node.children {
+                // Children are always inserted after their parent
+                if *child <= index || *child >= self.nodes.len() {
+                    return Err(ForbildError::InvalidIndex(format!("Invalid child {} of node {}", child, index)));
+                }
+                if hamming_distance(&node.hash, &self.nodes[*child].hash) != *distance {
+                    return Err(ForbildError::InvalidIndex(format!("Wrong distance of child {}", child)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> BkTree<T> {
+    pub fn save_json(&self, path: &std::path::Path) -> Result<()> {
+        let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load_json(path: &std::path::Path) -> Result<BkTree<T>> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let tree: BkTree<T> = serde_json::from_reader(reader)?;
+        tree.validate()?;
+        Ok(tree)
+    }
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self {
+        BkTree::new()
+    }
+}
+
+impl<T> std::iter::FromIterator<(Hash, T)> for BkTree<T> {
+    /// Panics if the hashes have different grid sizes
+    fn from_iter<I: IntoIterator<Item = (Hash, T)>>(iter: I) -> Self {
+        let mut tree = BkTree::new();
+        for (hash, value) in iter {
+            tree.insert(hash, value).expect("All hashes of a BK-tree need the same grid size");
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod bktree_tests {
+    use std::path::Path;
+    use image::imageops::FilterType;
+    use super::*;
+
+    /// Deterministic pseudo random hashes (xorshift)
+    fn create_random_hashes(count: usize) -> Vec<Hash> {
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut hashs = Vec::new();
+        let base: Vec<u64> = (0..4).map(|_| next()).collect();
+        for i in 0..count {
+            let mut hash = Hash::new();
+            hash.binary256 = base.clone();
+            // Flip a few random bits, so that there are hashes at all kinds of small distances
+            for _ in 0..(i % 40) {
+                let bit = (next() % 256) as usize;
+                hash.set_bit(bit, hash.get_bit(bit) == 0);
+            }
+            hashs.push(hash);
+        }
+        hashs
+    }
+
+    fn create_tree(hashs: &[Hash]) -> BkTree<usize> {
+        hashs.iter().cloned().zip(0..).collect()
+    }
+
+    #[test]
+    fn test_find_within() {
+        let hashs = create_random_hashes(300);
+        let tree = create_tree(&hashs);
+        assert_eq!(tree.len(), 300);
+
+        for (query, radius) in [(0, 0), (5, 8), (17, 20), (299, 256)] {
+            let found: Vec<usize> = tree.find_within(&hashs[query], radius).iter().map(|n| *n.value).collect();
+            let mut expected: Vec<(usize, usize)> = hashs.iter().enumerate()
+                .map(|(i, hash)| (hamming_distance(hash, &hashs[query]), i))
+                .filter(|(distance, _)| *distance <= radius)
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(found, expected.iter().map(|(_, i)| *i).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_find_nearest() {
+        let hashs = create_random_hashes(300);
+        let tree = create_tree(&hashs);
+
+        for (query, k) in [(0, 1), (5, 10), (17, 300), (299, 500)] {
+            let found: Vec<(usize, usize)> = tree.find_nearest(&hashs[query], k).iter()
+                .map(|n| (n.hamming, *n.value))
+                .collect();
+            let mut expected: Vec<(usize, usize)> = hashs.iter().enumerate()
+                .map(|(i, hash)| (hamming_distance(hash, &hashs[query]), i))
+                .collect();
+            expected.sort_unstable();
+            expected.truncate(k);
+            assert_eq!(found, expected);
+        }
+        assert!(tree.find_nearest(&hashs[0], 0).is_empty());
+    }
+
+    #[test]
+    fn test_duplicates_and_sizes() {
+        let mut tree = BkTree::new();
+        assert!(tree.find_within(&Hash::new(), 256).is_empty());
+
+        tree.insert(Hash::new(), "a").unwrap();
+        tree.insert(Hash::new(), "b").unwrap();
+        assert_eq!(tree.find_within(&Hash::new(), 0).len(), 2);
+
+        let hash8 = Hash::with_size(8).unwrap();
+        assert!(matches!(tree.insert(hash8.clone(), "c"), Err(ForbildError::InvalidLength { .. })));
+        assert!(tree.find_within(&hash8, 64).is_empty());
+        assert!(tree.find_nearest(&hash8, 1).is_empty());
+        assert_eq!(tree.size(), Some(16));
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let path = Path::new("./data/original/2017_China_Chongqing_Boats.jpg");
+        let img = image::open(path).unwrap();
+        let boats = Hash::from_dynamic_image(img.clone()).unwrap();
+        let small = Hash::from_dynamic_image(img.resize(300, 200, FilterType::Triangle)).unwrap();
+        let traffic = Hash::from_path(Path::new("./data/original/2015_Japan_Tokyo_Traffic.jpg")).unwrap();
+
+        let tree: BkTree<&str> = vec![(boats.clone(), "boats"), (small, "small"), (traffic, "traffic")]
+            .into_iter()
+            .collect();
+        let matches = tree.find_matches(&boats, 64, &Matcher::default());
+        let values: Vec<&str> = matches.iter().map(|m| *m.value).collect();
+        assert_eq!(values, vec!["boats", "small"]);
+
+        let rotated = Hash::from_dynamic_image(img.rotate90()).unwrap();
+        let tree: BkTree<&str> = vec![(rotated, "rotated")].into_iter().collect();
+        assert!(tree.find_matches(&boats, 16, &Matcher::default()).is_empty());
+        let matches = tree.find_matches(&boats, 16, &Matcher::default().with_rotation_invariance(true));
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].verdict.hamming_distance() <= 8);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_save_and_load() {
+        let hashs = create_random_hashes(50);
+        let tree = create_tree(&hashs);
+        let path = std::env::temp_dir().join(format!("forbild_bktree_{}.json", std::process::id()));
+
+        tree.save_json(&path).unwrap();
+        let loaded: BkTree<usize> = BkTree::load_json(&path).unwrap();
+        assert_eq!(loaded, tree);
+        assert_eq!(loaded.find_nearest(&hashs[3], 5), tree.find_nearest(&hashs[3], 5));
+
+        // A child pointing back to its parent would loop forever
+        let mut value = serde_json::to_value(&tree).unwrap();
+        value["nodes"][1]["children"] = serde_json::json!([[0, 0]]);
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(BkTree::<usize>::load_json(&path), Err(ForbildError::InvalidIndex(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+//! Multi-index hashing (Norouzi et al., "Fast Search in Hamming Space with Multi-Index Hashing").
+//!
+//! The binary hash is split into m substrings and every substring is stored in its own hash table.
+//! If two hashes are within the Hamming distance r, at least one of their substrings is within
+//! r / m (pigeonhole principle). A query looks up all substring keys within that distance in every
+//! table and verifies the candidates with the full Hamming distance, so the search is exact.
+//!
+//! More substrings mean shorter keys, fewer keys to look up, but more candidates to verify.
+//! `MultiIndex::evaluate` measures both to choose m for a collection.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::hashmath::hamming_distance;
+use crate::index::{find_oriented, rerank, Neighbour, RankedMatch};
+use crate::matcher::Matcher;
+
+pub struct MultiIndex<T> {
+    size: u32,
+    /// First bit and length of every substring
+    substrings: Vec<(usize, usize)>,
+    /// One table per substring, mapping the substring to the indexes of the entries
+    tables: Vec<HashMap<u64, Vec<usize>>>,
+    entries: Vec<(Hash, T)>,
+}
+
+impl<T> MultiIndex<T> {
+    /// Creates an empty index for hashes of the grid size, split into `substring_count` substrings.
+    /// The substrings have to be between 1 and 64 bits long.
+    pub fn new(size: u32, substring_count: usize) -> Result<MultiIndex<T>> {
+        crate::check_size(size)?;
+        let bits = (size*size) as usize;
+        if substring_count == 0 || substring_count > bits || bits.div_ceil(substring_count) > 64 {
+            return Err(ForbildError::InvalidIndex(
+                format!("{} bit hashes cannot be split into {} substrings of at most 64 bits", bits, substring_count)
+            ));
+        }
+
+        // The first bits % m substrings are one bit longer
+        let mut substrings = Vec::with_capacity(substring_count);
+        let mut start = 0;
+        for i in 0..substring_count {
+            let len = bits / substring_count + usize::from(i < bits % substring_count);
+            substrings.push((start, len));
+            start += len;
+        }
+
+        Ok(MultiIndex {
+            size,
+            substrings,
+            tables: vec![HashMap::new(); substring_count],
+            entries: Vec::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn substring_count(&self) -> usize {
+        self.substrings.len()
+    }
+
+    pub fn insert(&mut self, hash: Hash, value: T) -> Result<()> {
+        if hash.size != self.size {
+            return Err(ForbildError::InvalidLength { expected: (self.size*self.size) as usize, found: hash.len() });
+        }
+
+        let index = self.entries.len();
+        for (table, (start, len)) in self.tables.iter_mut().zip(&self.substrings) {
+            table.entry(substring(&hash, *start, *len)).or_default().push(index);
+        }
+        self.entries.push((hash, value));
+        Ok(())
+    }
+
+    /// Returns all hashes within the Hamming distance `radius` of the query, ordered by distance.
+    /// Hashes of another grid size than the index are never within any distance.
+    /// Every table is looked up for all keys within radius / m, so large radii need more substrings.
+    pub fn find_within(&self, query: &Hash, radius: usize) -> Vec<Neighbour<'_, T>> {
+        self.search(query, radius, radius / self.substring_count(), &mut SearchStats::default())
+    }
+
+    /// Searches the candidates within the Hamming distance `radius` and re-ranks them by weighted
+    /// distance, see `index::rerank`. Only candidates the matcher accepts are returned.
+    /// A rotation invariant matcher searches with all orientations of the query.
+    pub fn find_matches(&self, query: &Hash, radius: usize, matcher: &Matcher) -> Vec<RankedMatch<'_, T>> {
+        rerank(query, find_oriented(query, matcher, |query| self.find_within(query, radius)), matcher)
+    }
+
+    /// Looks up all substring keys within `probe_radius` of the query's substrings. With a probe radius
+    /// below radius / m, the search is faster, but may miss hashes.
+    fn search(&self, query: &Hash, radius: usize, probe_radius: usize, stats: &mut SearchStats) -> Vec<Neighbour<'_, T>> {
+        if query.size != self.size {
+            return Vec::new();
+        }
+
+        let mut candidates: HashSet<usize> = HashSet::new();
+        for (table, (start, len)) in self.tables.iter().zip(&self.substrings) {
+            let key = substring(query, *start, *len);
+            for_each_key_within(key, *len, probe_radius, &mut |key| {
+                stats.lookups += 1;
+                if let Some(indexes) = table.get(&key) {
+                    candidates.extend(indexes);
+                }
+            });
+        }
+        stats.candidates += candidates.len();
+
+        let mut found: Vec<(usize, usize)> = candidates.into_iter()
+            .map(|index| (hamming_distance(&self.entries[index].0, query), index))
+            .filter(|(distance, _)| *distance <= radius)
+            .collect();
+        found.sort_unstable();
+        stats.results += found.len();
+
+        found.into_iter()
+            .map(|(hamming, index)| Neighbour { value: &self.entries[index].1, hash: &self.entries[index].0, hamming })
+            .collect()
+    }
+
+    /// Runs all queries and compares the results with a linear scan. `probe_radius` defaults to
+    /// radius / m, the smallest one that finds all hashes.
+    pub fn evaluate(&self, queries: &[Hash], radius: usize, probe_radius: Option<usize>) -> SearchStats {
+        let probe_radius = probe_radius.unwrap_or(radius / self.substring_count());
+        let mut stats = SearchStats { queries: queries.len(), ..SearchStats::default() };
+
+        let now = Instant::now();
+        for query in queries {
+            self.search(query, radius, probe_radius, &mut stats);
+        }
+        stats.elapsed = now.elapsed();
+
+        stats.expected_results = queries.iter()
+            .filter(|query| query.size == self.size)
+            .map(|query| self.entries.iter().filter(|(hash, _)| hamming_distance(hash, query) <= radius).count())
+            .sum();
+        stats
+    }
+
+    /// Number of distinct keys and size of the largest bucket of every table
+    pub fn table_stats(&self) -> Vec<TableStats> {
+        self.tables.iter().zip(&self.substrings)
+            .map(|(table, (_, len))| TableStats {
+                substring_bits: *len,
+                keys: table.len(),
+                largest_bucket: table.values().map(|indexes| indexes.len()).max().unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+/// Statistics of the queries run by `MultiIndex::evaluate`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchStats {
+    pub queries: usize,
+    /// Number of hash table lookups
+    pub lookups: usize,
+    /// Number of distinct candidates that had to be verified
+    pub candidates: usize,
+    /// Number of hashes found within the radius
+    pub results: usize,
+    /// Number of hashes within the radius according to a linear scan
+    pub expected_results: usize,
+    /// Time of the searches (without the linear scan)
+    pub elapsed: Duration,
+}
+
+impl SearchStats {
+    /// Fraction of the hashes within the radius that were found, 1 for an exact search
+    pub fn recall(&self) -> f64 {
+        if self.expected_results == 0 {
+            return 1.0;
+        }
+        self.results as f64 / self.expected_results as f64
+    }
+
+    /// Fraction of the verified candidates that were within the radius
+    pub fn precision(&self) -> f64 {
+        if self.candidates == 0 {
+            return 1.0;
+        }
+        self.results as f64 / self.candidates as f64
+    }
+
+    pub fn queries_per_second(&self) -> f64 {
+        self.queries as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableStats {
+    pub substring_bits: usize,
+    pub keys: usize,
+    pub largest_bucket: usize,
+}
+
+/// Extracts `len` bits (1 to 64) starting at bit `start` of the binary hash
+fn substring(hash: &Hash, start: usize, len: usize) -> u64 {
+    let word = start / 64;
+    let high = (hash.binary256[word] as u128) << 64;
+    let low = hash.binary256.get(word + 1).copied().unwrap_or(0) as u128;
+    (((high | low) << (start % 64)) >> (128 - len)) as u64
+}
+
+/// Calls `f` for every `len` bit key within the Hamming distance `radius` of the key
+fn for_each_key_within<F: FnMut(u64)>(key: u64, len: usize, radius: usize, f: &mut F) {
+    f(key);
+    flip_bits(key, 0, len, radius, f);
+}
+
+/// Flips up to `radius` more bits at positions >= `first`, so that every key is visited once
+fn flip_bits<F: FnMut(u64)>(key: u64, first: usize, len: usize, radius: usize, f: &mut F) {
+    if radius == 0 {
+        return;
+    }
+    for bit in first..len {
+        let flipped = key ^ (1 << bit);
+        f(flipped);
+        flip_bits(flipped, bit + 1, len, radius - 1, f);
+    }
+}
+
+#[cfg(test)]
+mod mih_tests {
+    use super::*;
+
+    /// Deterministic pseudo random hashes (xorshift) in clusters of similar hashes
+    fn create_random_hashes(count: usize) -> Vec<Hash> {
+        let mut state: u64 = 0x9E3779B97F4A7C15;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut hashs = Vec::new();
+        let mut base = Vec::new();
+        for i in 0..count {
+            if i % 50 == 0 {
+                base = (0..4).map(|_| next()).collect();
+            }
+            let mut hash = Hash::new();
+            hash.binary256 = base.clone();
+            for _ in 0..(i % 30) {
+                let bit = (next() % 256) as usize;
+                hash.set_bit(bit, hash.get_bit(bit) == 0);
+            }
+            hashs.push(hash);
+        }
+        hashs
+    }
+
+    fn create_index(hashs: &[Hash], substring_count: usize) -> MultiIndex<usize> {
+        let mut index = MultiIndex::new(16, substring_count).unwrap();
+        for (i, hash) in hashs.iter().enumerate() {
+            index.insert(hash.clone(), i).unwrap();
+        }
+        index
+    }
+
+    #[test]
+    fn test_substring() {
+        let mut hash = Hash::new();
+        hash.binary256 = vec![0x0123456789ABCDEF, 0xFEDCBA9876543210, 0, u64::MAX];
+
+        assert_eq!(substring(&hash, 0, 64), 0x0123456789ABCDEF);
+        assert_eq!(substring(&hash, 4, 8), 0x12);
+        assert_eq!(substring(&hash, 60, 8), 0xFF);
+        assert_eq!(substring(&hash, 120, 16), 0x1000);
+        assert_eq!(substring(&hash, 255, 1), 1);
+    }
+
+    #[test]
+    fn test_keys_within() {
+        let mut keys = Vec::new();
+        for_each_key_within(0b1010, 4, 2, &mut |key| keys.push(key));
+        keys.sort_unstable();
+        keys.dedup();
+
+        // 1 + 4 + 6 keys within a distance of 2
+        assert_eq!(keys.len(), 11);
+        assert!(keys.iter().all(|key| (key ^ 0b1010).count_ones() <= 2));
+    }
+
+    #[test]
+    fn test_new() {
+        let index: MultiIndex<()> = MultiIndex::new(16, 4).unwrap();
+        assert!(index.table_stats().iter().all(|stats| stats.substring_bits == 64));
+
+        let index: MultiIndex<()> = MultiIndex::new(16, 10).unwrap();
+        let bits: Vec<usize> = index.table_stats().iter().map(|stats| stats.substring_bits).collect();
+        assert_eq!(bits, vec![26, 26, 26, 26, 26, 26, 25, 25, 25, 25]);
+
+        assert!(matches!(MultiIndex::<()>::new(16, 3), Err(ForbildError::InvalidIndex(_))));
+        assert!(matches!(MultiIndex::<()>::new(16, 0), Err(ForbildError::InvalidIndex(_))));
+        assert!(matches!(MultiIndex::<()>::new(12, 4), Err(ForbildError::InvalidGridSize(12))));
+    }
+
+    #[test]
+    fn test_find_within() {
+        let hashs = create_random_hashes(400);
+
+        // The number of keys to look up grows fast with radius / m, so larger radii need more substrings
+        for (substring_count, radii) in [(4, [0, 7]), (8, [10, 23]), (16, [24, 40])] {
+            let index = create_index(&hashs, substring_count);
+            for (query, radius) in [(0, radii[0]), (7, radii[1]), (123, radii[0]), (399, radii[1])] {
+                let found: Vec<usize> = index.find_within(&hashs[query], radius).iter().map(|n| *n.value).collect();
+                let mut expected: Vec<(usize, usize)> = hashs.iter().enumerate()
+                    .map(|(i, hash)| (hamming_distance(hash, &hashs[query]), i))
+                    .filter(|(distance, _)| *distance <= radius)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(found, expected.iter().map(|(_, i)| *i).collect::<Vec<_>>());
+            }
+        }
+
+        let mut index = create_index(&hashs, 8);
+        let hash8 = Hash::with_size(8).unwrap();
+        assert!(matches!(index.insert(hash8.clone(), 0), Err(ForbildError::InvalidLength { .. })));
+        assert!(index.find_within(&hash8, 64).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let hashs = create_random_hashes(200);
+        let index = create_index(&hashs, 8);
+
+        let stats = index.evaluate(&hashs[..20], 16, None);
+        assert_eq!(stats.queries, 20);
+        assert_eq!(stats.recall(), 1.0);
+        assert!(stats.results <= stats.candidates);
+        assert!(stats.precision() > 0.0 && stats.precision() <= 1.0);
+        assert!(stats.queries_per_second() > 0.0);
+
+        // Without probing neighbouring keys, only hashes with an identical substring are found
+        let approximate = index.evaluate(&hashs[..20], 16, Some(0));
+        assert!(approximate.lookups < stats.lookups);
+        assert!(approximate.recall() <= 1.0);
+        assert_eq!(approximate.expected_results, stats.expected_results);
+    }
+}
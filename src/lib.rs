@@ -1,40 +1,38 @@
-use std::path::PathBuf;
-
+pub mod batch;
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "serde")]
+pub mod checkpoint;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod config;
+pub mod db;
 pub mod editing;
+pub mod error;
 pub mod hashmath;
 pub mod hash;
-
-pub const SIZE: u32 = 16;
-
-pub fn parse_args_to_paths() -> Vec<PathBuf> {
-    let args = std::env::args().skip(1).collect();
-
-    match parse_args(args) {
-        Some(p) => p,
-        None => {
-            eprintln!("ERROR: No image paths were given. Exiting program.");
-            std::process::exit(1);
-        }
-    }
-}
-
-fn parse_args(args: Vec<String>) -> Option<Vec<PathBuf>> {
-    if args.len() == 0 {
-       return None;
+pub mod index;
+pub mod limits;
+pub mod matcher;
+pub mod orientation;
+#[cfg(feature = "serde")]
+pub mod record;
+pub mod report;
+pub mod scan;
+pub mod walk;
+
+pub use error::{ForbildError, Result};
+
+/// Default width and height of the hash grid (16x16 = 256 bit hashes)
+pub const DEFAULT_SIZE: u32 = 16;
+
+/// Checks if a grid size can be used for hashing. It has to be a positive multiple of 8,
+/// so that the hash consists of whole 64 bit words and the four subareas have the same size.
+pub fn check_size(size: u32) -> Result<()> {
+    if size == 0 || !size.is_multiple_of(8) {
+        return Err(ForbildError::InvalidGridSize(size));
     }
-
-    // Converting arguments to Paths and checking their validity
-    let mut paths= Vec::new();
-    for arg in &args {
-        let path = PathBuf::from(arg);
-        if path.exists() {
-            paths.push(path);
-        } else {
-            eprintln!("WARNING: No file found with path: {:?}", path);
-            eprintln!("         File will be skipped.");
-        }
-    }
-    Some(paths)
+    Ok(())
 }
 
 #[cfg(test)]
@@ -42,23 +40,12 @@ mod lib_testing {
     use super::*;
 
     #[test]
-    fn test_parse_args_success() {
-        let args = vec![
-            "data/original/2017_China_Chongqing_Boats.jpg".to_string(),
-            "data/original/NoImageToBeFoundHere.jpg".to_string(),
-        ];
-
-        let option = parse_args(args.clone()).unwrap();
-
-        assert_eq!(option.len(), 1);
-        assert_eq!(option[0].to_str().unwrap(), args[0]);
-    }
-
-    #[test]
-    fn test_parse_args_failure() {
-        assert_eq!(
-            parse_args(Vec::new()).is_none(),
-            true
-        );
+    fn test_check_size() {
+        assert!(check_size(8).is_ok());
+        assert!(check_size(16).is_ok());
+        assert!(check_size(32).is_ok());
+        assert!(check_size(0).is_err());
+        assert!(check_size(4).is_err());
+        assert!(check_size(20).is_err());
     }
 }
\ No newline at end of file
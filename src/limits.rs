@@ -0,0 +1,156 @@
+//! Limits for decoding images, protecting against decompression bombs.
+//!
+//! The limits are checked with the file size and the dimensions from the image header, before the image
+//! is decoded. The allocation is estimated with the largest pixel type (`MAX_BYTES_PER_PIXEL`), because the
+//! pixel type is only known after decoding, plus the copies of the hashing pipeline (`PIPELINE_BYTES_PER_PIXEL`).
+//!
+//! The limits apply to a single image. Batches hash one image per thread, so they may use the limits
+//! once per thread (`rayon::current_num_threads`), e.g. 16 GiB with the default `max_alloc` and 16 threads.
+//! The files are read while decoding and not kept in memory, so the file size does not add to it.
+
+use std::fmt;
+
+use crate::error::{ForbildError, Result};
+
+/// Bytes of the largest pixel type the decoders produce (RGBA with 16 bit per channel)
+pub const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+/// Bytes per pixel the hashing pipeline allocates while the decoded image is kept: an RGB copy
+/// for the grayscale conversion (3) and the grayscale image (1), see `editing::preprocess_dynamic_image_with_config`
+pub const PIPELINE_BYTES_PER_PIXEL: u64 = 4;
+
+/// Default of `DecodeLimits::max_pixels`: 256 megapixels
+pub const DEFAULT_MAX_PIXELS: u64 = 1 << 28;
+/// Default of `DecodeLimits::max_alloc`: 1 GiB, enough for about 89 megapixels
+pub const DEFAULT_MAX_ALLOC: u64 = 1 << 30;
+/// Default of `DecodeLimits::max_file_size`: 1 GiB
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// The limit that was exceeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Width,
+    Height,
+    Pixels,
+    Allocation,
+    FileSize,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Width => "width",
+            Limit::Height => "height",
+            Limit::Pixels => "pixel count",
+            Limit::Allocation => "allocation",
+            Limit::FileSize => "file size",
+        })
+    }
+}
+
+/// Maximum sizes of an image to decode, `None` means unlimited. They apply per image, i.e. per thread of a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    /// Maximum bytes of the decoded image and the copies made for hashing it
+    pub max_alloc: Option<u64>,
+    /// Maximum bytes of the encoded image
+    pub max_file_size: Option<u64>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: None,
+            max_height: None,
+            max_pixels: Some(DEFAULT_MAX_PIXELS),
+            max_alloc: Some(DEFAULT_MAX_ALLOC),
+            max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// No limits at all
+    pub fn none() -> DecodeLimits {
+        DecodeLimits { max_width: None, max_height: None, max_pixels: None, max_alloc: None, max_file_size: None }
+    }
+
+    pub fn with_max_dimensions(mut self, width: u32, height: u32) -> DecodeLimits {
+        self.max_width = Some(width);
+        self.max_height = Some(height);
+        self
+    }
+
+    pub fn with_max_pixels(mut self, pixels: u64) -> DecodeLimits {
+        self.max_pixels = Some(pixels);
+        self
+    }
+
+    pub fn with_max_alloc(mut self, bytes: u64) -> DecodeLimits {
+        self.max_alloc = Some(bytes);
+        self
+    }
+
+    pub fn with_max_file_size(mut self, bytes: u64) -> DecodeLimits {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    pub fn check_file_size(&self, file_size: u64) -> Result<()> {
+        check(Limit::FileSize, file_size, self.max_file_size)
+    }
+
+    /// Checks the dimensions read from the image header
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<()> {
+        check(Limit::Width, width as u64, self.max_width.map(u64::from))?;
+        check(Limit::Height, height as u64, self.max_height.map(u64::from))?;
+        let pixels = width as u64 * height as u64;
+        check(Limit::Pixels, pixels, self.max_pixels)?;
+        check(Limit::Allocation, pixels.saturating_mul(MAX_BYTES_PER_PIXEL + PIPELINE_BYTES_PER_PIXEL), self.max_alloc)
+    }
+}
+
+fn check(limit: Limit, value: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if value > max => Err(ForbildError::LimitExceeded { limit, value, max }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use super::*;
+
+    #[test]
+    fn test_check_dimensions() {
+        let limits = DecodeLimits::default();
+        assert!(limits.check_dimensions(900, 600).is_ok());
+        assert!(limits.check_dimensions(100_000, 100).is_ok());
+        assert!(matches!(limits.check_dimensions(65535, 65535),
+                         Err(ForbildError::LimitExceeded { limit: Limit::Pixels, value: 4294836225, max: DEFAULT_MAX_PIXELS })));
+        assert!(DecodeLimits::none().check_dimensions(u32::MAX, u32::MAX).is_ok());
+
+        let limits = DecodeLimits::none().with_max_dimensions(1000, 500);
+        assert!(limits.check_dimensions(1000, 500).is_ok());
+        assert!(matches!(limits.check_dimensions(1001, 1), Err(ForbildError::LimitExceeded { limit: Limit::Width, .. })));
+        assert!(matches!(limits.check_dimensions(1, 501), Err(ForbildError::LimitExceeded { limit: Limit::Height, .. })));
+
+        // The decoded image and the copies of the pipeline take 12 bytes per pixel
+        let limits = DecodeLimits::none().with_max_alloc(1200);
+        assert!(limits.check_dimensions(10, 10).is_ok());
+        assert!(matches!(limits.check_dimensions(10, 11), Err(ForbildError::LimitExceeded { limit: Limit::Allocation, value: 1320, max: 1200 })));
+        assert!(DecodeLimits::default().check_dimensions(8000, 11000).is_ok());
+        assert!(DecodeLimits::default().check_dimensions(10000, 10000).is_err());
+    }
+
+    #[test]
+    fn test_check_file_size() {
+        let limits = DecodeLimits::none().with_max_file_size(100);
+        assert!(limits.check_file_size(100).is_ok());
+        let err = limits.check_file_size(101).unwrap_err();
+        assert_eq!(err.to_string(), "Image file size limit exceeded: 101 > 100");
+    }
+}
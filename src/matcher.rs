@@ -0,0 +1,331 @@
+use crate::error::Result;
+use crate::hash::Hash;
+use crate::hashmath::{check_same_size, hamming_distance, weighted_distance_with_mode, WeightedMode};
+use crate::orientation::Orientation;
+
+/// Hamming distance up to which two images are said to be the same
+pub const DEFAULT_HAMMING_THRESHOLD: usize = 8;
+
+/// Weighted distance up to which two images are said to be the same
+pub const DEFAULT_WEIGHTED_THRESHOLD: f64 = 16.0;
+
+/// Decides whether two hashes belong to the same image, using the two stage ForBild comparison:
+/// 1. Hamming distance <= hamming threshold: the images are the same
+/// 2. Otherwise weighted distance <= weighted threshold: the images are the same
+///
+/// If the weighted distance cannot be calculated (no grayscale data or zero variance, see `weighted_distance`),
+/// the Hamming distance alone decides and the verdict is `NoMatch` without a weighted distance.
+///
+/// With rotation invariance, the first hash is compared with all eight orientations of the second hash
+/// (see `orientation`) and the best verdict is kept, which takes up to eight times as long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matcher {
+    pub hamming_threshold: usize,
+    pub weighted_threshold: f64,
+    pub weighted_mode: WeightedMode,
+    pub rotation_invariant: bool,
+}
+
+impl Matcher {
+    pub fn new(hamming_threshold: usize, weighted_threshold: f64) -> Matcher {
+        Matcher { hamming_threshold, weighted_threshold, weighted_mode: WeightedMode::default(), rotation_invariant: false }
+    }
+
+    pub fn with_weighted_mode(mut self, mode: WeightedMode) -> Matcher {
+        self.weighted_mode = mode;
+        self
+    }
+
+    pub fn with_rotation_invariance(mut self, rotation_invariant: bool) -> Matcher {
+        self.rotation_invariant = rotation_invariant;
+        self
+    }
+
+    /// The orientations of the second hash that are compared
+    pub fn orientations(&self) -> &'static [Orientation] {
+        if self.rotation_invariant {
+            &Orientation::ALL
+        } else {
+            &Orientation::ALL[..1]
+        }
+    }
+
+    /// Compares both hashes.
+    ///
+    /// # Panics
+    /// If the grid sizes of the hashes differ. `try_compare` returns an error instead.
+    pub fn compare(&self, hash1: &Hash, hash2: &Hash) -> MatchVerdict {
+        self.compare_oriented(hash1, hash2).1
+    }
+
+    /// Compares both hashes, `ForbildError::InvalidLength` if their grid sizes differ
+    pub fn try_compare(&self, hash1: &Hash, hash2: &Hash) -> Result<MatchVerdict> {
+        check_same_size(hash1, hash2)?;
+        Ok(self.compare(hash1, hash2))
+    }
+
+    /// Compares the first hash with the orientations of the second hash. Returns the orientation with the
+    /// best verdict: a match before no match, then the lower Hamming distance, then the first orientation.
+    pub fn compare_oriented(&self, hash1: &Hash, hash2: &Hash) -> (Orientation, MatchVerdict) {
+        if !self.rotation_invariant {
+            return (Orientation::Identity, self.compare_once(hash1, hash2));
+        }
+
+        let mut best = (Orientation::Identity, self.compare_once(hash1, hash2));
+        for orientation in &Orientation::ALL[1..] {
+            let verdict = self.compare_once(hash1, &hash2.oriented(*orientation));
+            if (!verdict.is_match(), verdict.hamming_distance()) < (!best.1.is_match(), best.1.hamming_distance()) {
+                best = (*orientation, verdict);
+            }
+        }
+        best
+    }
+
+    /// Compares both hashes like `compare`, but also calculates the weighted distance (if possible)
+    /// if the Hamming distance decides, so that both distances can be analysed.
+    pub fn compare_with_weighted(&self, hash1: &Hash, hash2: &Hash) -> (MatchVerdict, Option<f64>) {
+        let (orientation, verdict) = self.compare_oriented(hash1, hash2);
+        (verdict, self.weighted_distance_of(hash1, hash2, orientation, &verdict))
+    }
+
+    /// The weighted distance of a verdict of `compare_oriented`. It is calculated for the orientation
+    /// if the Hamming distance decided, `None` if it cannot be calculated.
+    pub fn weighted_distance_of(&self, hash1: &Hash, hash2: &Hash, orientation: Orientation, verdict: &MatchVerdict) -> Option<f64> {
+        verdict.weighted_distance().or_else(|| {
+            weighted_distance_with_mode(hash1, &hash2.oriented(orientation), self.weighted_mode).ok()
+        })
+    }
+
+    /// Like `compare_with_weighted`, but `ForbildError::InvalidLength` if the grid sizes differ
+    pub fn try_compare_with_weighted(&self, hash1: &Hash, hash2: &Hash) -> Result<(MatchVerdict, Option<f64>)> {
+        check_same_size(hash1, hash2)?;
+        Ok(self.compare_with_weighted(hash1, hash2))
+    }
+
+    fn compare_once(&self, hash1: &Hash, hash2: &Hash) -> MatchVerdict {
+        let hamming = hamming_distance(hash1, hash2);
+
+        // The weighted distance is only calculated if the Hamming distance cannot decide
+        if hamming == 0 {
+            return MatchVerdict::Identical;
+        }
+        if hamming <= self.hamming_threshold {
+            return MatchVerdict::MatchByHamming { hamming };
+        }
+
+        match weighted_distance_with_mode(hash1, hash2, self.weighted_mode) {
+            Ok(weighted) if weighted <= self.weighted_threshold => MatchVerdict::MatchByWeighted { hamming, weighted },
+            Ok(weighted) => MatchVerdict::NoMatch { hamming, weighted: Some(weighted) },
+            Err(_) => MatchVerdict::NoMatch { hamming, weighted: None },
+        }
+    }
+
+    pub fn is_match(&self, hash1: &Hash, hash2: &Hash) -> bool {
+        self.compare(hash1, hash2).is_match()
+    }
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Matcher::new(DEFAULT_HAMMING_THRESHOLD, DEFAULT_WEIGHTED_THRESHOLD)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchVerdict {
+    /// Both binary hashes are identical
+    Identical,
+    /// The Hamming distance is within the threshold, the weighted distance was not calculated
+    MatchByHamming { hamming: usize },
+    /// The Hamming distance is too high, but the weighted distance is within the threshold
+    MatchByWeighted { hamming: usize, weighted: f64 },
+    /// The weighted distance is None if it could not be calculated
+    NoMatch { hamming: usize, weighted: Option<f64> },
+}
+
+impl MatchVerdict {
+    pub fn is_match(&self) -> bool {
+        !matches!(self, MatchVerdict::NoMatch { .. })
+    }
+
+    pub fn hamming_distance(&self) -> usize {
+        match self {
+            MatchVerdict::Identical => 0,
+            MatchVerdict::MatchByHamming { hamming } => *hamming,
+            MatchVerdict::MatchByWeighted { hamming, .. } => *hamming,
+            MatchVerdict::NoMatch { hamming, .. } => *hamming,
+        }
+    }
+
+    /// The weighted distance, if it was calculated
+    pub fn weighted_distance(&self) -> Option<f64> {
+        match self {
+            MatchVerdict::Identical => Some(0.0),
+            MatchVerdict::MatchByHamming { .. } => None,
+            MatchVerdict::MatchByWeighted { weighted, .. } => Some(*weighted),
+            MatchVerdict::NoMatch { weighted, .. } => *weighted,
+        }
+    }
+
+    /// Short name of the verdict for reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatchVerdict::Identical => "identical",
+            MatchVerdict::MatchByHamming { .. } => "hamming",
+            MatchVerdict::MatchByWeighted { .. } => "weighted",
+            MatchVerdict::NoMatch { .. } => "nomatch",
+        }
+    }
+}
+
+#[cfg(test)]
+mod matcher_tests {
+    use std::path::Path;
+    use image::imageops::FilterType;
+    use crate::hashmath::weighted_distance;
+    use super::*;
+
+    const BOATS: &str = "./data/original/2017_China_Chongqing_Boats.jpg";
+    const TRAFFIC: &str = "./data/original/2015_Japan_Tokyo_Traffic.jpg";
+
+    fn create_testing_hashes(flipped_bits: usize) -> (Hash, Hash) {
+        let mut hash1 = Hash::new();
+        for i in 0..hash1.len() {
+            hash1.grayimage256[i] = i as u8;
+        }
+        hash1.has_grayscale = true;
+        hash1.set_subarea_medians();
+        hash1.set_binary_hash_from_grayimage();
+
+        let mut hash2 = hash1.clone();
+        for i in 0..flipped_bits {
+            let bit = hash2.get_bit(i) == 0;
+            hash2.set_bit(i, bit);
+        }
+        (hash1, hash2)
+    }
+
+    #[test]
+    fn test_identical() {
+        let (hash1, hash2) = create_testing_hashes(0);
+        let verdict = Matcher::default().compare(&hash1, &hash2);
+
+        assert_eq!(verdict, MatchVerdict::Identical);
+        assert!(verdict.is_match());
+        assert_eq!(verdict.hamming_distance(), 0);
+        assert_eq!(verdict.weighted_distance(), Some(0.0));
+    }
+
+    #[test]
+    fn test_match_by_hamming() {
+        let (hash1, hash2) = create_testing_hashes(8);
+        let verdict = Matcher::default().compare(&hash1, &hash2);
+
+        assert_eq!(verdict, MatchVerdict::MatchByHamming { hamming: 8 });
+        assert!(verdict.is_match());
+        assert_eq!(verdict.weighted_distance(), None);
+
+        let verdict = Matcher::new(7, 0.0).compare(&hash1, &hash2);
+        assert!(!verdict.is_match());
+    }
+
+    #[test]
+    fn test_match_by_weighted() {
+        let (hash1, hash2) = create_testing_hashes(9);
+        let weighted = weighted_distance(&hash1, &hash2).unwrap();
+
+        let verdict = Matcher::new(8, weighted).compare(&hash1, &hash2);
+        assert_eq!(verdict, MatchVerdict::MatchByWeighted { hamming: 9, weighted });
+        assert!(verdict.is_match());
+
+        let verdict = Matcher::new(8, weighted - 1.0).compare(&hash1, &hash2);
+        assert_eq!(verdict, MatchVerdict::NoMatch { hamming: 9, weighted: Some(weighted) });
+        assert!(!verdict.is_match());
+        assert_eq!(verdict.name(), "nomatch");
+    }
+
+    #[test]
+    fn test_weighted_mode() {
+        let (hash1, mut hash2) = create_testing_hashes(0);
+        let hashlen = hash2.len();
+        for i in 0..8 {
+            hash2.grayimage256[hashlen-1-i] = 0;
+        }
+        hash2.set_subarea_medians();
+        hash2.set_binary_hash_from_grayimage();
+
+        let matcher = Matcher::new(0, 1.0e9).with_weighted_mode(WeightedMode::Symmetric);
+        assert_eq!(matcher.weighted_mode, WeightedMode::Symmetric);
+        assert_eq!(matcher.compare(&hash1, &hash2), matcher.compare(&hash2, &hash1));
+
+        let matcher = Matcher::new(0, 1.0e9);
+        assert_ne!(matcher.compare(&hash1, &hash2), matcher.compare(&hash2, &hash1));
+    }
+
+    #[test]
+    fn test_weighted_not_calculable() {
+        let (hash1, hash2) = create_testing_hashes(9);
+        let hash_hex = Hash::from_hex_str(&hash2.to_string_hex()).unwrap();
+
+        let verdict = Matcher::new(8, 1.0e9).compare(&hash_hex, &hash1);
+        assert_eq!(verdict, MatchVerdict::NoMatch { hamming: 9, weighted: None });
+        assert_eq!(verdict.weighted_distance(), None);
+
+        // The Hamming distance still decides on its own
+        let verdict = Matcher::new(9, 1.0e9).compare(&hash_hex, &hash1);
+        assert_eq!(verdict, MatchVerdict::MatchByHamming { hamming: 9 });
+    }
+
+    #[test]
+    fn test_real_images() {
+        let boats = Path::new(BOATS);
+        let traffic = Path::new(TRAFFIC);
+        let hash_boats = Hash::from_path(boats).unwrap();
+        let hash_traffic = Hash::from_path(traffic).unwrap();
+
+        let img = image::open(boats).unwrap();
+        let hash_small = Hash::from_dynamic_image(img.resize(300, 200, FilterType::Triangle)).unwrap();
+
+        let matcher = Matcher::default();
+        assert_eq!(matcher.compare(&hash_boats, &hash_boats), MatchVerdict::Identical);
+        assert!(matcher.is_match(&hash_boats, &hash_small));
+        assert!(!matcher.is_match(&hash_boats, &hash_traffic));
+    }
+
+    #[test]
+    fn test_compare_oriented() {
+        let (hash1, hash2) = create_testing_hashes(0);
+        let rotated = hash2.oriented(Orientation::Rotate90);
+        let matcher = Matcher::default().with_rotation_invariance(true);
+
+        assert!(!Matcher::default().is_match(&hash1, &rotated));
+        assert_eq!(Matcher::default().orientations().len(), 1);
+        assert_eq!(matcher.orientations().len(), 8);
+        assert_eq!(matcher.compare_oriented(&hash1, &hash2), (Orientation::Identity, MatchVerdict::Identical));
+        assert_eq!(matcher.compare_oriented(&hash1, &rotated), (Orientation::Rotate270, MatchVerdict::Identical));
+        assert_eq!(matcher.compare_with_weighted(&hash1, &rotated), (MatchVerdict::Identical, Some(0.0)));
+    }
+
+    #[test]
+    fn test_rotated_images() {
+        let matcher = Matcher::default().with_rotation_invariance(true);
+        let hash_traffic = Hash::from_path(Path::new(TRAFFIC)).unwrap();
+
+        for path in [BOATS, TRAFFIC].iter() {
+            let img = image::open(path).unwrap();
+            let hash = Hash::from_dynamic_image(img.clone()).unwrap();
+            let rotated = Hash::from_dynamic_image(Orientation::Rotate90.apply(&img)).unwrap();
+            assert!(!Matcher::default().is_match(&hash, &rotated), "{}", path);
+
+            for orientation in Orientation::ALL.iter() {
+                // Rotated and slightly changed copies
+                let img = orientation.apply(&img).resize(300, 300, FilterType::Triangle).brighten(10);
+                let hash_oriented = Hash::from_dynamic_image(img).unwrap();
+                let verdict = matcher.compare(&hash, &hash_oriented);
+                assert!(verdict.is_match(), "{} {} {:?}", path, orientation, verdict);
+                assert!(verdict.hamming_distance() <= matcher.hamming_threshold, "{} {} {:?}", path, orientation, verdict);
+                assert_eq!(matcher.is_match(&hash_traffic, &hash_oriented), *path == TRAFFIC);
+            }
+        }
+    }
+}
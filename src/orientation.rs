@@ -0,0 +1,122 @@
+//! The eight orientations of an image that are reached by rotating and flipping it (the dihedral group).
+//!
+//! The automatic mirroring of the hash calculation only normalises flips, so an image rotated by 90°
+//! gets a different hash. A `Matcher` with rotation invariance compares the first hash with all
+//! orientations of the second hash (see `Hash::oriented`) and keeps the best match.
+
+use std::fmt;
+use image::DynamicImage;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Orientation {
+    #[default]
+    Identity,
+    /// Rotated clockwise by 90°
+    Rotate90,
+    Rotate180,
+    /// Rotated clockwise by 270°
+    Rotate270,
+    /// Mirrored left to right
+    FlipHorizontal,
+    /// Mirrored top to bottom
+    FlipVertical,
+    /// Mirrored along the diagonal from the top left to the bottom right
+    Transpose,
+    /// Mirrored along the diagonal from the top right to the bottom left
+    Transverse,
+}
+
+impl Orientation {
+    /// All orientations, the identity first
+    pub const ALL: [Orientation; 8] = [
+        Orientation::Identity,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::FlipHorizontal,
+        Orientation::FlipVertical,
+        Orientation::Transpose,
+        Orientation::Transverse,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Orientation::Identity => "identity",
+            Orientation::Rotate90 => "rotate90",
+            Orientation::Rotate180 => "rotate180",
+            Orientation::Rotate270 => "rotate270",
+            Orientation::FlipHorizontal => "flip_horizontal",
+            Orientation::FlipVertical => "flip_vertical",
+            Orientation::Transpose => "transpose",
+            Orientation::Transverse => "transverse",
+        }
+    }
+
+    /// Position of the pixel (x, y) of a size x size grid after the transformation
+    pub fn map(&self, x: u32, y: u32, size: u32) -> (u32, u32) {
+        let last = size - 1;
+        match self {
+            Orientation::Identity => (x, y),
+            Orientation::Rotate90 => (last - y, x),
+            Orientation::Rotate180 => (last - x, last - y),
+            Orientation::Rotate270 => (y, last - x),
+            Orientation::FlipHorizontal => (last - x, y),
+            Orientation::FlipVertical => (x, last - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Transverse => (last - y, last - x),
+        }
+    }
+
+    /// Rotates and flips the image
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        match self {
+            Orientation::Identity => img.clone(),
+            Orientation::Rotate90 => img.rotate90(),
+            Orientation::Rotate180 => img.rotate180(),
+            Orientation::Rotate270 => img.rotate270(),
+            Orientation::FlipHorizontal => img.fliph(),
+            Orientation::FlipVertical => img.flipv(),
+            Orientation::Transpose => img.rotate90().fliph(),
+            Orientation::Transverse => img.rotate90().flipv(),
+        }
+    }
+}
+
+impl fmt::Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod orientation_tests {
+    use image::{GenericImageView, GrayImage, Luma};
+    use super::*;
+
+    #[test]
+    fn test_map_matches_apply() {
+        let mut img = GrayImage::new(4, 4);
+        for (x, y, pix) in img.enumerate_pixels_mut() {
+            *pix = Luma([(x + 4*y) as u8]);
+        }
+        let img = DynamicImage::ImageLuma8(img);
+
+        for orientation in Orientation::ALL.iter() {
+            let oriented = orientation.apply(&img);
+            for (x, y, pix) in img.pixels() {
+                let (new_x, new_y) = orientation.map(x, y, 4);
+                assert_eq!(oriented.get_pixel(new_x, new_y), pix, "{}", orientation);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_non_square() {
+        let img = DynamicImage::new_luma8(30, 20);
+        assert_eq!(Orientation::Rotate90.apply(&img).dimensions(), (20, 30));
+        assert_eq!(Orientation::Transverse.apply(&img).dimensions(), (20, 30));
+        assert_eq!(Orientation::FlipVertical.apply(&img).dimensions(), (30, 20));
+    }
+}
@@ -0,0 +1,420 @@
+//! Versioned JSON and NDJSON (newline-delimited JSON) format for storing hashes.
+//!
+//! A `HashRecord` is serialized as a JSON object:
+//!
+//! ```text
+//! {
+//!   "format_version": 1,
+//!   "algorithm_version": 1,
+//!   "path": "data/original/2017_China_Chongqing_Boats.jpg",
+//!   "file_size": 859563,
+//!   "width": 900,
+//!   "height": 600,
+//!   "hash": {
+//!     "size": 16,
+//!     "binary256": "03FE83FE8EFE8E029EC09FE00FE00FE01FF03FE03F8F1FBE847E0CFC8BC00300",
+//!     "grayimage256": [42, 34, 33, ...],
+//!     "subarea_medians": [[74, 52], [65, 56]],
+//!     "has_grayscale": true,
+//!     "flip": "identity",
+//!     "config": {
+//!       "size": 16,
+//!       "filter": "gaussian",
+//!       "luma": "rec709",
+//!       "averaging": "srgb",
+//!       "threshold": "median",
+//!       "normalization": "brightest_pixel",
+//!       "jpeg": "full"
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! - `format_version`: Version of this record layout. Records with another version are rejected
+//!   with `ForbildError::UnsupportedVersion` instead of being misread.
+//! - `algorithm_version`: Version of the hashing algorithm that created the hash. Hashes of
+//!   different algorithm versions should not be compared with each other.
+//! - `hash.size`: Width and height of the hash grid.
+//! - `hash.binary256`: The binary hash as uppercase hex string (size*size/4 characters).
+//! - `hash.grayimage256`: The downsampled grayscale image row by row (size*size values).
+//! - `hash.subarea_medians`: Medians of the subareas, indexed by `[column][row]`.
+//! - `hash.has_grayscale`: False if the hash has no grayscale data, e.g. because it was created from a hex hash.
+//!   `grayimage256` and `subarea_medians` are all zeros then. If the field is missing (records written before
+//!   it was added), a hash has grayscale data unless `grayimage256` is all zeros.
+//! - `hash.flip`: Flip applied by the normalisation before hashing (`identity`, `flip_horizontal`, `flip_vertical`
+//!   or `rotate180`), see `editing::Normalization`. `identity` if the field is missing.
+//! - `hash.config`: The `config::HashConfig` the hash was created with. The default config if the field is missing.
+//!   `hash.config.jpeg` is `full` if it is missing.
+//!
+//! `grayimage256` and `subarea_medians` are needed to calculate the weighted distance.
+//!
+//! A JSON file contains an array of records, an NDJSON file contains one record per line.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::batch::HashedFile;
+use crate::config::HashConfig;
+use crate::editing::decode_image_from_file;
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::limits::DecodeLimits;
+use crate::orientation::Orientation;
+
+/// Version of the record layout described above
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Version of the hashing algorithm, increased whenever the same image would get a different hash
+pub const ALGORITHM_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HashRecord {
+    pub format_version: u32,
+    pub algorithm_version: u32,
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hash: Hash,
+}
+
+impl HashRecord {
+    pub fn new(path: PathBuf, file_size: u64, width: u32, height: u32, hash: Hash) -> HashRecord {
+        HashRecord {
+            format_version: FORMAT_VERSION,
+            algorithm_version: ALGORITHM_VERSION,
+            path,
+            file_size,
+            width,
+            height,
+            hash,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<HashRecord> {
+        HashRecord::from_path_with_size(path, crate::DEFAULT_SIZE)
+    }
+
+    pub fn from_path_with_size(path: &Path, size: u32) -> Result<HashRecord> {
+        HashRecord::from_path_with_config(path, &HashConfig::default().with_size(size))
+    }
+
+    /// Width and height are taken from the header of the decoded image, whose format is guessed from the content
+    pub fn from_path_with_config(path: &Path, config: &HashConfig) -> Result<HashRecord> {
+        let file_size = fs::metadata(path)?.len();
+        let decoded = decode_image_from_file(path, &DecodeLimits::default(), config)?;
+        let (width, height) = (decoded.width, decoded.height);
+        let hash = Hash::from_dynamic_image_with_config(decoded.image, config)?;
+
+        Ok(HashRecord::new(path.to_path_buf(), file_size, width, height, hash))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<HashRecord> {
+        HashRecord::from_value(serde_json::from_str(json)?)
+    }
+
+    /// Checks the format version before deserializing the rest of the record
+    fn from_value(value: Value) -> Result<HashRecord> {
+        let version = value.get("format_version")
+            .and_then(|v| v.as_u64())
+            .ok_or(ForbildError::UnsupportedVersion { found: 0, supported: FORMAT_VERSION })?;
+        if version != FORMAT_VERSION as u64 {
+            return Err(ForbildError::UnsupportedVersion { found: version as u32, supported: FORMAT_VERSION });
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+impl From<HashedFile> for HashRecord {
+    fn from(file: HashedFile) -> HashRecord {
+        HashRecord::new(file.path, file.file_size, file.width, file.height, file.hash)
+    }
+}
+
+/// Serializes the records as a JSON array
+pub fn records_to_json(records: &[HashRecord]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(records)?)
+}
+
+/// Deserializes a JSON array of records
+pub fn records_from_json(json: &str) -> Result<Vec<HashRecord>> {
+    let values: Vec<Value> = serde_json::from_str(json)?;
+    values.into_iter()
+        .map(HashRecord::from_value)
+        .collect()
+}
+
+/// Writes the records as NDJSON, one record per line
+pub fn write_ndjson<W: Write>(writer: &mut W, records: &[HashRecord]) -> Result<()> {
+    for record in records {
+        writeln!(writer, "{}", record.to_json()?)?;
+    }
+    Ok(())
+}
+
+/// Reads NDJSON records line by line. Empty lines are skipped.
+pub struct NdjsonReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+}
+
+impl<R: BufRead> NdjsonReader<R> {
+    pub fn new(reader: R) -> NdjsonReader<R> {
+        NdjsonReader { lines: reader.lines() }
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonReader<R> {
+    type Item = Result<HashRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if !line.trim().is_empty() {
+                return Some(HashRecord::from_json(&line));
+            }
+        }
+    }
+}
+
+/// Serialized form of `Hash`, storing the binary hash as hex string
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HashRepr {
+    size: u32,
+    binary256: String,
+    grayimage256: Vec<u8>,
+    subarea_medians: [[u8; 2]; 2],
+    #[serde(default)]
+    has_grayscale: Option<bool>,
+    #[serde(default)]
+    flip: Orientation,
+    #[serde(default)]
+    config: Option<HashConfig>,
+}
+
+impl From<Hash> for HashRepr {
+    fn from(hash: Hash) -> Self {
+        HashRepr {
+            size: hash.size,
+            binary256: hash.to_string_hex(),
+            grayimage256: hash.grayimage256,
+            subarea_medians: hash.subarea_medians,
+            has_grayscale: Some(hash.has_grayscale),
+            flip: hash.flip,
+            config: Some(hash.config),
+        }
+    }
+}
+
+impl TryFrom<HashRepr> for Hash {
+    type Error = ForbildError;
+
+    fn try_from(repr: HashRepr) -> Result<Hash> {
+        let mut hash = Hash::from_hex_str(&repr.binary256)?;
+        if hash.size != repr.size {
+            return Err(ForbildError::InvalidLength {
+                expected: (repr.size*repr.size/4) as usize,
+                found: repr.binary256.len()
+            });
+        }
+        if repr.grayimage256.len() != hash.len() {
+            return Err(ForbildError::InvalidLength { expected: hash.len(), found: repr.grayimage256.len() });
+        }
+
+        hash.has_grayscale = repr.has_grayscale
+            .unwrap_or_else(|| repr.grayimage256.iter().any(|v| *v != 0));
+        hash.grayimage256 = repr.grayimage256;
+        hash.subarea_medians = repr.subarea_medians;
+        hash.flip = repr.flip;
+        if let Some(config) = repr.config {
+            if config.size != hash.size {
+                return Err(ForbildError::InvalidGridSize(config.size));
+            }
+            hash.config = config;
+        }
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod record_tests {
+    use crate::hashmath::{hamming_distance, weighted_distance};
+    use super::*;
+
+    fn create_records() -> Vec<HashRecord> {
+        vec![
+            HashRecord::from_path(Path::new("./data/original/2017_China_Chongqing_Boats.jpg")).unwrap(),
+            HashRecord::from_path(Path::new("./data/original/2015_Japan_Tokyo_Traffic.jpg")).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_record_from_path() {
+        let records = create_records();
+
+        assert_eq!(records[0].format_version, FORMAT_VERSION);
+        assert_eq!(records[0].algorithm_version, ALGORITHM_VERSION);
+        assert_eq!(records[0].file_size, 859563);
+        assert_eq!(records[0].hash.subarea_medians, [[74, 52], [65, 56]]);
+        assert_eq!((records[0].width, records[0].height), (900, 600));
+    }
+
+    #[test]
+    fn test_record_dimensions_from_content() {
+        let path = std::env::temp_dir().join(format!("forbild_test_record_{}.bin", std::process::id()));
+        fs::copy("./data/original/2017_China_Chongqing_Boats.jpg", &path).unwrap();
+
+        let record = HashRecord::from_path(&path).unwrap();
+        assert_eq!((record.width, record.height), (900, 600));
+
+        // The header dimensions are kept when the JPEG is decoded at a reduced scale
+        let config = HashConfig::default().with_jpeg_decoding(crate::config::JpegDecoding::Dc);
+        let record = HashRecord::from_path_with_config(&path, &config).unwrap();
+        assert_eq!((record.width, record.height), (900, 600));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let records = create_records();
+
+        let json = records[0].to_json().unwrap();
+        let record = HashRecord::from_json(&json).unwrap();
+        assert_eq!(record, records[0]);
+
+        let json = records_to_json(&records).unwrap();
+        assert_eq!(records_from_json(&json).unwrap(), records);
+
+        // The weighted distance still works after a round-trip
+        let restored = records_from_json(&json).unwrap();
+        assert_eq!(
+            weighted_distance(&restored[0].hash, &restored[1].hash).unwrap(),
+            weighted_distance(&records[0].hash, &records[1].hash).unwrap()
+        );
+        assert_eq!(
+            hamming_distance(&restored[0].hash, &restored[1].hash),
+            hamming_distance(&records[0].hash, &records[1].hash)
+        );
+    }
+
+    #[test]
+    fn test_ndjson_roundtrip() {
+        let records = create_records();
+        let mut buffer = Vec::new();
+
+        write_ndjson(&mut buffer, &records).unwrap();
+        buffer.extend_from_slice(b"\n");
+
+        let text = String::from_utf8(buffer.clone()).unwrap();
+        assert_eq!(text.lines().filter(|l| !l.is_empty()).count(), 2);
+
+        let restored: Vec<HashRecord> = NdjsonReader::new(buffer.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(restored, records);
+    }
+
+    #[test]
+    fn test_hash_json_format() {
+        let hash = Hash::from_path(Path::new("./data/original/2017_China_Chongqing_Boats.jpg")).unwrap();
+        let value = serde_json::to_value(&hash).unwrap();
+
+        assert_eq!(value["size"], 16);
+        assert_eq!(value["binary256"], hash.to_string_hex());
+        assert_eq!(value["grayimage256"].as_array().unwrap().len(), 256);
+        assert_eq!(value["subarea_medians"], serde_json::json!([[74, 52], [65, 56]]));
+        assert_eq!(value["config"], serde_json::json!({
+            "size": 16,
+            "filter": "gaussian",
+            "luma": "rec709",
+            "averaging": "srgb",
+            "threshold": "median",
+            "normalization": "brightest_pixel",
+            "jpeg": "full",
+        }));
+    }
+
+    #[test]
+    fn test_hash_config() {
+        let config = HashConfig::default()
+            .with_size(8)
+            .with_filter(crate::config::ResizeFilter::Triangle)
+            .with_threshold(crate::config::ThresholdStatistic::Mean);
+        let path = Path::new("./data/original/2017_China_Chongqing_Boats.jpg");
+        let record = HashRecord::from_path_with_config(path, &config).unwrap();
+
+        let restored = HashRecord::from_json(&record.to_json().unwrap()).unwrap();
+        assert_eq!(restored.hash.config, config);
+        assert_eq!(restored, record);
+
+        // Records without the field were created with the default config
+        let mut value = serde_json::to_value(&record).unwrap();
+        value["hash"].as_object_mut().unwrap().remove("config");
+        let restored = HashRecord::from_json(&value.to_string()).unwrap();
+        assert_eq!(restored.hash.config, HashConfig::default().with_size(8));
+
+        value["hash"]["config"] = serde_json::to_value(HashConfig::default()).unwrap();
+        assert!(HashRecord::from_json(&value.to_string()).is_err());
+
+        value["hash"]["config"] = serde_json::to_value(config).unwrap();
+        value["hash"]["config"].as_object_mut().unwrap().remove("jpeg");
+        let restored = HashRecord::from_json(&value.to_string()).unwrap();
+        assert_eq!(restored.hash.config.jpeg, crate::config::JpegDecoding::Full);
+    }
+
+    #[test]
+    fn test_has_grayscale() {
+        let hash = Hash::from_path(Path::new("./data/original/2017_China_Chongqing_Boats.jpg")).unwrap();
+        let hash_hex = Hash::from_hex_str(&hash.to_string_hex()).unwrap();
+
+        let json = serde_json::to_string(&hash_hex).unwrap();
+        let restored: Hash = serde_json::from_str(&json).unwrap();
+        assert!(!restored.has_grayscale);
+
+        // Records without the field
+        let mut value = serde_json::to_value(&hash).unwrap();
+        value.as_object_mut().unwrap().remove("has_grayscale");
+        let restored: Hash = serde_json::from_value(value).unwrap();
+        assert!(restored.has_grayscale);
+
+        let mut value = serde_json::to_value(&hash_hex).unwrap();
+        value.as_object_mut().unwrap().remove("has_grayscale");
+        let restored: Hash = serde_json::from_value(value).unwrap();
+        assert!(!restored.has_grayscale);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut value = serde_json::to_value(&create_records()[0]).unwrap();
+        value["format_version"] = Value::from(FORMAT_VERSION + 1);
+        value["hash"] = Value::from("a future hash layout");
+
+        let result = HashRecord::from_json(&value.to_string());
+        assert!(matches!(result, Err(ForbildError::UnsupportedVersion { found: 2, supported: 1 })));
+
+        let result = HashRecord::from_json("{\"path\": \"missing_version.jpg\"}");
+        assert!(matches!(result, Err(ForbildError::UnsupportedVersion { found: 0, .. })));
+    }
+
+    #[test]
+    fn test_invalid_hash() {
+        let mut value = serde_json::to_value(&create_records()[0]).unwrap();
+        value["hash"]["grayimage256"] = serde_json::json!([1, 2, 3]);
+        assert!(HashRecord::from_json(&value.to_string()).is_err());
+
+        let mut value = serde_json::to_value(&create_records()[0]).unwrap();
+        value["hash"]["size"] = Value::from(8);
+        assert!(HashRecord::from_json(&value.to_string()).is_err());
+    }
+}
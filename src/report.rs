@@ -0,0 +1,424 @@
+//! Reports of compared image pairs as CSV or JSON.
+//!
+//! Each row contains both paths, the Hamming distance, the weighted distance and the verdict of the
+//! `Matcher` (see `MatchVerdict::name`), followed by the flips the normalisation applied to both images
+//! (see `Hash::flip`) and the config the hashes were created with (see `HashConfig`, written with its
+//! `Display` format in CSV). The weighted distance is empty in CSV and `null` in JSON if it cannot be
+//! calculated for the pair.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::config::HashConfig;
+use crate::error::{ForbildError, Result};
+use crate::hash::Hash;
+use crate::matcher::{MatchVerdict, Matcher};
+use crate::orientation::Orientation;
+
+/// Number of pairs `for_each_pair` compares before it passes their reports on
+const CHUNK_PAIRS: usize = 1 << 16;
+
+/// Number of pairs a thread of `for_each_pair` compares at once
+const SPAN_PAIRS: usize = 1 << 10;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PairReport {
+    pub path1: PathBuf,
+    pub path2: PathBuf,
+    pub hamming: usize,
+    pub weighted: Option<f64>,
+    pub verdict: String,
+    pub is_match: bool,
+    pub flip1: Orientation,
+    pub flip2: Orientation,
+    /// Config of the first hash, see `Hash::config`
+    pub config: HashConfig,
+}
+
+impl PairReport {
+    /// Compares both hashes with the matcher. Unlike `Matcher::compare`, the weighted distance
+    /// is always calculated (if possible), so that both distances can be analysed.
+    ///
+    /// # Panics
+    /// If the grid sizes of the hashes differ, like `Matcher::compare`.
+    pub fn new(path1: &Path, hash1: &Hash, path2: &Path, hash2: &Hash, matcher: &Matcher) -> PairReport {
+        let (orientation, verdict) = matcher.compare_oriented(hash1, hash2);
+        PairReport::from_verdict(path1, hash1, path2, hash2, matcher, orientation, verdict)
+    }
+
+    /// Report of a verdict of `Matcher::compare_oriented`, so that the hashes are not compared again
+    fn from_verdict(
+        path1: &Path, hash1: &Hash, path2: &Path, hash2: &Hash, matcher: &Matcher, orientation: Orientation, verdict: MatchVerdict,
+    ) -> PairReport {
+        let weighted = matcher.weighted_distance_of(hash1, hash2, orientation, &verdict);
+
+        PairReport {
+            path1: path1.to_path_buf(),
+            path2: path2.to_path_buf(),
+            hamming: verdict.hamming_distance(),
+            weighted,
+            verdict: verdict.name().to_string(),
+            is_match: verdict.is_match(),
+            flip1: hash1.flip,
+            flip2: hash2.flip,
+            config: hash1.config,
+        }
+    }
+}
+
+/// Compares every hash with every other hash on all threads. Returns the matching pairs, or all
+/// pairs if `all` is set, ordered by the indexes of the hashes, independent of the number of threads.
+/// Hashes of different grid sizes give `ForbildError::InvalidLength`, hashes of configs that cannot
+/// be compared `ForbildError::ConfigMismatch`.
+///
+/// All pairs of n hashes are n*(n-1)/2 reports, `for_each_pair` passes them on without keeping them.
+pub fn compare_all(paths: &[PathBuf], hashs: &[Hash], matcher: &Matcher, all: bool) -> Result<Vec<PairReport>> {
+    let mut pairs = Vec::new();
+    for_each_pair(paths, hashs, matcher, all, |pair| {
+        pairs.push(pair);
+        Ok(())
+    })?;
+    Ok(pairs)
+}
+
+/// Like `compare_all`, but calls `f` with each report in the same order instead of collecting them.
+/// The pairs are compared on all threads in chunks of `CHUNK_PAIRS`, so only one chunk is kept in memory.
+/// An error of `f` stops the comparison.
+pub fn for_each_pair<F>(paths: &[PathBuf], hashs: &[Hash], matcher: &Matcher, all: bool, mut f: F) -> Result<()>
+    where F: FnMut(PairReport) -> Result<()>
+{
+    if let Some(other) = hashs.iter().find(|hash| hash.size != hashs[0].size) {
+        return Err(ForbildError::InvalidLength { expected: hashs[0].len(), found: other.len() });
+    }
+    if let Some(other) = hashs.iter().find(|hash| !hash.config.is_comparable(&hashs[0].config)) {
+        return Err(ForbildError::ConfigMismatch { expected: hashs[0].config, found: other.config });
+    }
+
+    // Compares the spans (i, j_start, j_end) of a chunk and passes the reports on in their order
+    let mut compare_chunk = |spans: &[(usize, usize, usize)]| -> Result<()> {
+        let reports: Vec<Vec<PairReport>> = spans.par_iter()
+            .map(|&(i, start, end)| {
+                (start..end).filter_map(|j| {
+                    let (orientation, verdict) = matcher.compare_oriented(&hashs[i], &hashs[j]);
+                    (all || verdict.is_match()).then(|| {
+                        PairReport::from_verdict(&paths[i], &hashs[i], &paths[j], &hashs[j], matcher, orientation, verdict)
+                    })
+                }).collect()
+            })
+            .collect();
+        reports.into_iter().flatten().try_for_each(&mut f)
+    };
+
+    let n = hashs.len();
+    let mut spans = Vec::new();
+    let mut chunk_pairs = 0;
+    for i in 0..n {
+        let mut start = i + 1;
+        while start < n {
+            let end = n.min(start + SPAN_PAIRS).min(start + CHUNK_PAIRS - chunk_pairs);
+            spans.push((i, start, end));
+            chunk_pairs += end - start;
+            start = end;
+            if chunk_pairs == CHUNK_PAIRS {
+                compare_chunk(&spans)?;
+                spans.clear();
+                chunk_pairs = 0;
+            }
+        }
+    }
+    compare_chunk(&spans)
+}
+
+/// Groups the paths that are connected by matching pairs (if a matches b and b matches c, all three
+/// are in one group). Paths without any match are left out. Groups and their paths keep the order of `paths`.
+pub fn group_duplicates(paths: &[PathBuf], pairs: &[PairReport]) -> Vec<Vec<PathBuf>> {
+    let indexes: HashMap<&Path, usize> = paths.iter().enumerate().map(|(i, path)| (path.as_path(), i)).collect();
+
+    // Union-find, every group is represented by its smallest index
+    let mut parents: Vec<usize> = (0..paths.len()).collect();
+    fn find(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    for pair in pairs.iter().filter(|pair| pair.is_match) {
+        if let (Some(&i), Some(&j)) = (indexes.get(pair.path1.as_path()), indexes.get(pair.path2.as_path())) {
+            let (root_i, root_j) = (find(&mut parents, i), find(&mut parents, j));
+            parents[root_i.max(root_j)] = root_i.min(root_j);
+        }
+    }
+
+    let mut groups: Vec<Vec<PathBuf>> = vec![Vec::new(); paths.len()];
+    for (i, path) in paths.iter().enumerate() {
+        let root = find(&mut parents, i);
+        groups[root].push(path.clone());
+    }
+    groups.into_iter().filter(|group| group.len() > 1).collect()
+}
+
+/// Output format of a report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ReportFormat::Csv),
+            #[cfg(feature = "serde")]
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("Unknown report format: {}", s)),
+        }
+    }
+}
+
+pub fn write_report<W: Write>(writer: &mut W, pairs: &[PairReport], format: ReportFormat) -> Result<()> {
+    let mut report = ReportWriter::new(writer, format)?;
+    for pair in pairs {
+        report.write(pair)?;
+    }
+    report.finish()?;
+    Ok(())
+}
+
+/// Writes the pairs as CSV with a header line
+pub fn write_csv<W: Write>(writer: &mut W, pairs: &[PairReport]) -> Result<()> {
+    write_report(writer, pairs, ReportFormat::Csv)
+}
+
+/// Writes the pairs as pretty printed JSON array
+#[cfg(feature = "serde")]
+pub fn write_json<W: Write>(writer: &mut W, pairs: &[PairReport]) -> Result<()> {
+    write_report(writer, pairs, ReportFormat::Json)
+}
+
+/// Writes a report pair by pair, e.g. the reports of `for_each_pair`, without keeping them in memory
+pub struct ReportWriter<W: Write> {
+    writer: W,
+    format: ReportFormat,
+    rows: usize,
+}
+
+impl<W: Write> ReportWriter<W> {
+    /// Writes the CSV header line or the start of the JSON array
+    pub fn new(mut writer: W, format: ReportFormat) -> Result<ReportWriter<W>> {
+        match format {
+            ReportFormat::Csv => writeln!(writer, "path1,path2,hamming,weighted,verdict,flip1,flip2,config")?,
+            #[cfg(feature = "serde")]
+            ReportFormat::Json => write!(writer, "[")?,
+        }
+        Ok(ReportWriter { writer, format, rows: 0 })
+    }
+
+    pub fn write(&mut self, pair: &PairReport) -> Result<()> {
+        match self.format {
+            ReportFormat::Csv => {
+                let weighted = pair.weighted.map(|w| w.to_string()).unwrap_or_default();
+                writeln!(self.writer, "{},{},{},{},{},{},{},{}",
+                         csv_field(&pair.path1.to_string_lossy()),
+                         csv_field(&pair.path2.to_string_lossy()),
+                         pair.hamming,
+                         weighted,
+                         pair.verdict,
+                         pair.flip1,
+                         pair.flip2,
+                         csv_field(&pair.config.to_string())
+                )?;
+            },
+            #[cfg(feature = "serde")]
+            ReportFormat::Json => {
+                // Same layout as `serde_json::to_writer_pretty` of the whole array
+                let separator = if self.rows == 0 { "\n" } else { ",\n" };
+                let json = serde_json::to_string_pretty(pair)?;
+                write!(self.writer, "{}  {}", separator, json.replace('\n', "\n  "))?;
+            },
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Writes the end of the JSON array, flushes and returns the writer
+    pub fn finish(mut self) -> Result<W> {
+        #[cfg(feature = "serde")]
+        if self.format == ReportFormat::Json {
+            if self.rows > 0 {
+                writeln!(self.writer)?;
+            }
+            writeln!(self.writer, "]")?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod report_tests {
+    use super::*;
+
+    fn create_testing_hashes() -> (Hash, Hash) {
+        let mut hash1 = Hash::new();
+        for i in 0..hash1.len() {
+            hash1.grayimage256[i] = i as u8;
+        }
+        hash1.has_grayscale = true;
+        hash1.set_subarea_medians();
+        hash1.set_binary_hash_from_grayimage();
+
+        let mut hash2 = hash1.clone();
+        for i in 0..20 {
+            hash2.set_bit(i, hash2.get_bit(i) == 0);
+        }
+        (hash1, hash2)
+    }
+
+    fn create_testing_pairs() -> Vec<PairReport> {
+        let (hash1, mut hash2) = create_testing_hashes();
+        let hash_hex = Hash::from_hex_str(&hash2.to_string_hex()).unwrap();
+        hash2.flip = Orientation::FlipVertical;
+
+        let matcher = Matcher::default();
+        vec![
+            PairReport::new(Path::new("a.jpg"), &hash1, Path::new("b.jpg"), &hash1, &matcher),
+            PairReport::new(Path::new("a.jpg"), &hash1, Path::new("c, \"d\".jpg"), &hash2, &matcher),
+            PairReport::new(Path::new("e.jpg"), &hash_hex, Path::new("a.jpg"), &hash1, &matcher),
+        ]
+    }
+
+    #[test]
+    fn test_pair_report() {
+        let (hash1, hash2) = create_testing_hashes();
+        let pairs = create_testing_pairs();
+
+        assert_eq!(pairs[0].verdict, "identical");
+        assert!(pairs[0].is_match);
+        assert_eq!(pairs[0].weighted, Some(0.0));
+        assert_eq!(pairs[1].hamming, 20);
+        assert!(pairs[1].weighted.is_some());
+        assert_eq!(pairs[2].weighted, None);
+        assert_eq!(pairs[2].verdict, "nomatch");
+
+        // The weighted distance is calculated even if the Hamming distance decides
+        let pair = PairReport::new(&pairs[1].path1, &hash1, &pairs[1].path2, &hash2, &Matcher::new(30, 0.0));
+        assert_eq!(pair.verdict, "hamming");
+        assert_eq!(pair.weighted, pairs[1].weighted);
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let pairs = create_testing_pairs();
+        let mut buffer = Vec::new();
+        write_report(&mut buffer, &pairs, ReportFormat::Csv).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        let config = "size=16 filter=gaussian luma=rec709 averaging=srgb threshold=median normalization=brightest_pixel jpeg=full";
+        assert_eq!(lines[0], "path1,path2,hamming,weighted,verdict,flip1,flip2,config");
+        assert_eq!(lines[1], format!("a.jpg,b.jpg,0,0,identical,identity,identity,{}", config));
+        assert!(lines[2].starts_with("a.jpg,\"c, \"\"d\"\".jpg\",20,"));
+        assert!(lines[2].ends_with(&format!(",identity,flip_vertical,{}", config)));
+        assert_eq!(lines[3], format!("e.jpg,a.jpg,20,,nomatch,identity,identity,{}", config));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_write_json() {
+        let pairs = create_testing_pairs();
+        let mut buffer = Vec::new();
+        write_report(&mut buffer, &pairs, ReportFormat::Json).unwrap();
+
+        let restored: Vec<PairReport> = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(restored, pairs);
+
+        let value: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(value[2]["weighted"], serde_json::Value::Null);
+        assert_eq!(value[0]["config"]["filter"], "gaussian");
+
+        // The pairs are written one by one in the layout of the whole pretty printed array
+        assert_eq!(String::from_utf8(buffer).unwrap(), format!("{}\n", serde_json::to_string_pretty(&pairs).unwrap()));
+        let mut buffer = Vec::new();
+        write_report(&mut buffer, &[], ReportFormat::Json).unwrap();
+        assert_eq!(buffer, b"[]\n");
+    }
+
+    #[test]
+    fn test_compare_all() {
+        let (hash1, hash2) = create_testing_hashes();
+        let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(PathBuf::from).collect();
+        let hashs = vec![hash1.clone(), hash2, hash1];
+
+        let pairs = compare_all(&paths, &hashs, &Matcher::default(), false).unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].path1.as_path(), pairs[0].path2.as_path()), (Path::new("a"), Path::new("c")));
+
+        let pairs = compare_all(&paths, &hashs, &Matcher::default(), true).unwrap();
+        let names: Vec<(&str, &str)> = pairs.iter()
+            .map(|pair| (pair.path1.to_str().unwrap(), pair.path2.to_str().unwrap()))
+            .collect();
+        assert_eq!(names, vec![("a", "b"), ("a", "c"), ("b", "c")]);
+
+        // More pairs than fit into a chunk are passed on in the order of their indexes
+        let many_paths: Vec<PathBuf> = (0..400).map(|i| PathBuf::from(i.to_string())).collect();
+        let many: Vec<Hash> = (0..400).map(|i| hashs[i % 3].clone()).collect();
+        let mut indexes = Vec::new();
+        for_each_pair(&many_paths, &many, &Matcher::default(), true, |pair| {
+            indexes.push((pair.path1.to_str().unwrap().parse().unwrap(), pair.path2.to_str().unwrap().parse().unwrap()));
+            Ok(())
+        }).unwrap();
+        assert!(indexes.len() > CHUNK_PAIRS);
+        let expected: Vec<(usize, usize)> = (0..400).flat_map(|i| ((i + 1)..400).map(move |j| (i, j))).collect();
+        assert_eq!(indexes, expected);
+        let matches = compare_all(&many_paths, &many, &Matcher::default(), false).unwrap();
+        assert!(matches.iter().all(|pair| pair.is_match));
+        // Only equal hashes match: 267 copies of hash1 and 133 of hash2
+        assert_eq!(matches.len(), 267*266/2 + 133*132/2);
+
+        let hashs = vec![hashs[0].clone(), Hash::with_size(8).unwrap(), hashs[2].clone()];
+        let result = compare_all(&paths, &hashs, &Matcher::default(), false);
+        assert!(matches!(result, Err(ForbildError::InvalidLength { expected: 256, found: 64 })));
+
+        let mut area = hashs[2].clone();
+        area.config = area.config.with_filter(crate::config::ResizeFilter::Area);
+        let result = compare_all(&paths, &[hashs[0].clone(), hashs[0].clone(), area], &Matcher::default(), false);
+        assert!(matches!(result, Err(ForbildError::ConfigMismatch { .. })));
+    }
+
+    #[test]
+    fn test_group_duplicates() {
+        let (hash1, hash2) = create_testing_hashes();
+        let paths: Vec<PathBuf> = ["a", "b", "c", "d", "e"].iter().map(PathBuf::from).collect();
+        let pair = |i: usize, j: usize, hash: &Hash| PairReport::new(&paths[i], &hash1, &paths[j], hash, &Matcher::new(8, 0.0));
+
+        // a-d and d-e match, b-c does not
+        let pairs = vec![pair(3, 4, &hash1), pair(1, 2, &hash2), pair(0, 3, &hash1)];
+        let groups = group_duplicates(&paths, &pairs);
+        assert_eq!(groups, vec![vec![paths[0].clone(), paths[3].clone(), paths[4].clone()]]);
+        assert!(group_duplicates(&paths, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_report_format() {
+        assert_eq!("csv".parse::<ReportFormat>(), Ok(ReportFormat::Csv));
+        assert_eq!("CSV".parse::<ReportFormat>(), Ok(ReportFormat::Csv));
+        assert!("xml".parse::<ReportFormat>().is_err());
+    }
+}
@@ -0,0 +1,555 @@
+//! Scanning images for matches with a set of known reference hashes.
+//!
+//! Reference hashes can be loaded from
+//! - a binary hash database (see `db`),
+//! - a JSON or NDJSON file of hash records (see `record`, needs the `serde` feature),
+//! - a text file with one `id;hash` line per reference, as written by `forbild_create`. The hash is
+//!   either binary (0s and 1s, optionally with a `0b` prefix) or hex. Empty lines and lines starting with `#` are ignored.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::batch::{hash_files_with_config, Batch, Failure, Stage};
+use crate::config::HashConfig;
+use crate::db::{DbReader, MAGIC};
+use crate::error::{ForbildError, Result};
+use crate::hash::{grid_size_of, Hash};
+use crate::limits::DecodeLimits;
+use crate::matcher::Matcher;
+use crate::orientation::Orientation;
+use crate::report::{csv_field, ReportFormat};
+
+/// Exit code of a scan without hits
+pub const EXIT_NO_HITS: i32 = 0;
+/// Exit code of a scan with hits
+pub const EXIT_HITS: i32 = 1;
+/// Exit code of a scan with errors, even if there were hits
+pub const EXIT_ERRORS: i32 = 2;
+
+/// Known hashes with their identifiers. All hashes have the same grid size and, if it is known, a comparable config.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReferenceSet {
+    references: Vec<(String, Hash)>,
+    /// Config of the hashes, `None` if it is not known (hex and binary hashes, databases)
+    config: Option<HashConfig>,
+}
+
+impl ReferenceSet {
+    pub fn new() -> ReferenceSet {
+        ReferenceSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.references.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// Grid size of the reference hashes, `None` if there are none
+    pub fn size(&self) -> Option<u32> {
+        self.references.first().map(|(_, hash)| hash.size)
+    }
+
+    /// Config of the reference hashes, `None` if it is not known
+    pub fn config(&self) -> Option<HashConfig> {
+        self.config
+    }
+
+    /// Config the images have to be hashed with: the config of the references if it is known,
+    /// otherwise the default config of their grid size
+    pub fn hash_config(&self) -> HashConfig {
+        self.config.unwrap_or_else(|| HashConfig::default().with_size(self.size().unwrap_or(crate::DEFAULT_SIZE)))
+    }
+
+    /// Checks that hashes of the config can be compared with the references, `ForbildError::ConfigMismatch` if not
+    pub fn check_config(&self, config: &HashConfig) -> Result<()> {
+        let size_differs = self.size().is_some_and(|size| size != config.size);
+        let config_differs = self.config.is_some_and(|expected| !expected.is_comparable(config));
+        if size_differs || config_differs {
+            return Err(ForbildError::ConfigMismatch { expected: self.hash_config(), found: *config });
+        }
+        Ok(())
+    }
+
+    /// Adds a hash whose config is not known, e.g. a hex hash
+    pub fn insert(&mut self, id: String, hash: Hash) -> Result<()> {
+        if let Some(size) = self.size() {
+            if size != hash.size {
+                return Err(ForbildError::InvalidLength { expected: (size*size) as usize, found: hash.len() });
+            }
+        }
+        self.references.push((id, hash));
+        Ok(())
+    }
+
+    /// Adds a hash created with `Hash::config`, e.g. of a hash record
+    pub fn insert_with_config(&mut self, id: String, hash: Hash) -> Result<()> {
+        if let Some(config) = self.config {
+            if !config.is_comparable(&hash.config) {
+                return Err(ForbildError::ConfigMismatch { expected: config, found: hash.config });
+            }
+        }
+        let config = hash.config;
+        self.insert(id, hash)?;
+        self.config.get_or_insert(config);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, Hash)> {
+        self.references.iter()
+    }
+
+    /// Loads a reference file, its format is detected from its content
+    pub fn load(path: &Path) -> Result<ReferenceSet> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 8];
+        let is_db = file.read_exact(&mut magic).is_ok() && magic == MAGIC;
+        drop(file);
+
+        if is_db {
+            let mut references = ReferenceSet::new();
+            for entry in DbReader::new(File::open(path)?)? {
+                let (id, hash) = entry?;
+                references.insert_with_config(id, hash)?;
+            }
+            return Ok(references);
+        }
+        ReferenceSet::from_text(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads references from JSON, NDJSON or `id;hash` lines
+    pub fn from_text<R: BufRead>(reader: R) -> Result<ReferenceSet> {
+        let mut lines = Vec::new();
+        for line in reader.lines() {
+            lines.push(line?);
+        }
+
+        let first = lines.iter().map(|line| line.trim()).find(|line| !line.is_empty() && !line.starts_with('#'));
+        match first {
+            #[cfg(feature = "serde")]
+            Some(line) if line.starts_with('[') => {
+                let mut references = ReferenceSet::new();
+                for record in crate::record::records_from_json(&lines.join("\n"))? {
+                    references.insert_with_config(record.path.to_string_lossy().into_owned(), record.hash)?;
+                }
+                Ok(references)
+            },
+            #[cfg(feature = "serde")]
+            Some(line) if line.starts_with('{') => {
+                let mut references = ReferenceSet::new();
+                let text = lines.join("\n");
+                for record in crate::record::NdjsonReader::new(text.as_bytes()) {
+                    let record = record?;
+                    references.insert_with_config(record.path.to_string_lossy().into_owned(), record.hash)?;
+                }
+                Ok(references)
+            },
+            _ => {
+                let mut references = ReferenceSet::new();
+                for line in lines.iter().map(|line| line.trim()) {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let (id, hash) = parse_reference_line(line, references.size())?;
+                    references.insert(id.to_string(), hash)?;
+                }
+                Ok(references)
+            },
+        }
+    }
+}
+
+/// Splits an `id;hash` line at its last `;`, so that the id may contain `;`.
+/// `size` is the grid size of the references read before, if any.
+fn parse_reference_line(line: &str, size: Option<u32>) -> Result<(&str, Hash)> {
+    let (id, hash) = line.rsplit_once(';')
+        .ok_or_else(|| ForbildError::InvalidDatabase(format!("Missing ';' in reference line: {}", line)))?;
+    let hash = hash.trim();
+    let is_binary = |bits: &str| !bits.is_empty() && bits.chars().all(|c| c == '0' || c == '1');
+
+    // A lowercase hex hash may start with `0b` as well, but then has other digits after it
+    if let Some(bits) = hash.strip_prefix("0b").filter(|bits| is_binary(bits)) {
+        return Ok((id, Hash::from_binary_str(bits)?));
+    }
+
+    // Binary hashes without prefix, as written by older versions. Every number of bits is a valid hex length
+    // as well, with twice the grid size. If that is the grid size of the other references, it is ambiguous.
+    if let Some(bit_size) = Some(hash).filter(|hash| is_binary(hash)).and_then(|hash| grid_size_of(hash.len())) {
+        if size == Some(bit_size*2) {
+            return Err(ForbildError::InvalidDatabase(format!(
+                "Ambiguous hash in reference line, binary hashes need a 0b prefix here: {}", line
+            )));
+        }
+        return Ok((id, Hash::from_binary_str(hash)?));
+    }
+    Ok((id, Hash::from_hex_str(hash)?))
+}
+
+/// An image that matches a reference hash
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanHit {
+    pub path: PathBuf,
+    pub reference: String,
+    pub hamming: usize,
+    /// The weighted distance, if it can be calculated
+    pub weighted: Option<f64>,
+    /// The stage of the `Matcher` that decided, see `MatchVerdict::name`
+    pub stage: String,
+    /// Flip the normalisation applied to the image, see `Hash::flip`
+    pub flip: Orientation,
+    /// Flip the normalisation applied to the reference, `identity` if it is not known (e.g. for hex hashes)
+    pub reference_flip: Orientation,
+    /// Config the image was hashed with, see `Hash::config`
+    pub config: HashConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Number of scanned images, including the ones with errors
+    pub files: usize,
+    pub hits: Vec<ScanHit>,
+    /// Images that could not be hashed
+    pub errors: Vec<Failure>,
+}
+
+impl ScanReport {
+    /// `EXIT_ERRORS` if any image could not be scanned, otherwise `EXIT_HITS` or `EXIT_NO_HITS`
+    pub fn exit_code(&self) -> i32 {
+        if !self.errors.is_empty() {
+            EXIT_ERRORS
+        } else if !self.hits.is_empty() {
+            EXIT_HITS
+        } else {
+            EXIT_NO_HITS
+        }
+    }
+
+    pub fn write_hits<W: Write>(&self, writer: &mut W, format: ReportFormat) -> Result<()> {
+        match format {
+            ReportFormat::Csv => {
+                writeln!(writer, "path,reference,hamming,weighted,stage,flip,reference_flip,config")?;
+                for hit in &self.hits {
+                    writeln!(writer, "{},{},{},{},{},{},{},{}",
+                             csv_field(&hit.path.to_string_lossy()),
+                             csv_field(&hit.reference),
+                             hit.hamming,
+                             hit.weighted.map(|w| w.to_string()).unwrap_or_default(),
+                             hit.stage,
+                             hit.flip,
+                             hit.reference_flip,
+                             csv_field(&hit.config.to_string())
+                    )?;
+                }
+            },
+            #[cfg(feature = "serde")]
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut *writer, &self.hits)?;
+                writeln!(writer)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Compares images with every reference hash. The images are hashed with `ReferenceSet::hash_config`
+/// unless another comparable config is set.
+pub struct Scanner {
+    references: ReferenceSet,
+    matcher: Matcher,
+    config: HashConfig,
+}
+
+impl Scanner {
+    pub fn new(references: ReferenceSet, matcher: Matcher) -> Scanner {
+        let config = references.hash_config();
+        Scanner { references, matcher, config }
+    }
+
+    /// Hashes the images with the config, `ForbildError::ConfigMismatch` if it cannot be compared with the references
+    pub fn with_hash_config(mut self, config: HashConfig) -> Result<Scanner> {
+        self.references.check_config(&config)?;
+        self.config = config;
+        Ok(self)
+    }
+
+    pub fn references(&self) -> &ReferenceSet {
+        &self.references
+    }
+
+    /// Returns all matching references, ordered by Hamming distance.
+    /// The image hash comes first in the comparison, so the directional weighted distance uses its
+    /// grayscale data and works with reference hashes without grayscale data.
+    /// A hash of another grid size than the references gives `ForbildError::InvalidLength`,
+    /// a hash of a config that cannot be compared with the one of the references `ForbildError::ConfigMismatch`.
+    pub fn match_hash(&self, path: &Path, hash: &Hash) -> Result<Vec<ScanHit>> {
+        if let Some(config) = self.references.config() {
+            if !config.is_comparable(&hash.config) {
+                return Err(ForbildError::ConfigMismatch { expected: config, found: hash.config });
+            }
+        }
+        let mut hits = Vec::new();
+        for (id, reference) in self.references.iter() {
+            let (verdict, weighted) = self.matcher.try_compare_with_weighted(hash, reference)?;
+            if verdict.is_match() {
+                hits.push(ScanHit {
+                    path: path.to_path_buf(),
+                    reference: id.clone(),
+                    hamming: verdict.hamming_distance(),
+                    weighted,
+                    stage: verdict.name().to_string(),
+                    flip: hash.flip,
+                    reference_flip: reference.flip,
+                    config: hash.config,
+                });
+            }
+        }
+        hits.sort_by_key(|hit| hit.hamming);
+        Ok(hits)
+    }
+
+    /// Config the images are hashed with
+    pub fn hash_config(&self) -> &HashConfig {
+        &self.config
+    }
+
+    /// Hashes and scans the images on all threads. Hits and errors are in the order of the paths.
+    pub fn scan_paths(&self, paths: &[PathBuf]) -> ScanReport {
+        self.scan_batch(hash_files_with_config(paths, &self.config, &DecodeLimits::default()))
+    }
+
+    /// Scans already hashed images, e.g. from a cache. The failures of the batch become the errors of the report,
+    /// followed by the images that could not be compared with the references.
+    pub fn scan_batch(&self, batch: Batch) -> ScanReport {
+        let results: Vec<Result<Vec<ScanHit>>> = batch.hashed.par_iter()
+            .map(|file| self.match_hash(&file.path, &file.hash))
+            .collect();
+
+        let mut report = ScanReport {
+            files: batch.hashed.len() + batch.failures.len(),
+            hits: Vec::new(),
+            errors: batch.failures,
+        };
+        for (file, result) in batch.hashed.iter().zip(results) {
+            match result {
+                Ok(hits) => report.hits.extend(hits),
+                Err(err) => report.errors.push(Failure {
+                    path: file.path.clone(),
+                    stage: Stage::Compare,
+                    message: err.to_string(),
+                    panicked: false,
+                }),
+            }
+        }
+        report
+    }
+
+    /// Scans all images in the directory and its subdirectories
+    pub fn scan_directory(&self, directory: &Path) -> Result<ScanReport> {
+        Ok(self.scan_paths(&crate::walk::find_images(directory)?))
+    }
+}
+
+#[cfg(test)]
+mod scan_tests {
+    use image::imageops::FilterType;
+    use super::*;
+
+    const BOATS: &str = "./data/original/2017_China_Chongqing_Boats.jpg";
+    const TRAFFIC: &str = "./data/original/2015_Japan_Tokyo_Traffic.jpg";
+
+    fn create_references() -> ReferenceSet {
+        let img = image::open(BOATS).unwrap();
+        let small = Hash::from_dynamic_image(img.resize(300, 200, FilterType::Triangle)).unwrap();
+        let mut unrelated = Hash::new();
+        unrelated.binary256 = vec![0x0F0F0F0F0F0F0F0F; 4];
+
+        let text = format!("# known images\nsmall;boats.jpg;{}\n\nunrelated;0b{}\n", small.to_string_hex(), unrelated);
+        ReferenceSet::from_text(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_references_from_text() {
+        let references = create_references();
+        let ids: Vec<&str> = references.iter().map(|(id, _)| id.as_str()).collect();
+
+        assert_eq!(ids, vec!["small;boats.jpg", "unrelated"]);
+        assert_eq!(references.iter().nth(1).unwrap().1.binary256, vec![0x0F0F0F0F0F0F0F0F; 4]);
+        assert_eq!(references.size(), Some(16));
+
+        assert!(ReferenceSet::from_text("no separator".as_bytes()).is_err());
+        assert!(ReferenceSet::from_text("a;XYZ".as_bytes()).is_err());
+        let mixed = format!("a;{}\nb;{}", "A".repeat(64), "A".repeat(16));
+        assert!(matches!(ReferenceSet::from_text(mixed.as_bytes()), Err(ForbildError::InvalidLength { .. })));
+
+        // 0s and 1s of a valid number of bits are binary, with or without prefix
+        let (_, hash) = parse_reference_line(&format!("a;{}", "01".repeat(32)), None).unwrap();
+        assert_eq!((hash.size, hash.binary256[0]), (8, 0x5555555555555555));
+        let (_, hash) = parse_reference_line(&format!("a;0b{}", "01".repeat(32)), Some(8)).unwrap();
+        assert_eq!((hash.size, hash.binary256[0]), (8, 0x5555555555555555));
+        let (_, hash) = parse_reference_line(&format!("a;{}", "01".repeat(8)), None).unwrap();
+        assert_eq!((hash.size, hash.binary256[0]), (8, 0x0101010101010101));
+        // The same digits could be a hex hash of the grid size of the other references
+        let ambiguous = format!("a;{}", "01".repeat(32));
+        assert!(matches!(parse_reference_line(&ambiguous, Some(16)), Err(ForbildError::InvalidDatabase(_))));
+        assert!(matches!(
+            parse_reference_line(&format!("a;0b{}", "1".repeat(14)), None),
+            Err(ForbildError::InvalidLength { expected: 64, found: 14 })
+        ));
+
+        // Hex hashes may be lowercase, even if they start with 0b
+        let hex = format!("0b{}", "3f".repeat(31));
+        let (_, hash) = parse_reference_line(&format!("a;{}", hex), None).unwrap();
+        assert_eq!(hash.to_string_hex(), hex.to_uppercase());
+    }
+
+    #[test]
+    fn test_references_baseline_format() {
+        // Lines as written by forbild_create before hex hashes and the 0b prefix were supported
+        let path = PathBuf::from("./data/original/2017_China_Chongqing_Boats.jpg");
+        let hash = Hash::from_path(&path).unwrap();
+        let text = format!("{};{}
+", path.display(), hash);
+        let references = ReferenceSet::from_text(text.as_bytes()).unwrap();
+
+        assert_eq!(references.size(), Some(16));
+        let (id, reference) = references.iter().next().unwrap();
+        assert_eq!(id, &path.display().to_string());
+        assert_eq!(reference.binary256, hash.binary256);
+    }
+
+    #[test]
+    fn test_load_db_and_records() {
+        let references = create_references();
+        let entries: Vec<(String, Hash)> = references.iter().cloned().collect();
+        let path = std::env::temp_dir().join(format!("forbild_scan_{}.db", std::process::id()));
+
+        let layout = crate::db::RecordLayout::new(16, false, false).unwrap();
+        crate::db::write_db_file(&path, layout, &entries).unwrap();
+        let loaded = ReferenceSet::load(&path).unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), references.iter().collect::<Vec<_>>());
+        assert_eq!(loaded.config(), Some(HashConfig::default()));
+
+        // The images are hashed with the config of the database
+        let config = HashConfig::bit_exact().with_threshold(crate::config::ThresholdStatistic::Mean);
+        let hash = Hash::from_path_with_config(std::path::Path::new(BOATS), &config).unwrap();
+        crate::db::write_db_file(&path, layout, &[("boats".to_string(), hash)]).unwrap();
+        assert_eq!(ReferenceSet::load(&path).unwrap().hash_config(), config);
+
+        #[cfg(feature = "serde")]
+        {
+            use crate::record::{records_to_json, write_ndjson, HashRecord};
+            let records: Vec<HashRecord> = entries.iter()
+                .map(|(id, hash)| HashRecord::new(PathBuf::from(id), 0, 0, 0, hash.clone()))
+                .collect();
+
+            // The config of the records is known
+            std::fs::write(&path, records_to_json(&records).unwrap()).unwrap();
+            let loaded = ReferenceSet::load(&path).unwrap();
+            assert_eq!(loaded.iter().collect::<Vec<_>>(), references.iter().collect::<Vec<_>>());
+            assert_eq!(loaded.config(), Some(HashConfig::default()));
+            assert_eq!(references.config(), None);
+
+            let mut ndjson = Vec::new();
+            write_ndjson(&mut ndjson, &records).unwrap();
+            std::fs::write(&path, ndjson).unwrap();
+            assert_eq!(ReferenceSet::load(&path).unwrap(), loaded);
+
+            let mut other = records[0].clone();
+            other.hash.config = other.hash.config.with_filter(crate::config::ResizeFilter::Area);
+            std::fs::write(&path, records_to_json(&[records[1].clone(), other]).unwrap()).unwrap();
+            assert!(matches!(ReferenceSet::load(&path), Err(ForbildError::ConfigMismatch { .. })));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_paths() {
+        let scanner = Scanner::new(create_references(), Matcher::default());
+        let paths = vec![PathBuf::from(BOATS), PathBuf::from("./data/missing.jpg"), PathBuf::from(TRAFFIC)];
+        let report = scanner.scan_paths(&paths);
+
+        assert_eq!(report.files, 3);
+        assert_eq!(report.hits.len(), 1);
+        assert_eq!(report.hits[0].path, PathBuf::from(BOATS));
+        assert_eq!(report.hits[0].reference, "small;boats.jpg");
+        assert!(report.hits[0].weighted.is_some());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].path, PathBuf::from("./data/missing.jpg"));
+        assert_eq!(report.errors[0].stage, crate::batch::Stage::Read);
+        assert_eq!(report.exit_code(), EXIT_ERRORS);
+
+        assert_eq!(scanner.scan_paths(&paths[..1]).exit_code(), EXIT_HITS);
+        assert_eq!(scanner.scan_paths(&paths[2..]).exit_code(), EXIT_NO_HITS);
+
+        // Hashes of another grid size are reported instead of being skipped
+        let report = scanner.scan_batch(crate::batch::hash_files(&paths[..1], 8));
+        assert!(report.hits.is_empty());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].stage, crate::batch::Stage::Compare);
+        assert_eq!(report.exit_code(), EXIT_ERRORS);
+    }
+
+    #[test]
+    fn test_scan_config() {
+        let boats = Hash::from_path(Path::new(BOATS)).unwrap();
+        let mut references = ReferenceSet::new();
+        references.insert_with_config("boats".to_string(), boats).unwrap();
+        assert_eq!(references.hash_config(), HashConfig::default());
+
+        let area = HashConfig::default().with_filter(crate::config::ResizeFilter::Area);
+        let scanner = Scanner::new(references.clone(), Matcher::default());
+        assert!(matches!(scanner.with_hash_config(area), Err(ForbildError::ConfigMismatch { .. })));
+        let jpeg = HashConfig::default().with_jpeg_decoding(crate::config::JpegDecoding::Scaled);
+        let scanner = Scanner::new(references.clone(), Matcher::default()).with_hash_config(jpeg).unwrap();
+        assert_eq!(scanner.hash_config(), &jpeg);
+        assert_eq!(scanner.scan_paths(&[PathBuf::from(BOATS)]).exit_code(), EXIT_HITS);
+
+        // Hashes of another config are not compared with the references
+        let batch = crate::batch::hash_files_with_config(&[PathBuf::from(BOATS)], &area, &DecodeLimits::default());
+        let report = scanner.scan_batch(batch);
+        assert!(report.hits.is_empty());
+        assert_eq!(report.errors[0].stage, crate::batch::Stage::Compare);
+
+        // Without a known config, only the grid size has to match
+        let scanner = Scanner::new(create_references(), Matcher::default());
+        assert_eq!(scanner.hash_config(), &HashConfig::default());
+        assert!(Scanner::new(create_references(), Matcher::default()).with_hash_config(area).is_ok());
+        assert!(Scanner::new(create_references(), Matcher::default()).with_hash_config(area.with_size(8)).is_err());
+    }
+
+    #[test]
+    fn test_write_hits() {
+        let hit = ScanHit {
+            path: PathBuf::from("a.jpg"),
+            reference: "known, 1".to_string(),
+            hamming: 3,
+            weighted: None,
+            stage: "hamming".to_string(),
+            flip: Orientation::FlipHorizontal,
+            reference_flip: Orientation::Identity,
+            config: HashConfig::bit_exact().with_size(8),
+        };
+        let report = ScanReport { files: 1, hits: vec![hit], errors: Vec::new() };
+
+        let mut buffer = Vec::new();
+        report.write_hits(&mut buffer, ReportFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), concat!(
+            "path,reference,hamming,weighted,stage,flip,reference_flip,config\n",
+            "a.jpg,\"known, 1\",3,,hamming,flip_horizontal,identity,",
+            "size=8 filter=area luma=rec709 averaging=srgb threshold=median normalization=brightest_pixel jpeg=full\n",
+        ));
+
+        #[cfg(feature = "serde")]
+        {
+            let mut buffer = Vec::new();
+            report.write_hits(&mut buffer, ReportFormat::Json).unwrap();
+            let hits: Vec<ScanHit> = serde_json::from_slice(&buffer).unwrap();
+            assert_eq!(hits, report.hits);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Finding the images in files and directories.
+//!
+//! Images are detected by the magic bytes at the start of the file (see `image::guess_format`), not
+//! by their extension, so images with wrong or missing extensions are found as well. Symbolic links
+//! are followed. A file that is reachable by several paths (symbolic or hard links) is only taken once.
+//! Entries that are skipped are returned with the reason, so that the caller can log them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use image::ImageFormat;
+use walkdir::WalkDir;
+
+use crate::error::{ForbildError, Result};
+
+/// Number of bytes read to detect the image format
+const MAGIC_LEN: usize = 16;
+
+/// Which files of a directory are images to hash
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WalkOptions {
+    /// Search the subdirectories as well
+    pub recursive: bool,
+    /// Glob patterns of the files to take, all images if empty
+    pub include: Vec<String>,
+    /// Glob patterns of the files to skip
+    pub exclude: Vec<String>,
+}
+
+impl WalkOptions {
+    pub fn recursive() -> WalkOptions {
+        WalkOptions { recursive: true, ..WalkOptions::default() }
+    }
+}
+
+/// An image found by the `Walker`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageFile {
+    pub path: PathBuf,
+    /// Format detected from the magic bytes
+    pub format: ImageFormat,
+}
+
+/// A file or directory that was not taken
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SkipReason {
+    /// The entry could not be read, e.g. missing permissions or a symbolic link loop
+    Unreadable(String),
+    /// The file content is no known image format
+    NotAnImage,
+    /// The file was already taken by another path (symbolic or hard link)
+    Duplicate(PathBuf),
+    /// The file was excluded by the patterns or the filter of the walker
+    Filtered,
+}
+
+impl SkipReason {
+    /// True if the entry was skipped because of an error
+    pub fn is_error(&self) -> bool {
+        matches!(self, SkipReason::Unreadable(_))
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Unreadable(msg) => write!(f, "unreadable: {}", msg),
+            SkipReason::NotAnImage => write!(f, "not an image"),
+            SkipReason::Duplicate(path) => write!(f, "same file as {}", path.display()),
+            SkipReason::Filtered => write!(f, "filtered"),
+        }
+    }
+}
+
+/// Result of a walk. Images are in the order of the given paths, the images of a directory are sorted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Walk {
+    pub images: Vec<ImageFile>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Walk {
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.images.iter().map(|image| image.path.clone()).collect()
+    }
+
+    /// The entries that were skipped because of an error
+    pub fn errors(&self) -> impl Iterator<Item = &Skipped> {
+        self.skipped.iter().filter(|skipped| skipped.reason.is_error())
+    }
+}
+
+type Filter = Box<dyn Fn(&Path, &Metadata) -> bool + Send + Sync>;
+
+/// Walks files and directories to find the images
+pub struct Walker {
+    options: WalkOptions,
+    filter: Option<Filter>,
+}
+
+/// Identifies a file independent of the path it is reached by
+#[derive(PartialEq, Eq, Hash)]
+enum FileId {
+    #[cfg(unix)]
+    Inode(u64, u64),
+    #[cfg(not(unix))]
+    Path(PathBuf),
+}
+
+impl Walker {
+    pub fn new(options: WalkOptions) -> Walker {
+        Walker { options, filter: None }
+    }
+
+    /// Only files for which the filter returns true are taken. It is called before the file is
+    /// opened, e.g. to skip files by size or modification time.
+    pub fn with_filter<F>(mut self, filter: F) -> Walker
+    where
+        F: Fn(&Path, &Metadata) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Finds the images of the files and directories. The include and exclude patterns are matched
+    /// against the path relative to the directory, or against the whole path for files, e.g. `*.png`
+    /// or `raw/**`. Only invalid patterns are an error, everything else is skipped.
+    pub fn walk(&self, paths: &[PathBuf]) -> Result<Walk> {
+        let include = build_globset(&self.options.include)?;
+        let exclude = build_globset(&self.options.exclude)?;
+        let is_selected = |path: &Path| {
+            (self.options.include.is_empty() || include.is_match(path)) && !exclude.is_match(path)
+        };
+
+        let mut walk = Walk::default();
+        let mut seen: HashMap<FileId, PathBuf> = HashMap::new();
+        for root in paths {
+            let max_depth = if self.options.recursive { usize::MAX } else { 1 };
+            let entries = WalkDir::new(root)
+                .follow_links(true)
+                .max_depth(max_depth)
+                .sort_by_file_name();
+
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        let path = err.path().unwrap_or(root).to_path_buf();
+                        walk.skipped.push(Skipped { path, reason: SkipReason::Unreadable(err.to_string()) });
+                        continue;
+                    }
+                };
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+
+                let path = entry.path();
+                let relative = path.strip_prefix(root).ok().filter(|p| !p.as_os_str().is_empty()).unwrap_or(path);
+                match self.check_file(path, relative, &is_selected, &mut seen) {
+                    Ok(format) => walk.images.push(ImageFile { path: entry.into_path(), format }),
+                    Err(reason) => walk.skipped.push(Skipped { path: entry.into_path(), reason }),
+                }
+            }
+        }
+        Ok(walk)
+    }
+
+    fn check_file<S>(&self, path: &Path, relative: &Path, is_selected: &S, seen: &mut HashMap<FileId, PathBuf>)
+        -> std::result::Result<ImageFormat, SkipReason>
+    where
+        S: Fn(&Path) -> bool,
+    {
+        let unreadable = |err: std::io::Error| SkipReason::Unreadable(err.to_string());
+
+        // Follows symbolic links
+        let metadata = std::fs::metadata(path).map_err(unreadable)?;
+        if !metadata.is_file() || !is_selected(relative) {
+            return Err(SkipReason::Filtered);
+        }
+        if let Some(filter) = &self.filter {
+            if !filter(path, &metadata) {
+                return Err(SkipReason::Filtered);
+            }
+        }
+
+        let format = sniff_format(path).map_err(unreadable)?.ok_or(SkipReason::NotAnImage)?;
+
+        let id = file_id(path, &metadata).map_err(unreadable)?;
+        if let Some(first) = seen.get(&id) {
+            return Err(SkipReason::Duplicate(first.clone()));
+        }
+        seen.insert(id, path.to_path_buf());
+        Ok(format)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &Metadata) -> std::io::Result<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(FileId::Inode(metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &Metadata) -> std::io::Result<FileId> {
+    Ok(FileId::Path(std::fs::canonicalize(path)?))
+}
+
+/// Detects the image format from the first bytes of the file, `None` if it is no known image format
+pub fn sniff_format(path: &Path) -> std::io::Result<Option<ImageFormat>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    File::open(path)?.take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    Ok(image::guess_format(&magic).ok())
+}
+
+/// Returns the images of the given files and directories, see `Walker::walk`. Skipped entries are left out.
+pub fn collect_images(paths: &[PathBuf], options: &WalkOptions) -> Result<Vec<PathBuf>> {
+    Ok(Walker::new(options.clone()).walk(paths)?.paths())
+}
+
+/// Returns the paths of all images in the directory and its subdirectories, sorted
+pub fn find_images(directory: &Path) -> Result<Vec<PathBuf>> {
+    collect_images(&[directory.to_path_buf()], &WalkOptions::recursive())
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|err| ForbildError::InvalidPattern(format!("{}: {}", pattern, err)))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|err| ForbildError::InvalidPattern(err.to_string()))
+}
+
+#[cfg(test)]
+mod walk_tests {
+    use super::*;
+
+    const BOATS: &str = "data/original/2017_China_Chongqing_Boats.jpg";
+
+    fn collect(paths: &[&str], options: &WalkOptions) -> Vec<String> {
+        let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+        collect_images(&paths, options).unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Creates an empty temporary directory for a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("forbild_walk_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_find_images() {
+        let paths = find_images(Path::new("data")).unwrap();
+
+        assert_eq!(paths.len(), 6);
+        assert!(paths.contains(&PathBuf::from(BOATS)));
+        assert!(paths.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(find_images(Path::new("src")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recursion() {
+        assert_eq!(collect(&["data/original"], &WalkOptions::default()), vec![
+            "data/original/2015_Japan_Tokyo_Traffic.jpg",
+            "data/original/2017_China_Chongqing_Boats.jpg",
+        ]);
+        assert_eq!(collect(&["data/original"], &WalkOptions::recursive()).len(), 6);
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let options = WalkOptions { include: vec!["out/*".to_string()], ..WalkOptions::recursive() };
+        assert_eq!(collect(&["data/original"], &options).len(), 4);
+
+        let options = WalkOptions { exclude: vec!["out/**".to_string(), "*Tokyo*".to_string()], ..WalkOptions::recursive() };
+        assert_eq!(collect(&["data/original"], &options), vec![BOATS]);
+
+        // Files are matched with their whole path
+        assert_eq!(collect(&[BOATS], &WalkOptions::default()), vec![BOATS]);
+        let options = WalkOptions { exclude: vec!["*.jpg".to_string()], ..WalkOptions::default() };
+        assert!(collect(&[BOATS], &options).is_empty());
+
+        let options = WalkOptions { include: vec!["[".to_string()], ..WalkOptions::default() };
+        assert!(matches!(collect_images(&[PathBuf::from("data")], &options), Err(ForbildError::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_content_sniffing() {
+        let dir = temp_dir("sniffing");
+        std::fs::copy(BOATS, dir.join("boats.Jpg")).unwrap();
+        std::fs::copy(BOATS, dir.join("no_extension")).unwrap();
+        std::fs::write(dir.join("fake.png"), "not an image").unwrap();
+        std::fs::write(dir.join("empty.gif"), "").unwrap();
+
+        let walk = Walker::new(WalkOptions::default()).walk(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(walk.paths(), vec![dir.join("boats.Jpg"), dir.join("no_extension")]);
+        assert!(walk.images.iter().all(|image| image.format == ImageFormat::Jpeg));
+        assert_eq!(walk.skipped, vec![
+            Skipped { path: dir.join("empty.gif"), reason: SkipReason::NotAnImage },
+            Skipped { path: dir.join("fake.png"), reason: SkipReason::NotAnImage },
+        ]);
+        assert_eq!(walk.errors().count(), 0);
+
+        assert_eq!(sniff_format(Path::new(BOATS)).unwrap(), Some(ImageFormat::Jpeg));
+        assert_eq!(sniff_format(Path::new("Cargo.toml")).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_links() {
+        let dir = temp_dir("links");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::copy(BOATS, dir.join("a.jpg")).unwrap();
+        std::fs::hard_link(dir.join("a.jpg"), dir.join("b.jpg")).unwrap();
+        std::os::unix::fs::symlink(dir.join("a.jpg"), dir.join("sub").join("c.jpg")).unwrap();
+        // A loop must not be followed forever
+        std::os::unix::fs::symlink(&dir, dir.join("sub").join("loop")).unwrap();
+
+        let walk = Walker::new(WalkOptions::recursive()).walk(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(walk.paths(), vec![dir.join("a.jpg")]);
+        let duplicates: Vec<&Skipped> = walk.skipped.iter()
+            .filter(|skipped| matches!(skipped.reason, SkipReason::Duplicate(_)))
+            .collect();
+        assert_eq!(duplicates, vec![
+            &Skipped { path: dir.join("b.jpg"), reason: SkipReason::Duplicate(dir.join("a.jpg")) },
+            &Skipped { path: dir.join("sub").join("c.jpg"), reason: SkipReason::Duplicate(dir.join("a.jpg")) },
+        ]);
+        assert_eq!(walk.errors().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter_and_unreadable() {
+        let walker = Walker::new(WalkOptions::recursive())
+            .with_filter(|path, metadata| metadata.len() > 800_000 && !path.ends_with("missing.jpg"));
+        let walk = walker.walk(&[PathBuf::from("data/original"), PathBuf::from("data/missing.jpg")]).unwrap();
+
+        assert_eq!(walk.paths(), vec![PathBuf::from(BOATS)]);
+        assert_eq!(walk.skipped.iter().filter(|skipped| skipped.reason == SkipReason::Filtered).count(), 5);
+
+        let errors: Vec<&Skipped> = walk.errors().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, PathBuf::from("data/missing.jpg"));
+        assert!(errors[0].reason.to_string().starts_with("unreadable"));
+    }
+}